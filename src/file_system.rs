@@ -6,249 +6,1604 @@
 // Create a new Disk
 // Format a Disk
 
+mod bitmap;
 mod block;
+mod compress;
+mod defrag;
 mod diagnostics;
 mod directory;
 pub mod disk;
+mod encrypt;
+mod error;
+mod extent;
+mod file;
+mod fsck;
+mod geometry;
+mod image;
 mod inode;
+mod journal;
 mod line_handler;
+mod mem_disk;
+mod pointers;
+mod resize;
+mod scrub;
+mod snapshot;
+mod upgrade;
 mod utils;
 
+use bitmap::*;
+pub use block::SuperBlock;
 use block::*;
+pub use compress::Compression;
+pub use defrag::{Defrag, Fragmentation};
 use diagnostics::*;
 use directory::*;
 pub use disk::*;
+pub use encrypt::{EncryptedDevice, Encryption};
+pub use error::*;
+use extent::*;
+pub use file::{File, MountedFileSystem, OpenOptions};
+pub use fsck::{FsckProblem, FsckReport};
+pub use geometry::Geometry;
+pub use image::{image_to_lines, lines_to_image, open_device, ImageFile, BLOCK_SIZE};
 use inode::*;
+pub use inode::{FileStat, InodeKind, Layout};
+use journal::transaction;
+pub use line_handler::LineFile;
+pub use mem_disk::MemDisk;
+use pointers::Pointers;
+pub use resize::Resize;
+pub use scrub::ScrubReport;
+pub use snapshot::{Snapshot, SnapshotDevice};
+use std::collections::HashSet;
+pub use upgrade::Upgrade;
 
 pub fn write_inode_and_blocks<'a>(
     a: (Inode, Vec<Block>),
-) -> DiskAction<'a, Option<(Inode, Vec<Block>)>> {
+) -> DiskAction<'a, FsResult<(Inode, Vec<Block>)>> {
     let (i, blocks) = a;
+    let numbers = blocks.iter().map(|b| b.number).collect();
     let write_inode = Inode::write_inode(i);
-    let write_blocks = blocks.into_iter().map(|b| Block::write_block(b)).collect();
-    let write_blocks = sequence(write_blocks);
-    let write_blocks = map(write_blocks, Box::new(|w| utils::remove_options(w)));
-
-    map2(
-        write_inode,
-        write_blocks,
-        Box::new(|a, b| a.map(|aa| (aa, b))),
-    )
+    let write_blocks = blocks.into_iter().map(Block::write_block).collect();
+    let write_blocks = sequence_result(write_blocks);
+    let mark = Bitmap::mark(BitmapKind::Blocks, numbers, true);
+    let write_blocks = map2_result(write_blocks, mark, Box::new(|a, _| a));
+
+    map2_result(write_inode, write_blocks, Box::new(|a, b| (a, b)))
 }
 
 pub fn get_file_inode_and_blocks<'a>(
     file_name: String,
-) -> DiskAction<'a, Option<(Inode, Vec<Block>)>> {
-    let d = Directory::get_directory();
-    let d = map(
+) -> DiskAction<'a, FsResult<(Inode, Vec<Block>)>> {
+    let d = get_file_inode(file_name);
+    flat_map_result(d, Box::new(Inode::get_inode_blocks))
+}
+
+// Resolve a path to its inode, failing for directories
+fn get_file_inode<'a>(file_name: String) -> DiskAction<'a, FsResult<Inode>> {
+    let d = Directory::resolve(file_name.clone());
+    let d = flat_map_result(d, Box::new(Inode::get_inode));
+    map(
         d,
-        utils::lift(Box::new(move |dir: Directory| dir.find(&file_name[..]))),
-    );
-    let d = map(d, Box::new(&|x: Option<Option<u32>>| x.flatten()));
-    let d = flat_map(d, utils::lift_disk_action(Box::new(Inode::get_inode)));
-    let d = flatten_option(d);
-    let d = flat_map(
+        Box::new(move |x| {
+            x.and_then(|i: Inode| {
+                if i.is_directory() {
+                    return Err(FsError::IsADirectory(file_name.clone()));
+                }
+                Ok(i)
+            })
+        }),
+    )
+}
+
+// Replace a file's data with f applied to it, keeping the blocks it already has
+// size gives the new size from the old one, None when it overflows
+// Sizes past what the file can hold fail before the data is touched
+fn modify_data<'a>(
+    file_name: String,
+    size: Box<dyn Fn(u64) -> Option<u64> + 'a>,
+    f: Box<dyn Fn(Vec<u8>) -> Vec<u8> + 'a>,
+) -> DiskAction<'a, FsResult<u64>> {
+    let d = get_file_inode_and_blocks(file_name);
+    let d = map2_result(d, SuperBlock::get_super_block(), Box::new(|a, sb| (a, sb)));
+    let d = flat_map_result(
         d,
-        utils::lift_disk_action(Box::new(Inode::get_inode_blocks)),
+        Box::new(move |((i, old), sb): ((Inode, Vec<Block>), SuperBlock)| {
+            let lens: Vec<usize> = old.iter().map(|b| b.data.len()).collect();
+            let data = match compress::decode(&i, Block::blocks_to_data(old.clone())) {
+                Ok(data) => data,
+                Err(e) => return unit(Err(e)),
+            };
+            match size(data.len() as u64) {
+                Some(size) if size <= sb.max_file_size(i.layout) => (),
+                _ => return unit(Err(FsError::FileTooLarge)),
+            }
+            let data = f(data);
+            let new = Inode {
+                modified: utils::now(),
+                ..i
+            };
+            let d = encode_data(new, data, lens);
+            let d = flat_map_result(
+                d,
+                Box::new(move |(new, chunks)| write_inode_data(i, old.clone(), new, chunks)),
+            );
+            map_result(d, Box::new(|i| i.size))
+        }),
     );
-    flatten_option(d)
+    d
+}
+
+// Write data at offset, or at the end when there's no offset
+// Files using extents or pointers only have the blocks data lands in read and written
+// Compressed data, chains and empty files are rewritten whole
+fn write_file(disk: &mut Disk, file_name: &str, offset: Option<u64>, data: &[u8]) -> FsResult<u64> {
+    let i = run(disk, get_file_inode(file_name.into()))?;
+    if i.layout == Layout::Chain || !i.compression.is_none() || i.size == 0 {
+        let len = data.len() as u64;
+        let data = data.to_vec();
+        let d = match offset {
+            Some(offset) => modify_data(
+                file_name.into(),
+                Box::new(move |old| offset.checked_add(len).map(|end| end.max(old))),
+                Box::new(move |mut old| {
+                    let (offset, end) = (offset as usize, offset as usize + data.len());
+                    if old.len() < end {
+                        old.resize(end, 0);
+                    }
+                    old[offset..end].copy_from_slice(&data);
+                    old
+                }),
+            ),
+            None => modify_data(
+                file_name.into(),
+                Box::new(move |old| old.checked_add(len)),
+                Box::new(move |mut old| {
+                    old.extend_from_slice(&data);
+                    old
+                }),
+            ),
+        };
+        return run(disk, d);
+    }
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    let offset = offset.unwrap_or(i.size);
+    let end = offset
+        .checked_add(data.len() as u64)
+        .filter(|end| end.max(&i.size) <= &sb.max_file_size(i.layout))
+        .ok_or(FsError::FileTooLarge)?;
+    let bs = sb.geometry.block_data_size as u64;
+    let size = end.max(i.size);
+    let new = Inode {
+        size,
+        modified: utils::now(),
+        ..i
+    };
+
+    // Every block but the last is full, so where data lands is known from the size.
+    // A gap between the old end and offset is filled with zeros
+    let first = (offset.min(i.size) / bs) as usize;
+    let count = i.size.div_ceil(bs) as usize;
+    let grows = end.div_ceil(bs) as usize > count;
+    let last = match grows {
+        true => count,
+        false => end.div_ceil(bs) as usize,
+    };
+    let numbers = run(disk, Inode::get_data_numbers(i, first, last))?;
+    // Blocks a snapshot holds have to be copied, as do more than fit in the journal.
+    // write_data does that from the blocks it's given to the end of the file,
+    // like it does when the file grows. The held blocks are only read the once
+    let held = run(disk, snapshot::held_blocks())?;
+    let whole_tail =
+        grows || numbers.iter().any(|n| held.contains(n)) || !fits_journal(disk, numbers.len())?;
+    let (last, numbers) = match whole_tail && last < count {
+        true => (count, run(disk, Inode::get_data_numbers(i, first, count))?),
+        false => (last, numbers),
+    };
+    let reads = numbers.iter().map(|n| Block::get_block(*n)).collect();
+    let old = run(disk, sequence_result(reads))?;
+
+    let from = first as u64 * bs;
+    let to = match whole_tail {
+        true => size,
+        false => (last as u64 * bs).min(size),
+    };
+    let mut bytes = Block::blocks_to_data(old.clone());
+    bytes.resize((to - from) as usize, 0);
+    bytes[(offset - from) as usize..(end - from) as usize].copy_from_slice(data);
+    let chunks: Vec<Vec<u8>> = bytes.chunks(bs as usize).map(|c| c.to_vec()).collect();
+
+    if !whole_tail {
+        let blocks = Block::set_data_blocks_data((old.clone(), chunks));
+        let changed = blocks.into_iter().filter(|b| !old.contains(b)).collect();
+        let (i, _) = run(disk, write_inode_and_blocks((new, changed)))?;
+        return Ok(i.size);
+    }
+    let kept = run(disk, Inode::get_data_numbers(i, 0, first))?;
+    write_data(disk, &held, i, &kept, &old, new, &chunks).map(|i| i.size)
+}
+
+// Split data into chunks of the disk's block data size
+// Chunks keep the boundaries of the lens given, so the blocks they came from can be reused
+pub fn chunk_data<'a>(data: Vec<u8>, lens: Vec<usize>) -> DiskAction<'a, FsResult<Vec<Vec<u8>>>> {
+    let d = SuperBlock::get_super_block();
+    map_result(
+        d,
+        Box::new(move |sb| {
+            let max = sb.geometry.block_data_size;
+            utils::split_into_chunks(&data, &utils::resize_chunks(&lens, data.len(), max))
+        }),
+    )
+}
+
+// Chunks of what new keeps in its blocks for data, compressed if new asks for it
+// new comes back with the size of data and how much of it is stored
+fn encode_data<'a>(
+    new: Inode,
+    data: Vec<u8>,
+    lens: Vec<usize>,
+) -> DiskAction<'a, FsResult<(Inode, Vec<Vec<u8>>)>> {
+    let size = data.len() as u64;
+    let stored = compress::encode(new.compression, data);
+    let new = Inode {
+        size,
+        stored: match new.compression {
+            Compression::None => 0,
+            _ => stored.len() as u64,
+        },
+        ..new
+    };
+    map_result(
+        chunk_data(stored, lens),
+        Box::new(move |chunks| (new, chunks)),
+    )
+}
+
+// Store chunks as the data of new, reusing the blocks old had in order
+// Blocks are only allocated or freed at the end, and only blocks
+// whose contents changed get written, extent and pointer blocks included
+pub fn write_inode_data<'a>(
+    old: Inode,
+    old_blocks: Vec<Block>,
+    new: Inode,
+    chunks: Vec<Vec<u8>>,
+) -> DiskAction<'a, FsResult<Inode>> {
+    Box::new(move |disk| {
+        let mut disk = disk;
+        let res = run(&mut disk, snapshot::held_blocks())
+            .and_then(|held| write_data(&mut disk, &held, old, &[], &old_blocks, new, &chunks));
+        (res, disk)
+    })
+}
+
+// Blocks in use are copied into the journal when they're written over, blocks that were
+// free aren't. Half the journal is left for the inode, bitmaps and directories
+fn fits_journal(disk: &mut Disk, rewritten: usize) -> FsResult<bool> {
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    Ok(sb.journal_blocks == 0 || rewritten <= sb.journal_blocks as usize / 2)
+}
+
+// kept are data blocks before old_blocks that stay as they are, never for chains
+// held are the blocks snapshots hold
+fn write_data(
+    disk: &mut Disk,
+    held: &HashSet<u32>,
+    old: Inode,
+    kept: &[u32],
+    old_blocks: &[Block],
+    new: Inode,
+    chunks: &[Vec<u8>],
+) -> FsResult<Inode> {
+    if new.layout == Layout::Pointers && kept.len() + chunks.len() > Pointers::max_blocks() {
+        return Err(FsError::FileTooLarge);
+    }
+    let old_metadata = run(disk, Inode::get_metadata_blocks(old))?;
+    // Blocks a snapshot holds can't be written over, so a file sharing any
+    // of them is copied to new blocks and the old ones are left to the snapshot.
+    // So is a file rewriting more blocks in place than the journal has room for
+    let shared = old_blocks
+        .iter()
+        .chain(old_metadata.iter())
+        .any(|b| held.contains(&b.number));
+    let rewritten = old_blocks
+        .iter()
+        .zip(chunks)
+        .filter(|(b, c)| b.data != **c)
+        .count();
+    let copy = shared || !fits_journal(disk, rewritten + old_metadata.len())?;
+    let (old_blocks, old_metadata, copied) = match copy {
+        true => (&[][..], vec![], [old_blocks, &old_metadata[..]].concat()),
+        false => (old_blocks, old_metadata, vec![]),
+    };
+    let keep = chunks.len().min(old_blocks.len());
+    let near = old_blocks[..keep]
+        .last()
+        .map(|b| b.number)
+        .or(kept.last().copied());
+    let extra = run(
+        disk,
+        Block::get_free_data_blocks_near(chunks.len() - keep, near, vec![]),
+    )?;
+    let data = [&old_blocks[..keep], &extra[..]].concat();
+    let data = Block::set_data_blocks_data((data, chunks.to_vec()));
+
+    let new = Inode {
+        pointers: Pointers::default(),
+        ..new
+    };
+    let (new, blocks, keep_metadata) = match new.layout {
+        Layout::Chain => {
+            let (i, b) = Inode::set_inode_blocks(new, data)?;
+            (i, b, 0)
+        }
+        Layout::Extents => {
+            let numbers: Vec<u32> = kept
+                .iter()
+                .copied()
+                .chain(data.iter().map(|b| b.number))
+                .collect();
+            let extents = Extent::from_numbers(&numbers);
+            let needed = Extent::blocks_needed(extents.len());
+            let keep_extents = needed.min(old_metadata.len());
+            let more = run(
+                disk,
+                Block::get_free_data_blocks_near(needed - keep_extents, None, numbers),
+            )?;
+            let mut lists: Vec<Vec<u8>> = extents
+                .chunks(EXTENTS_PER_BLOCK)
+                .map(Extent::to_bytes)
+                .collect();
+            lists.resize(needed, vec![]);
+            let extent_blocks = [&old_metadata[..keep_extents], &more[..]].concat();
+            let extent_blocks = Block::set_data_blocks_data((extent_blocks, lists));
+            let (i, extent_blocks) = Inode::set_inode_blocks(new, extent_blocks)?;
+            let data = data.into_iter().map(|b| Block {
+                b_type: BlockType::End,
+                ..b
+            });
+            (
+                i,
+                extent_blocks.into_iter().chain(data).collect(),
+                keep_extents,
+            )
+        }
+        Layout::Pointers => {
+            let numbers: Vec<u32> = kept
+                .iter()
+                .copied()
+                .chain(data.iter().map(|b| b.number))
+                .collect();
+            let needed = Pointers::blocks_needed(numbers.len());
+            let keep_pointers = needed.min(old_metadata.len());
+            let more = run(
+                disk,
+                Block::get_free_data_blocks_near(needed - keep_pointers, None, numbers.clone()),
+            )?;
+            let pointer_blocks = [&old_metadata[..keep_pointers], &more[..]].concat();
+            let pointer_numbers: Vec<u32> = pointer_blocks.iter().map(|b| b.number).collect();
+            let (pointers, lists) = Pointers::build(&numbers, &pointer_numbers);
+            let i = Inode {
+                start_block: numbers.first().copied(),
+                pointers,
+                ..new
+            };
+            let blocks = Block::set_data_blocks_data((pointer_blocks, lists))
+                .into_iter()
+                .chain(data)
+                .map(|b| Block {
+                    b_type: BlockType::End,
+                    ..b
+                })
+                .collect();
+            (i, blocks, keep_pointers)
+        }
+    };
+
+    let dropped = [
+        &old_blocks[keep..],
+        &old_metadata[keep_metadata..],
+        &copied[..],
+    ]
+    .concat();
+    run(disk, Block::free_blocks_except(dropped, held))?;
+    let changed = blocks
+        .into_iter()
+        .filter(|b| !old_blocks.contains(b) && !old_metadata.contains(b))
+        .collect();
+    let (i, _) = run(disk, write_inode_and_blocks((new, changed)))?;
+    Ok(i)
+}
+
+// A snapshot mounted read-only can't record the access, which is no reason to fail a read
+fn record_access<'a>(i: Inode) -> DiskAction<'a, FsResult<Inode>> {
+    let d = transaction(Inode::touch_accessed(i));
+    map(
+        d,
+        Box::new(move |x| match x {
+            Err(FsError::ReadOnly) => Ok(i),
+            x => x,
+        }),
+    )
+}
+
+// Resolve the parent directory for a new entry, failing if the name is taken
+fn resolve_new_entry<'a>(path: String) -> DiskAction<'a, FsResult<(u32, String)>> {
+    let d = Directory::resolve_parent(path.clone());
+    flat_map_result(
+        d,
+        Box::new(move |(parent, name)| {
+            let path = path.clone();
+            let d = Directory::get_directory_at(parent);
+            map(
+                d,
+                Box::new(move |x| {
+                    x.and_then(|dir: Directory| match dir.find(&name) {
+                        Some(_) => Err(FsError::AlreadyExists(path.clone())),
+                        None => Ok((parent, name.clone())),
+                    })
+                }),
+            )
+        }),
+    )
 }
 
 #[derive(Debug)]
 pub struct FileSystem {}
 impl FileSystem {
-    pub fn read_file<'a>(file_name: String) -> DiskAction<'a, Option<String>> {
+    pub fn read_file<'a>(file_name: String) -> DiskAction<'a, FsResult<Vec<u8>>> {
         let d = get_file_inode_and_blocks(file_name);
-        map(d, utils::lift(Box::new(|(_, b)| Block::blocks_to_data(b))))
-    }
-
-    pub fn save_as_file<'a>(file_name: String, data: String) -> DiskAction<'a, Option<u32>> {
-        let data = utils::string_to_block_data_chunks(data);
-        let d = Inode::get_free_inode(); // Get A Free Inode
-        let data_block = Block::get_free_data_blocks(data.len()); // Get Enough Free Blocks for data
-        let data_block = map(
-            data_block,
-            Box::new(move |x| Block::set_data_blocks_data((x, data.clone()))),
-        ); // Set Data On Free Blocks
-        let d = map2(d, data_block, Box::new(Inode::set_inode_blocks)); // Combine inode and data_blocks and set them up
-        let d = flat_map(d, utils::lift_disk_action(Box::new(write_inode_and_blocks))); // Write out the inode and data blocks
-        let d = flatten_option(d);
-        flat_map(
+        flat_map_result(
+            d,
+            Box::new(|(i, b)| {
+                let data = match compress::decode(&i, Block::blocks_to_data(b)) {
+                    Ok(data) => data,
+                    Err(e) => return unit(Err(e)),
+                };
+                let d = record_access(i);
+                map_result(d, Box::new(move |_| data.clone()))
+            }),
+        )
+    }
+
+    // Read len bytes starting at offset, less if the file ends first
+    // Only the blocks up to the end of the range are read
+    pub fn read_at<'a>(
+        file_name: String,
+        offset: u64,
+        len: usize,
+    ) -> DiskAction<'a, FsResult<Vec<u8>>> {
+        let d = get_file_inode(file_name);
+        flat_map_result(
+            d,
+            Box::new(move |i: Inode| {
+                // Older disks have no size, so the chain has to be walked to find out
+                if len == 0 || (i.size != 0 && offset >= i.size) {
+                    return unit(Ok(vec![]));
+                }
+                let d = Inode::read_range(i, offset, len);
+                flat_map_result(
+                    d,
+                    Box::new(move |data| {
+                        let d = record_access(i);
+                        map_result(d, Box::new(move |_| data.clone()))
+                    }),
+                )
+            }),
+        )
+    }
+
+    pub fn save_as_file<'a>(file_name: String, data: Vec<u8>) -> DiskAction<'a, FsResult<u32>> {
+        let size = data.len() as u64;
+        // Check the name is free before anything is allocated
+        let d = resolve_new_entry(file_name);
+        let d = flat_map_result(
             d,
-            Box::new(move |a| match a {
-                Some((i, _)) => Directory::write_file_name(i.number, file_name.clone()),
-                None => unit(None),
+            Box::new(move |(parent, name)| {
+                let data = data.clone();
+                let d = Inode::get_free_inode(); // Get A Free Inode
+                let d = map2_result(d, SuperBlock::get_super_block(), Box::new(|i, sb| (i, sb)));
+                let d = flat_map_result(
+                    d,
+                    Box::new(move |(i, sb): (Inode, SuperBlock)| {
+                        let new = Inode {
+                            compression: sb.compression,
+                            ..i.created(InodeKind::File, size)
+                        };
+                        let d = encode_data(new, data.clone(), vec![]);
+                        flat_map_result(
+                            d,
+                            Box::new(move |(new, chunks)| write_inode_data(i, vec![], new, chunks)),
+                        )
+                    }),
+                ); // Write out the inode and data blocks
+                flat_map_result(
+                    d,
+                    Box::new(move |i| {
+                        Directory::write_file_name_at(parent, i.number, name.clone())
+                    }),
+                ) // Write out the file name to the directory
+            }),
+        );
+        transaction(d)
+    }
+
+    // Overwrite the file at offset, growing it if the data runs past the end
+    // A gap between the old end and offset is filled with zeros
+    // Gives back the new size of the file
+    pub fn write_at<'a>(
+        file_name: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> DiskAction<'a, FsResult<u64>> {
+        transaction(Box::new(move |disk| {
+            let mut disk = disk;
+            let res = write_file(&mut disk, &file_name, Some(offset), &data);
+            (res, disk)
+        }))
+    }
+
+    pub fn append<'a>(file_name: String, data: Vec<u8>) -> DiskAction<'a, FsResult<u64>> {
+        transaction(Box::new(move |disk| {
+            let mut disk = disk;
+            let res = write_file(&mut disk, &file_name, None, &data);
+            (res, disk)
+        }))
+    }
+
+    // Cut the file down to size, or pad it out to size with zeros
+    pub fn truncate<'a>(file_name: String, size: u64) -> DiskAction<'a, FsResult<u64>> {
+        transaction(modify_data(
+            file_name,
+            Box::new(move |_| Some(size)),
+            Box::new(move |mut old| {
+                old.resize(size as usize, 0);
+                old
             }),
-        ) // Write out the file name to the directory
+        ))
+    }
+
+    // Turn compression on or off for a file, its data is rewritten to match
+    pub fn set_compression<'a>(
+        file_name: String,
+        compression: Compression,
+    ) -> DiskAction<'a, FsResult<FileStat>> {
+        let d = get_file_inode_and_blocks(file_name);
+        let d = flat_map_result(
+            d,
+            Box::new(move |(i, old): (Inode, Vec<Block>)| {
+                let data = match compress::decode(&i, Block::blocks_to_data(old.clone())) {
+                    Ok(data) => data,
+                    Err(e) => return unit(Err(e)),
+                };
+                let new = Inode { compression, ..i };
+                let d = encode_data(new, data, vec![]);
+                let d = flat_map_result(
+                    d,
+                    Box::new(move |(new, chunks)| write_inode_data(i, old.clone(), new, chunks)),
+                );
+                let d = flat_map_result(d, Box::new(Inode::get_inode_blocks));
+                let d = map_result(d, Box::new(|(i, b)| i.to_stat(&b)));
+                match compression {
+                    Compression::None => d,
+                    _ => map2_result(
+                        d,
+                        SuperBlock::add_feature(FEATURE_COMPRESSION),
+                        Box::new(|a, _| a),
+                    ),
+                }
+            }),
+        );
+        transaction(d)
+    }
+
+    pub fn mkdir<'a>(path: String) -> DiskAction<'a, FsResult<u32>> {
+        let d = resolve_new_entry(path);
+        let d = flat_map_result(
+            d,
+            Box::new(move |(parent, name)| {
+                let name = name.clone();
+                let d = Inode::get_free_inode();
+                flat_map_result(
+                    d,
+                    Box::new(move |i: Inode| {
+                        let name = name.clone();
+                        let dir = Directory::new(i.number, parent);
+                        let i = i.created(InodeKind::Directory, 0);
+                        let d = Directory::save_directory(i, dir);
+                        flat_map_result(
+                            d,
+                            Box::new(move |_| {
+                                Directory::write_file_name_at(parent, i.number, name.clone())
+                            }),
+                        )
+                    }),
+                )
+            }),
+        );
+        transaction(d)
+    }
+
+    // Only empty directories can be removed
+    pub fn rmdir<'a>(path: String) -> DiskAction<'a, FsResult<()>> {
+        let d = Directory::resolve_parent(path.clone());
+        let d = flat_map_result(
+            d,
+            Box::new(move |(parent, name)| {
+                if name == "." || name == ".." {
+                    return unit(Err(FsError::InvalidName(path.clone())));
+                }
+                let d = Directory::resolve(path.clone());
+                let d = flat_map_result(d, Box::new(Inode::get_inode));
+                let p = path.clone();
+                let d = map(
+                    d,
+                    Box::new(move |x| {
+                        x.and_then(|i: Inode| {
+                            if !i.is_directory() {
+                                return Err(FsError::NotADirectory(p.clone()));
+                            }
+                            Ok(i)
+                        })
+                    }),
+                );
+                let d = flat_map_result(
+                    d,
+                    Box::new(|i: Inode| {
+                        map2_result(
+                            Directory::get_directory_at(i.number),
+                            Inode::get_inode_blocks(i),
+                            Box::new(|dir, blocks| (dir, blocks)),
+                        )
+                    }),
+                );
+                let p = path.clone();
+                let d = map(
+                    d,
+                    Box::new(move |x| {
+                        x.and_then(|(dir, blocks): (Directory, _)| {
+                            if !dir.is_empty() {
+                                return Err(FsError::DirectoryNotEmpty(p.clone()));
+                            }
+                            Ok(blocks)
+                        })
+                    }),
+                );
+                let d = flat_map_result(
+                    d,
+                    Box::new(|(i, b)| {
+                        let free_inode = Inode::free_inode(i);
+                        let free_blocks = Inode::free_all_blocks(i, b);
+                        map2_result(free_inode, free_blocks, Box::new(|a, b| (a, b)))
+                    }),
+                );
+                let remove = Directory::remove_file_name_at(parent, name.clone());
+                map2_result(d, remove, Box::new(|_, b| b))
+            }),
+        );
+        transaction(d)
+    }
+
+    // List a directory
+    pub fn list_directory<'a>(path: String) -> DiskAction<'a, FsResult<Directory>> {
+        let d = Directory::resolve(path.clone());
+        let d = flat_map_result(d, Box::new(Inode::get_inode));
+        flat_map_result(
+            d,
+            Box::new(move |i: Inode| {
+                if !i.is_directory() {
+                    return unit(Err(FsError::NotADirectory(path.clone())));
+                }
+                Directory::get_directory_at(i.number)
+            }),
+        )
+    }
+
+    // Inode details for a file or directory
+    pub fn stat<'a>(path: String) -> DiskAction<'a, FsResult<FileStat>> {
+        let d = Directory::resolve(path);
+        let d = flat_map_result(d, Box::new(Inode::get_inode));
+        let d = flat_map_result(d, Box::new(Inode::get_inode_blocks));
+        map_result(d, Box::new(|(i, b)| i.to_stat(&b)))
+    }
+
+    // Change the permission bits of a file or directory
+    pub fn chmod<'a>(path: String, mode: u32) -> DiskAction<'a, FsResult<()>> {
+        let d = Directory::resolve(path);
+        let d = flat_map_result(d, Box::new(Inode::get_inode));
+        let d = flat_map_result(
+            d,
+            Box::new(move |i| {
+                Inode::write_inode(Inode {
+                    mode,
+                    modified: utils::now(),
+                    ..i
+                })
+            }),
+        );
+        transaction(map_result(d, Box::new(|_| ())))
+    }
+
+    pub fn chown<'a>(path: String, uid: u32, gid: u32) -> DiskAction<'a, FsResult<()>> {
+        let d = Directory::resolve(path);
+        let d = flat_map_result(d, Box::new(Inode::get_inode));
+        let d = flat_map_result(
+            d,
+            Box::new(move |i| {
+                Inode::write_inode(Inode {
+                    uid,
+                    gid,
+                    modified: utils::now(),
+                    ..i
+                })
+            }),
+        );
+        transaction(map_result(d, Box::new(|_| ())))
     }
 
     // ls the directory
-    pub fn get_directory<'a>() -> DiskAction<'a, Option<Directory>> {
+    pub fn get_directory<'a>() -> DiskAction<'a, FsResult<Directory>> {
         Directory::get_directory()
     }
 
     // Size == how many line
-    pub fn create_disk<'a>(file: String, size: u32) -> bool {
-        use std::fs;
+    pub fn create_disk(file: String, size: u32) -> FsResult<()> {
+        let device = ImageFile::create(&file)?;
+        FileSystem::format(&device, size)
+    }
+
+    pub fn create_disk_with(file: String, super_block: SuperBlock) -> FsResult<()> {
+        let device = ImageFile::create(&file)?;
+        FileSystem::format_with(&device, super_block)
+    }
 
-        fs::File::create(file.clone())
-            .ok()
-            .map(move |_| FileSystem::format(file, size))
-            .unwrap_or(false)
+    pub fn create_encrypted_disk(
+        file: String,
+        super_block: SuperBlock,
+        passphrase: &str,
+        metadata: bool,
+    ) -> FsResult<()> {
+        let device = ImageFile::create(&file)?;
+        FileSystem::format_encrypted(&device, super_block, passphrase, metadata)
     }
 
-    pub fn remove_file<'a>(file_name: String) -> DiskAction<'a, bool> {
+    pub fn remove_file<'a>(file_name: String) -> DiskAction<'a, FsResult<()>> {
         if file_name == "/" || file_name == "." {
-            return unit(false);
+            return unit(Err(FsError::InvalidName(file_name)));
         }
 
-        let write_inode_and_blocks = get_file_inode_and_blocks(file_name.clone());
-        let write_inode_and_blocks = flat_map(
-            write_inode_and_blocks,
-            utils::lift_disk_action(Box::new(|(i, b)| {
-                let free_inode = Inode::free_inode(i);
-                let free_blocks = Block::free_blocks(b);
-                map2(free_inode, free_blocks, Box::new(|a, b| (a, b)))
-            })),
+        let d = Directory::resolve_parent(file_name.clone());
+        let d = flat_map_result(
+            d,
+            Box::new(move |(parent, name)| {
+                let write_inode_and_blocks = get_file_inode_and_blocks(file_name.clone());
+                let write_inode_and_blocks = flat_map_result(
+                    write_inode_and_blocks,
+                    Box::new(|(i, b)| {
+                        let free_inode = Inode::free_inode(i);
+                        let free_blocks = Inode::free_all_blocks(i, b);
+                        map2_result(free_inode, free_blocks, Box::new(|a, b| (a, b)))
+                    }),
+                );
+                let write_directory = Directory::remove_file_name_at(parent, name.clone());
+                map2_result(write_inode_and_blocks, write_directory, Box::new(|_, b| b))
+            }),
         );
-        let write_directory = Directory::remove_file_name(file_name.clone());
-        let d = map2(write_inode_and_blocks, write_directory, Box::new(|_, b| b));
-        map(d, Box::new(|x| x.is_some()))
-    }
-
-    pub fn format<'a>(file_name: String, size: u32) -> bool {
-        let disk = Disk::new(&file_name);
-        let super_block = SuperBlock::new(size);
-        let write_blocks: Vec<DiskAction<Option<Block>>> = (1..size + 1)
-            .into_iter()
-            .map(|x| {
-                if x == 1 {
-                    return Block {
-                        number: x,
-                        b_type: BlockType::Free,
-                        data: serde_json::to_string(&super_block).unwrap_or("".into()),
-                    };
-                } else {
-                    Block {
-                        number: x,
-                        b_type: BlockType::Free,
-                        data: "".into(),
-                    }
-                }
+        transaction(d)
+    }
+
+    pub fn format(device: &dyn BlockDevice, size: u32) -> FsResult<()> {
+        let super_block = SuperBlock::with_journal(size).with_bitmaps().with_backups();
+        FileSystem::format_with(device, super_block)
+    }
+
+    // Format with a superblock already laid out, new files get its layout
+    pub fn format_with(device: &dyn BlockDevice, super_block: SuperBlock) -> FsResult<()> {
+        let super_block = super_block.with_current_version();
+        super_block.geometry.validate()?;
+        if super_block.get_storage_block_range().is_empty() {
+            return Err(FsError::InvalidGeometry(
+                "no blocks are left for storage".into(),
+            ));
+        }
+        let disk = Disk::new(device);
+        let size = super_block.total_blocks;
+        let layout = super_block.layout;
+        let write_blocks: Vec<DiskAction<FsResult<Block>>> = (2..size + 1)
+            .map(|x| Block {
+                number: x,
+                b_type: BlockType::Free,
+                data: "".into(),
             })
             .map(Block::write_block)
             .collect();
 
-        let write_blocks = sequence(write_blocks);
+        // Superblock goes last, its backups are in blocks that start off free
+        let write_blocks = sequence_result(write_blocks);
+        let write_blocks = map2_result(
+            write_blocks,
+            SuperBlock::write_super_block(super_block.clone()),
+            Box::new(|b, _| b),
+        );
 
-        let inodes = Inode::generate_inodes(size);
+        let inodes = Inode::generate_inodes(&super_block);
         let write_inodes = Inode::replace_all_inodes(inodes);
 
-        let directory = Directory::default();
-        let write_directory = Directory::save_directory(directory);
+        let root = Inode {
+            number: ROOT_INODE,
+            layout,
+            ..Inode::default()
+        }
+        .created(InodeKind::Directory, 0);
+        let write_directory = Directory::save_directory(root, Directory::default());
 
-        let d = map2(write_blocks, write_inodes, Box::new(|a, _| a));
-        let d = map2(d, write_directory, Box::new(|_, b| b));
+        let d = map2_result(write_blocks, write_inodes, Box::new(|a, _| a));
+        let d = map2_result(d, Bitmap::rebuild(), Box::new(|a, _| a));
+        let d = map2_result(d, write_directory, Box::new(|_, _| ()));
         let (res, _d) = d(disk);
-        res.is_some()
+        res.and_then(|_| device.flush())
+    }
+
+    // Format with every block but the superblock encrypted with a key from the passphrase
+    // File data, directories and the journal always are, metadata adds the inode table and bitmaps
+    pub fn format_encrypted(
+        device: &dyn BlockDevice,
+        super_block: SuperBlock,
+        passphrase: &str,
+        metadata: bool,
+    ) -> FsResult<()> {
+        encrypt::format(device, super_block, passphrase, metadata)
     }
 
     // Check that superblock is valid, if so return disk
-    // POTENTIAL PROBLEM, file_name needs to last as long as disk, so
-    // in the shell it may be a issue if the users command is dropped
-    // I hope not :/
-    pub fn mount<'a>(file_name: &'a str) -> Option<Disk<'a>> {
-        let disk = Disk::new(&file_name);
+    // A broken superblock is put back from a backup when there is one
+    // Any transaction left committed in the journal is finished off first
+    pub fn mount(device: &dyn BlockDevice) -> FsResult<Disk<'_>> {
+        let disk = Disk::new(device);
         let (res, disk) = SuperBlock::get_super_block()(disk);
-        res.and_then(|s| {
-            if s.valid_super_block() {
-                Some(disk)
-            } else {
-                None
+        let (s, disk) = match res {
+            Ok(s) if s.valid_super_block() => (s, disk),
+            Ok(_) | Err(FsError::InvalidSuperBlock) => {
+                let (backup, disk) = SuperBlock::get_backup_super_block()(disk);
+                let backup = backup?;
+                backup.check_supported()?;
+                let (res, disk) = SuperBlock::write_super_block(backup)(disk);
+                (res?, disk)
             }
+            Err(e) => return Err(e),
+        };
+        s.check_supported()?;
+        if s.encryption.is_some() && !device.decrypts() {
+            return Err(FsError::Locked);
+        }
+        let replayed = journal::replay(device)?;
+        Ok(Disk {
+            writes: disk.writes + replayed,
+            ..disk
         })
     }
 
-    pub fn get_diagnostic<'a>() -> DiskAction<'a, Option<DiskDiagnostics>> {
+    // Mount an encrypted disk, a wrong passphrase fails before any sealed block is read
+    // The device that comes back decrypts the disk, use it with Disk::new
+    pub fn mount_encrypted<'a>(
+        device: &'a dyn BlockDevice,
+        passphrase: &str,
+    ) -> FsResult<EncryptedDevice<'a>> {
+        encrypt::mount(device, passphrase)
+    }
+
+    // Make sure everything written has reached the device
+    pub fn unmount(disk: Disk) -> FsResult<()> {
+        disk.device.flush()
+    }
+
+    // Move every inode still using a chain of Next blocks over to extents
+    // Each inode moves in its own transaction, gives back how many moved
+    pub fn migrate_to_extents<'a>() -> DiskAction<'a, FsResult<u32>> {
+        let d = Inode::get_inode_table();
+        flat_map_result(
+            d,
+            Box::new(|inodes: Vec<Inode>| {
+                let moves = inodes
+                    .into_iter()
+                    .filter(|i| i.start_block.is_some() && i.layout == Layout::Chain)
+                    .map(|i| {
+                        let d = Inode::get_inode_blocks(i);
+                        let d = flat_map_result(
+                            d,
+                            Box::new(|(i, b)| {
+                                let data = Block::blocks_to_data(b.clone());
+                                // Compressed files always recorded their size
+                                let size = match i.compression {
+                                    Compression::None => data.len() as u64,
+                                    _ => i.size,
+                                };
+                                let new = Inode {
+                                    layout: Layout::Extents,
+                                    size,
+                                    ..i
+                                };
+                                let d = chunk_data(data, vec![]);
+                                flat_map_result(
+                                    d,
+                                    Box::new(move |chunks| {
+                                        write_inode_data(i, b.clone(), new, chunks)
+                                    }),
+                                )
+                            }),
+                        );
+                        transaction(d)
+                    })
+                    .collect();
+                map_result(sequence_result(moves), Box::new(|x| x.len() as u32))
+            }),
+        )
+    }
+
+    // Bring an older disk up to the current format version
+    pub fn upgrade(device: &dyn BlockDevice) -> FsResult<Upgrade> {
+        upgrade::upgrade(device)
+    }
+
+    // How many pieces each file and directory is in
+    pub fn fragmentation<'a>() -> DiskAction<'a, FsResult<Vec<Fragmentation>>> {
+        defrag::fragmentation()
+    }
+
+    // Move fragmented files into one piece, at most budget data blocks at a time
+    pub fn defragment<'a>(budget: Option<u32>) -> DiskAction<'a, FsResult<Defrag>> {
+        defrag::defragment(budget)
+    }
+
+    // Grow or shrink a disk, moving whatever is in the way
+    pub fn resize(device: &dyn BlockDevice, size: u32) -> FsResult<Resize> {
+        resize::resize(device, size)
+    }
+
+    // Take a named snapshot of the whole filesystem, sharing its blocks until they're written
+    pub fn snapshot<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+        snapshot::snapshot(name)
+    }
+
+    pub fn list_snapshots<'a>() -> DiskAction<'a, FsResult<Vec<Snapshot>>> {
+        snapshot::list()
+    }
+
+    // A read-only view of the disk as it was, use it with Disk::new
+    pub fn mount_snapshot(device: &dyn BlockDevice, name: String) -> FsResult<SnapshotDevice<'_>> {
+        snapshot::mount(device, name)
+    }
+
+    // Throw away everything since the snapshot was taken, the snapshot is kept
+    pub fn rollback<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+        snapshot::rollback(name)
+    }
+
+    pub fn delete_snapshot<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+        snapshot::delete(name)
+    }
+
+    pub fn get_diagnostic<'a>() -> DiskAction<'a, FsResult<DiskDiagnostics>> {
         DiskDiagnostics::get_diagnostics()
     }
+
+    // Check the disk is consistent, optionally fixing what can be fixed safely
+    pub fn fsck<'a>(repair: bool) -> DiskAction<'a, FsResult<FsckReport>> {
+        fsck::fsck(repair)
+    }
+
+    // Read every block in use looking for ones that were changed after being written
+    pub fn scrub<'a>() -> DiskAction<'a, FsResult<ScrubReport>> {
+        scrub::scrub()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::pointers::{DIRECT_POINTERS, POINTERS_PER_BLOCK};
     use super::*;
 
     #[test]
     fn read_file_should_return_expected() {
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = FileSystem::read_file("secret.txt".into())(disk);
-        assert_eq!(data, Some("SomebodyOnceToldMe".into()));
+        assert_eq!(data, Ok("SomebodyOnceToldMe".into()));
     }
 
     #[test]
-    fn write_file_should_return_expected() {
-        use std::fs;
+    fn extent_reads_should_not_depend_on_offset() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let file_data: Vec<u8> = (0..250).collect();
+        let (_, disk) = FileSystem::save_as_file("big.bin".into(), file_data.clone())(disk);
+
+        let (head, disk2) = FileSystem::read_at("big.bin".into(), 0, 10)(disk);
+        assert_eq!(head, Ok(file_data[..10].to_vec()));
+        let head_reads = disk2.reads - disk.reads;
+        let (tail, disk2) = FileSystem::read_at("big.bin".into(), 240, 10)(disk);
+        assert_eq!(tail, Ok(file_data[240..].to_vec()));
+        assert_eq!(disk2.reads - disk.reads, head_reads);
+
+        // Blocks for a new file come from one contiguous run
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let (extents, _) = Inode::get_extents(i.unwrap())(disk);
+        assert_eq!(extents.unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn migrate_to_extents_should_keep_data() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (moved, disk) = FileSystem::migrate_to_extents()(disk);
+        assert_eq!(moved, Ok(2));
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(b"SomebodyOnceToldMe".to_vec()));
+        let (i, disk) = Inode::get_inode(3)(disk);
+        assert_eq!(i.unwrap().layout, Layout::Extents);
+        let (report, disk) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (moved, _) = FileSystem::migrate_to_extents()(disk);
+        assert_eq!(moved, Ok(0));
+    }
+
+    #[test]
+    fn format_with_geometry_should_lay_out_disk() {
+        let device = MemDisk::new();
+        let geometry = Geometry {
+            inode_percent: 20,
+            block_data_size: 120,
+            inodes_per_block: 3,
+        };
+        let sb = SuperBlock::with_journal(50)
+            .with_geometry(geometry)
+            .with_bitmaps();
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        assert_eq!(sb.geometry, geometry);
+        assert_eq!(sb.get_inode_table_block_range().len(), 10);
+        let (inodes, disk) = Inode::get_inode_table()(disk);
+        assert_eq!(inodes.unwrap().len(), 30);
+
+        let file_data = vec![b'a'; 250];
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), file_data.clone())(disk);
+        let (blocks, disk) = get_file_inode_and_blocks("a.txt".into())(disk);
+        assert_eq!(blocks.unwrap().1.len(), 3);
+        let (data, disk) = FileSystem::read_at("a.txt".into(), 115, 10)(disk);
+        assert_eq!(data, Ok(file_data[115..125].to_vec()));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+
+        let bad = SuperBlock::with_journal(50).with_geometry(Geometry {
+            inode_percent: 0,
+            ..geometry
+        });
+        let res = FileSystem::format_with(&MemDisk::new(), bad);
+        assert!(matches!(res, Err(FsError::InvalidGeometry(_))));
+    }
+
+    #[test]
+    fn pointers_layout_should_reach_double_indirect_blocks() {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(400)
+            .with_bitmaps()
+            .with_layout(Layout::Pointers);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = FileSystem::mount(&device).unwrap();
+        let blocks = DIRECT_POINTERS + POINTERS_PER_BLOCK + 2;
+        let file_data: Vec<u8> = (0..blocks * Geometry::default().block_data_size as usize)
+            .map(|x| x as u8)
+            .collect();
+        let (res, disk) = FileSystem::save_as_file("big.bin".into(), file_data.clone())(disk);
+        let (i, disk) = Inode::get_inode(res.unwrap())(disk);
+        let i = i.unwrap();
+        assert_eq!(i.layout, Layout::Pointers);
+        assert_ne!(i.pointers.double_indirect, 0);
+        let (data, disk) = FileSystem::read_file("big.bin".into())(disk);
+        assert_eq!(data, Ok(file_data.clone()));
 
-        let file_data = fs::read_to_string("./test-files/sda1").unwrap_or("".into());
-        fs::write("./test-files/sda1_write_file_test", file_data).unwrap();
+        // Reading the end only needs the double indirect blocks
+        let offset = file_data.len() as u64 - 60;
+        let reads = disk.reads;
+        let (data, disk) = Inode::read_range(i, offset, 60)(disk);
+        assert_eq!(data, Ok(file_data[offset as usize..].to_vec()));
+        // Superblock, double indirect, indirect and two data blocks
+        assert_eq!(disk.reads - reads, 5);
+        let (data, disk) = FileSystem::read_at("big.bin".into(), offset, 60)(disk);
+        assert_eq!(data, Ok(file_data[offset as usize..].to_vec()));
 
-        let disk = Disk::new("./test-files/sda1_write_file_test");
-        let file_data: String = "Ten long years have I waited for the day that COBOL will come back to rise from the bits".into();
+        // Writes keep the pointers to blocks before the ones they land in
+        let mut file_data = file_data;
+        let (_, disk) = FileSystem::write_at("big.bin".into(), 700, b"bbbb".to_vec())(disk);
+        file_data[700..704].copy_from_slice(b"bbbb");
+        let extra = vec![b'c'; 3 * Geometry::default().block_data_size as usize];
+        let (size, disk) = FileSystem::append("big.bin".into(), extra.clone())(disk);
+        file_data.extend_from_slice(&extra);
+        assert_eq!(size, Ok(file_data.len() as u64));
+        let (data, disk) = FileSystem::read_file("big.bin".into())(disk);
+        assert_eq!(data, Ok(file_data.clone()));
+        let (report, disk) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+
+        let (size, disk) = FileSystem::truncate("big.bin".into(), 100)(disk);
+        assert_eq!(size, Ok(100));
+        let (i, disk) = Inode::get_inode(i.number)(disk);
+        let i = i.unwrap();
+        assert_eq!((i.pointers.indirect, i.pointers.double_indirect), (0, 0));
+        let (report, disk) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, _) = FileSystem::read_file("big.bin".into())(disk);
+        assert_eq!(data, Ok(file_data[..100].to_vec()));
+    }
+
+    #[test]
+    fn pointers_layout_should_refuse_more_than_it_can_reach() {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(100)
+            .with_bitmaps()
+            .with_layout(Layout::Pointers);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = FileSystem::mount(&device).unwrap();
+        let (i, disk) = Inode::get_free_inode()(disk);
+        let i = i.unwrap();
+        let writes = disk.writes;
+
+        // Checked before anything is allocated, a file that fits runs out of space instead
+        let chunks = vec![vec![b'a']; Pointers::max_blocks() + 1];
+        let (res, disk) = write_inode_data(i, vec![], i, chunks)(disk);
+        assert_eq!(res, Err(FsError::FileTooLarge));
+        let chunks = vec![vec![b'a']; Pointers::max_blocks()];
+        let (res, disk) = write_inode_data(i, vec![], i, chunks)(disk);
+        assert_eq!(res, Err(FsError::NoSpace));
+        assert_eq!(disk.writes, writes);
+    }
+
+    #[test]
+    fn write_file_should_return_expected() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let file_data: Vec<u8> = "Ten long years have I waited for the day that COBOL will come back to rise from the bits".into();
 
         let (result, disk) =
             FileSystem::save_as_file("cobol_rise.txt".into(), file_data.clone())(disk);
-        assert_eq!(result, Some(2));
+        assert_eq!(result, Ok(2));
 
         let (data, _) = FileSystem::read_file("cobol_rise.txt".into())(disk);
-        assert_eq!(data, Some(file_data));
+        assert_eq!(data, Ok(file_data));
+    }
+
+    #[test]
+    fn read_at_should_only_read_needed_blocks() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let file_data: Vec<u8> = (0..200).collect();
+        let (_, disk) = FileSystem::save_as_file("big.bin".into(), file_data.clone())(disk);
+
+        let (whole, disk2) = FileSystem::read_file("big.bin".into())(disk);
+        assert_eq!(whole, Ok(file_data.clone()));
+        let whole_reads = disk2.reads - disk.reads;
+
+        let (head, disk2) = FileSystem::read_at("big.bin".into(), 10, 20)(disk);
+        assert_eq!(head, Ok(file_data[10..30].to_vec()));
+        // 3 fewer data blocks, but the superblock is read for the block data size
+        assert_eq!(whole_reads - (disk2.reads - disk.reads), 2);
+
+        let (tail, _) = FileSystem::read_at("big.bin".into(), 180, 100)(disk);
+        assert_eq!(tail, Ok(file_data[180..].to_vec()));
+        let (past_end, disk2) = FileSystem::read_at("big.bin".into(), 500, 10)(disk);
+        assert_eq!(past_end, Ok(vec![]));
+        assert!(disk2.reads - disk.reads < whole_reads);
+    }
+
+    #[test]
+    fn read_at_should_work_on_older_disks() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, _) = FileSystem::read_at("secret.txt".into(), 8, 8)(disk);
+        assert_eq!(data, Ok(b"OnceTold".to_vec()));
+    }
+
+    fn block_numbers(disk: Disk, file_name: &str) -> Vec<u32> {
+        let (x, _) = get_file_inode_and_blocks(file_name.into())(disk);
+        x.unwrap().1.iter().map(|b| b.number).collect()
+    }
+
+    #[test]
+    fn write_at_should_reuse_blocks() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let mut file_data = vec![b'a'; 120];
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), file_data.clone())(disk);
+        let before = block_numbers(disk, "a.txt");
+
+        let (size, disk) = FileSystem::write_at("a.txt".into(), 60, b"bbbb".to_vec())(disk);
+        assert_eq!(size, Ok(120));
+        file_data[60..64].copy_from_slice(b"bbbb");
+        let (data, disk) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(file_data.clone()));
+        assert_eq!(block_numbers(disk, "a.txt"), before);
+
+        // Past the end leaves a gap of zeros
+        let (size, disk) = FileSystem::write_at("a.txt".into(), 130, b"c".to_vec())(disk);
+        assert_eq!(size, Ok(131));
+        file_data.extend_from_slice(&[0; 10]);
+        file_data.push(b'c');
+        let (data, disk) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(file_data));
+        assert_eq!(block_numbers(disk, "a.txt")[..3], before[..]);
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn write_at_and_append_should_only_touch_the_blocks_they_land_in() {
+        let device = MemDisk::formatted(200).unwrap();
+        let disk = Disk::new(&device);
+        let mut big: Vec<u8> = (0..3000).map(|n| n as u8).collect();
+        let (_, disk) = FileSystem::save_as_file("big.txt".into(), big.clone())(disk);
+        let (_, disk) = FileSystem::save_as_file("small.txt".into(), big[..100].to_vec())(disk);
+        assert!(block_numbers(disk, "big.txt").len() > 40);
+
+        // Reads cost the same however much of the file is left untouched, writes depend
+        // on whether the modified time changed within the second
+        fn cost<'a>(disk: Disk<'a>, action: DiskAction<'a, FsResult<u64>>) -> (u32, Disk<'a>) {
+            let (res, after) = action(disk);
+            assert!(res.is_ok());
+            (after.reads - disk.reads, after)
+        }
+        let (small_cost, disk) = cost(
+            disk,
+            FileSystem::write_at("small.txt".into(), 50, b"bbbb".to_vec()),
+        );
+        let (big_cost, disk) = cost(
+            disk,
+            FileSystem::write_at("big.txt".into(), 1500, b"bbbb".to_vec()),
+        );
+        assert_eq!(big_cost, small_cost);
+        big[1500..1504].copy_from_slice(b"bbbb");
+
+        let (small_cost, disk) = cost(disk, FileSystem::append("small.txt".into(), b"c".to_vec()));
+        let (big_cost, disk) = cost(disk, FileSystem::append("big.txt".into(), b"c".to_vec()));
+        assert_eq!(big_cost, small_cost);
+        big.push(b'c');
+
+        let (data, disk) = FileSystem::read_file("big.txt".into())(disk);
+        assert_eq!(data, Ok(big));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn append_and_truncate_should_change_blocks_at_the_end() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (size, disk) = FileSystem::append("secret.txt".into(), b"!".to_vec())(disk);
+        assert_eq!(size, Ok(19));
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(b"SomebodyOnceToldMe!".to_vec()));
+        assert_eq!(block_numbers(disk, "secret.txt"), vec![4, 6, 8, 9]);
+
+        let (size, disk) = FileSystem::truncate("secret.txt".into(), 8)(disk);
+        assert_eq!(size, Ok(8));
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(b"Somebody".to_vec()));
+        assert_eq!(block_numbers(disk, "secret.txt"), vec![4]);
+        let (stat, disk) = FileSystem::stat("secret.txt".into())(disk);
+        assert_eq!(stat.unwrap().size, 8);
+        let (free, disk) = Block::get_all_free_data_blocks()(disk);
+        assert_eq!(free.unwrap().len(), 6);
+
+        let (size, disk) = FileSystem::truncate("secret.txt".into(), 0)(disk);
+        assert_eq!(size, Ok(0));
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(vec![]));
+        let (size, _) = FileSystem::append("/".into(), b"x".to_vec())(disk);
+        assert_eq!(size, Err(FsError::IsADirectory("/".into())));
+    }
+
+    #[test]
+    fn writes_past_the_largest_file_should_fail_before_allocating() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), b"Shrek".to_vec())(disk);
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let max = sb.unwrap().max_file_size(Layout::Extents);
+        let writes = disk.writes;
+
+        let (res, disk) = FileSystem::write_at("a.txt".into(), u64::MAX, b"x".to_vec())(disk);
+        assert_eq!(res, Err(FsError::FileTooLarge));
+        let (res, disk) = FileSystem::write_at("a.txt".into(), max, b"x".to_vec())(disk);
+        assert_eq!(res, Err(FsError::FileTooLarge));
+        let (res, disk) = FileSystem::truncate("a.txt".into(), u64::MAX)(disk);
+        assert_eq!(res, Err(FsError::FileTooLarge));
+        let (res, disk) = FileSystem::append("a.txt".into(), vec![b'x'; max as usize])(disk);
+        assert_eq!(res, Err(FsError::FileTooLarge));
+        assert_eq!(disk.writes, writes);
+        let (data, _) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(b"Shrek".to_vec()));
+    }
+
+    #[test]
+    fn binary_files_should_round_trip() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let file_data: Vec<u8> = (0..=255).collect();
+
+        let (_, disk) = FileSystem::save_as_file("bytes.bin".into(), file_data.clone())(disk);
+        let (data, disk) = FileSystem::read_file("bytes.bin".into())(disk);
+        assert_eq!(data, Ok(file_data));
+        let (stat, _) = FileSystem::stat("bytes.bin".into())(disk);
+        assert_eq!(stat.unwrap().size, 256);
     }
 
     #[test]
     fn format_should_return_expected() {
-        let file: String = "./test-files/format_test".into();
         let blocks = 50;
-        let res = FileSystem::format(file.clone(), blocks);
-        assert!(res);
-        let disk = Disk::new(&file);
+        let device = MemDisk::new();
+        let res = FileSystem::format(&device, blocks);
+        assert_eq!(res, Ok(()));
+        let disk = Disk::new(&device);
         let (sb, _) = SuperBlock::get_super_block()(disk);
         assert_eq!(sb.unwrap().total_blocks, blocks);
     }
 
     #[test]
     fn create_disk_should_return_expected() {
-        let file: String = "./test-files/create_test".into();
+        let file = std::env::temp_dir().join("file_system_create_test");
+        let file: String = file.to_string_lossy().into();
         let blocks = 50;
         let res = FileSystem::create_disk(file.clone(), blocks);
-        assert!(res);
-        let disk = Disk::new(&file);
+        assert_eq!(res, Ok(()));
+        let device = ImageFile::open(&file).unwrap();
+        assert_eq!(device.len(), Ok(blocks));
+        let disk = Disk::new(&device);
         let (sb, _) = SuperBlock::get_super_block()(disk);
+        std::fs::remove_file(&file).unwrap();
         assert_eq!(sb.unwrap().total_blocks, blocks);
     }
 
     #[test]
     fn mount_should_mount_successfully() {
-        let res = FileSystem::mount("./test-files/sda1".into());
-        assert!(res.is_some());
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let res = FileSystem::mount(&device);
+        assert!(res.is_ok());
     }
 
     #[test]
     fn mount_should_mount_unsuccessfully() {
-        let res = FileSystem::mount("./test-files/line_handler_test_file.txt".into());
-        assert!(res.is_none());
+        let device = MemDisk::from_image("./test-files/line_handler_test_file.txt").unwrap();
+        let res = FileSystem::mount(&device);
+        assert_eq!(res.err(), Some(FsError::InvalidSuperBlock));
     }
 
     #[test]
-    fn remove_file_should_return_expected() {
-        use std::fs;
+    fn mount_should_recover_superblock_from_backup() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        let (sb, _) = SuperBlock::get_super_block()(disk);
+        assert_eq!(sb.unwrap().get_backup_block_range(), 49..51);
+        device.write_block(1, b"Donkey").unwrap();
+
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        assert!(sb.unwrap().valid_super_block());
+        let (data, disk) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok(b"Ogres".to_vec()));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+
+        // Without backups there's nothing to fall back on
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        device.write_block(1, b"Donkey").unwrap();
+        let res = FileSystem::mount(&device);
+        assert_eq!(res.err(), Some(FsError::InvalidSuperBlock));
+    }
+
+    #[test]
+    fn mount_should_not_trust_a_superblock_failing_its_checksum() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        let (sb, _) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        // Inodes per block still parses after the flip, only the checksum tells
+        let mut bytes = device.read_block(1).unwrap();
+        bytes[36] ^= 1;
+        device.write_block(1, &bytes).unwrap();
+        let disk = Disk::new(&device);
+        let (res, _) = SuperBlock::get_super_block()(disk);
+        assert_eq!(res, Err(FsError::InvalidSuperBlock));
+
+        let disk = FileSystem::mount(&device).unwrap();
+        let (res, disk) = SuperBlock::get_super_block()(disk);
+        assert_eq!(res.unwrap().geometry, sb.geometry);
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok(b"Ogres".to_vec()));
+    }
 
-        let file_data = fs::read_to_string("./test-files/sda1").unwrap_or("".into());
-        fs::write("./test-files/remove_file_test", file_data).unwrap();
-        let disk = Disk::new("./test-files/remove_file_test");
+    #[test]
+    fn remove_file_should_return_expected() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
 
         let (result, disk) = FileSystem::remove_file("secret.txt".into())(disk);
-        assert_eq!(result, true);
+        assert_eq!(result, Ok(()));
 
         let (data, _) = FileSystem::read_file("secret.txt".into())(disk);
-        assert_eq!(data, None);
+        assert_eq!(data, Err(FsError::NotFound("secret.txt".into())));
+    }
+
+    #[test]
+    fn remove_missing_file_should_return_not_found() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (result, disk) = FileSystem::remove_file("missing.txt".into())(disk);
+        assert_eq!(result, Err(FsError::NotFound("missing.txt".into())));
+        assert_eq!(disk.writes, 0);
+    }
+
+    #[test]
+    fn write_existing_file_should_return_already_exists() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (result, disk) = FileSystem::save_as_file("secret.txt".into(), "Shrek".into())(disk);
+        assert_eq!(result, Err(FsError::AlreadyExists("secret.txt".into())));
+        assert_eq!(disk.writes, 0);
+    }
+
+    #[test]
+    fn mkdir_and_rmdir_should_return_expected() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+
+        let (result, disk) = FileSystem::mkdir("/docs".into())(disk);
+        assert!(result.is_ok());
+        let (result, disk) = FileSystem::mkdir("/docs".into())(disk);
+        assert_eq!(result, Err(FsError::AlreadyExists("/docs".into())));
+
+        let (result, disk) =
+            FileSystem::save_as_file("/docs/notes.txt".into(), "Shrek is love".into())(disk);
+        assert!(result.is_ok());
+        let (data, disk) = FileSystem::read_file("/docs/notes.txt".into())(disk);
+        assert_eq!(data, Ok("Shrek is love".into()));
+        let (data, disk) = FileSystem::read_file("/docs".into())(disk);
+        assert_eq!(data, Err(FsError::IsADirectory("/docs".into())));
+
+        let (result, disk) = FileSystem::rmdir("/docs".into())(disk);
+        assert_eq!(result, Err(FsError::DirectoryNotEmpty("/docs".into())));
+        let (result, disk) = FileSystem::remove_file("/docs/notes.txt".into())(disk);
+        assert_eq!(result, Ok(()));
+        let (result, disk) = FileSystem::rmdir("/docs".into())(disk);
+        assert_eq!(result, Ok(()));
+
+        let (dir, _) = FileSystem::list_directory("/".into())(disk);
+        assert!(dir.unwrap().is_empty());
+    }
+
+    #[test]
+    fn stat_should_return_expected() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (stat, disk) = FileSystem::stat("/secret.txt".into())(disk);
+        let expected = FileStat {
+            inode: 3,
+            kind: InodeKind::File,
+            layout: Layout::Chain,
+            blocks: 4,
+            size: 18,
+            stored: 18,
+            compression: Compression::None,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+        };
+        assert_eq!(stat, Ok(expected));
+        let (stat, _) = FileSystem::stat("/".into())(disk);
+        assert_eq!(stat.unwrap().kind, InodeKind::Directory);
+    }
+
+    #[test]
+    fn new_files_should_have_metadata() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("/shrek.txt".into(), "Ogres".into())(disk);
+        let (_, disk) = FileSystem::mkdir("/docs".into())(disk);
+        let (_, disk) = FileSystem::chown("/shrek.txt".into(), 1000, 100)(disk);
+        let (_, disk) = FileSystem::chmod("/shrek.txt".into(), 0o600)(disk);
+
+        let (stat, disk) = FileSystem::stat("/shrek.txt".into())(disk);
+        let stat = stat.unwrap();
+        assert_eq!(stat.size, 5);
+        assert_eq!(stat.mode, 0o600);
+        assert_eq!((stat.uid, stat.gid), (1000, 100));
+        assert!(stat.created > 0 && stat.modified >= stat.created);
+
+        let (stat, _) = FileSystem::stat("/docs".into())(disk);
+        let stat = stat.unwrap();
+        assert_eq!(stat.kind, InodeKind::Directory);
+        assert_eq!(stat.mode, 0o755);
+        assert!(stat.size > 0);
+    }
+
+    #[test]
+    fn rmdir_file_should_return_not_a_directory() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (result, _) = FileSystem::rmdir("/secret.txt".into())(disk);
+        assert_eq!(result, Err(FsError::NotADirectory("/secret.txt".into())));
+    }
+
+    #[test]
+    fn compressed_files_should_read_back_the_same() {
+        let device = MemDisk::formatted(80).unwrap();
+        let mut disk = Disk::new(&device);
+        let text = b"Ogres are like onions. Onions have layers. ".repeat(10);
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), text.clone()),
+        )
+        .unwrap();
+        let stat = run(
+            &mut disk,
+            FileSystem::set_compression("shrek.txt".into(), Compression::Lz),
+        )
+        .unwrap();
+        assert_eq!(stat.size, text.len() as u64);
+        assert!(stat.stored < stat.size / 2);
+
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok(text.clone()));
+        let data = run(&mut disk, FileSystem::read_at("shrek.txt".into(), 23, 6));
+        assert_eq!(data, Ok(b"Onions".to_vec()));
+        let size = run(
+            &mut disk,
+            FileSystem::append("shrek.txt".into(), b"The end".to_vec()),
+        );
+        assert_eq!(size, Ok(text.len() as u64 + 7));
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok([text.clone(), b"The end".to_vec()].concat()));
+
+        // The disk is marked as having compressed files
+        let sb = run(&mut disk, SuperBlock::get_super_block()).unwrap();
+        assert_eq!(sb.features & FEATURE_COMPRESSION, FEATURE_COMPRESSION);
+
+        let stat = run(
+            &mut disk,
+            FileSystem::set_compression("shrek.txt".into(), Compression::None),
+        )
+        .unwrap();
+        assert_eq!(stat.stored, stat.size);
+        let report = run(&mut disk, FileSystem::fsck(false)).unwrap();
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn new_files_should_get_the_disks_compression() {
+        let sb = SuperBlock::with_journal(80)
+            .with_bitmaps()
+            .with_compression(Compression::Lz);
+        let device = MemDisk::new();
+        FileSystem::format_with(&device, sb).unwrap();
+        let mut disk = Disk::new(&device);
+        let sb = run(&mut disk, SuperBlock::get_super_block()).unwrap();
+        assert_eq!(sb.compression, Compression::Lz);
+        assert_eq!(sb.features & FEATURE_COMPRESSION, FEATURE_COMPRESSION);
+
+        let text = vec![b'z'; 400];
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), text.clone()),
+        )
+        .unwrap();
+        let stat = run(&mut disk, FileSystem::stat("shrek.txt".into())).unwrap();
+        assert_eq!(stat.compression, Compression::Lz);
+        assert!(stat.blocks < 8); // 8 blocks kept as it is
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok(text));
     }
 }
@@ -0,0 +1,243 @@
+use super::block::*;
+use super::disk::*;
+use super::error::*;
+use super::inode::Inode;
+
+// Allocation bitmaps, one bit per block or inode, a set bit means in use
+// Bit n is for block or inode n, so bit 0 is never used
+// Each bitmap block holds BITMAP_BYTES bytes, small enough for any block size
+pub static BITMAP_BYTES: u32 = 1024;
+static BITS_PER_BLOCK: u32 = BITMAP_BYTES * 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BitmapKind {
+    Blocks,
+    Inodes,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bitmap {
+    bits: Vec<u8>,
+}
+
+impl Bitmap {
+    // Room for bits 0 to len, rounded up to whole bitmap blocks
+    pub fn new(len: u32) -> Bitmap {
+        Bitmap {
+            bits: vec![0; (Bitmap::blocks_needed(len) * BITMAP_BYTES) as usize],
+        }
+    }
+
+    // How many bitmap blocks it takes to hold bits 0 to len
+    pub fn blocks_needed(len: u32) -> u32 {
+        len / BITS_PER_BLOCK + 1
+    }
+
+    pub fn get(&self, n: u32) -> bool {
+        self.bits
+            .get(n as usize / 8)
+            .map(|x| x & (1 << (n % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    pub fn set(&mut self, n: u32, used: bool) {
+        if let Some(x) = self.bits.get_mut(n as usize / 8) {
+            if used {
+                *x |= 1 << (n % 8);
+            } else {
+                *x &= !(1 << (n % 8));
+            }
+        }
+    }
+
+    // Every clear bit in range, in order
+    pub fn clear_in(&self, range: std::ops::Range<u32>) -> Vec<u32> {
+        range.filter(|n| !self.get(*n)).collect()
+    }
+
+    fn range(sb: &SuperBlock, kind: BitmapKind) -> std::ops::Range<u32> {
+        match kind {
+            BitmapKind::Blocks => sb.get_block_bitmap_range(),
+            BitmapKind::Inodes => sb.get_inode_bitmap_range(),
+        }
+    }
+
+    // Disks formatted before bitmaps existed give back None
+    pub fn read<'a>(sb: &SuperBlock, kind: BitmapKind) -> DiskAction<'a, FsResult<Option<Bitmap>>> {
+        let range = Bitmap::range(sb, kind);
+        if range.is_empty() {
+            return unit(Ok(None));
+        }
+        let size = range.len() * BITMAP_BYTES as usize;
+        let reads = range.map(Block::get_block).collect();
+        let d = sequence_result(reads);
+        map_result(
+            d,
+            Box::new(move |blocks| {
+                let mut bits: Vec<u8> = blocks.into_iter().flat_map(|b| b.data).collect();
+                bits.resize(size, 0);
+                Some(Bitmap { bits })
+            }),
+        )
+    }
+
+    // Only the bitmap blocks that differ from old are written
+    pub fn write<'a>(
+        sb: &SuperBlock,
+        kind: BitmapKind,
+        old: Option<Bitmap>,
+        new: Bitmap,
+    ) -> DiskAction<'a, FsResult<()>> {
+        let writes = Bitmap::range(sb, kind)
+            .enumerate()
+            .filter_map(|(i, number)| {
+                let start = i * BITMAP_BYTES as usize;
+                let end = start + BITMAP_BYTES as usize;
+                let chunk =
+                    |b: &Bitmap| b.bits.get(start..end.min(b.bits.len())).map(|x| x.to_vec());
+                let data = chunk(&new).unwrap_or_default();
+                if old.as_ref().map(chunk) == Some(Some(data.clone())) {
+                    return None;
+                }
+                Some(Block::write_block(Block {
+                    number,
+                    b_type: BlockType::End,
+                    data,
+                }))
+            })
+            .collect();
+        map_result(sequence_result(writes), Box::new(|_| ()))
+    }
+
+    // Set the bits for numbers, does nothing on disks without bitmaps
+    pub fn mark<'a>(
+        kind: BitmapKind,
+        numbers: Vec<u32>,
+        used: bool,
+    ) -> DiskAction<'a, FsResult<()>> {
+        let d = SuperBlock::get_super_block();
+        flat_map_result(
+            d,
+            Box::new(move |sb| {
+                let numbers = numbers.clone();
+                let d = Bitmap::read(&sb, kind);
+                flat_map_result(
+                    d,
+                    Box::new(move |old: Option<Bitmap>| match old.clone() {
+                        Some(mut new) => {
+                            numbers.iter().for_each(|n| new.set(*n, used));
+                            Bitmap::write(&sb, kind, old, new)
+                        }
+                        None => unit(Ok(())),
+                    }),
+                )
+            }),
+        )
+    }
+
+    // Work both bitmaps out from the blocks and inode table themselves
+    // Everything before the storage blocks is always in use, and so are the backups after
+    pub fn build<'a>(sb: SuperBlock) -> DiskAction<'a, FsResult<(Bitmap, Bitmap)>> {
+        let blocks = sb.get_storage_block_range().map(Block::get_block).collect();
+        let blocks = sequence_result(blocks);
+        let inodes = Inode::get_inode_table();
+        map2_result(
+            blocks,
+            inodes,
+            Box::new(move |blocks, inodes| {
+                let mut block_bitmap = Bitmap::new(sb.total_blocks);
+                (1..sb.get_storage_block_range().start).for_each(|n| block_bitmap.set(n, true));
+                sb.get_backup_block_range()
+                    .for_each(|n| block_bitmap.set(n, true));
+                blocks
+                    .iter()
+                    .filter(|b| b.b_type != BlockType::Free)
+                    .for_each(|b| block_bitmap.set(b.number, true));
+
+                let mut inode_bitmap = Bitmap::new(sb.get_inode_capacity());
+                inodes
+                    .iter()
+                    .filter(|i| i.start_block.is_some())
+                    .for_each(|i| inode_bitmap.set(i.number, true));
+                (block_bitmap, inode_bitmap)
+            }),
+        )
+    }
+
+    // Rewrite both bitmaps so they match the disk
+    pub fn rebuild<'a>() -> DiskAction<'a, FsResult<()>> {
+        let d = SuperBlock::get_super_block();
+        flat_map_result(
+            d,
+            Box::new(|sb: SuperBlock| {
+                if sb.get_block_bitmap_range().is_empty() {
+                    return unit(Ok(()));
+                }
+                let s = sb.clone();
+                let d = Bitmap::build(sb.clone());
+                flat_map_result(
+                    d,
+                    Box::new(move |(blocks, inodes)| {
+                        let write_blocks = Bitmap::write(&s, BitmapKind::Blocks, None, blocks);
+                        let write_inodes = Bitmap::write(&s, BitmapKind::Inodes, None, inodes);
+                        map2_result(write_blocks, write_inodes, Box::new(|_, _| ()))
+                    }),
+                )
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
+    use super::*;
+
+    #[test]
+    fn bits_should_set_and_clear() {
+        let mut bitmap = Bitmap::new(20);
+        bitmap.set(3, true);
+        bitmap.set(20, true);
+        assert!(bitmap.get(3) && bitmap.get(20));
+        assert_eq!(bitmap.clear_in(1..6), vec![1, 2, 4, 5]);
+        bitmap.set(3, false);
+        assert!(!bitmap.get(3));
+        assert!(!bitmap.get(500));
+        assert_eq!(Bitmap::blocks_needed(BITS_PER_BLOCK - 1), 1);
+        assert_eq!(Bitmap::blocks_needed(BITS_PER_BLOCK), 2);
+    }
+
+    #[test]
+    fn bitmaps_should_follow_saves_and_removes() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        let (stored, disk) = Bitmap::read(&sb, BitmapKind::Blocks)(disk);
+        let (built, disk) = Bitmap::build(sb.clone())(disk);
+        let (blocks, inodes) = built.unwrap();
+        assert_eq!(stored, Ok(Some(blocks)));
+        let (stored, disk) = Bitmap::read(&sb, BitmapKind::Inodes)(disk);
+        assert_eq!(stored, Ok(Some(inodes.clone())));
+        assert!(inodes.get(2));
+
+        let (_, disk) = FileSystem::remove_file("shrek.txt".into())(disk);
+        let (stored, disk) = Bitmap::read(&sb, BitmapKind::Inodes)(disk);
+        assert!(!stored.unwrap().unwrap().get(2));
+        let (stored, disk) = Bitmap::read(&sb, BitmapKind::Blocks)(disk);
+        let (built, _) = Bitmap::build(sb)(disk);
+        assert_eq!(stored, Ok(Some(built.unwrap().0)));
+    }
+
+    #[test]
+    fn legacy_disks_should_have_no_bitmap() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let (bitmap, _) = Bitmap::read(&sb.unwrap(), BitmapKind::Blocks)(disk);
+        assert_eq!(bitmap, Ok(None));
+    }
+}
@@ -1,15 +1,106 @@
+use super::bitmap::*;
+use super::compress::Compression;
 use super::disk::*;
-use super::line_handler;
+use super::encrypt::Encryption;
+use super::error::*;
+use super::extent::Extent;
+use super::geometry::Geometry;
+use super::inode::Layout;
+use super::pointers::Pointers;
+use super::snapshot::{self, Snapshot};
 use super::utils;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 static MAGIC_NUMBER: &str = "0x70736575646F4653";
 
+// Format version of disks formatted or upgraded by this build
+// 0 is every disk from before versions were recorded
+pub static FORMAT_VERSION: u32 = 3;
+// Disks from this version on have a checksum on every block, the superblock included
+pub static CHECKSUM_VERSION: u32 = 3;
+// Features a disk uses, mounting fails on any bit not known here
+pub static FEATURE_JOURNAL: u32 = 1;
+pub static FEATURE_BITMAPS: u32 = 1 << 1;
+pub static FEATURE_POINTERS: u32 = 1 << 2; // New files use direct and indirect pointers
+pub static FEATURE_GEOMETRY: u32 = 1 << 3; // Geometry isn't the default one
+pub static FEATURE_BACKUP_SUPER_BLOCKS: u32 = 1 << 4;
+pub static FEATURE_SNAPSHOTS: u32 = 1 << 5; // Blocks held by snapshots must never be written
+pub static FEATURE_COMPRESSION: u32 = 1 << 6; // Files may keep their data compressed
+pub static FEATURE_ENCRYPTION: u32 = 1 << 7; // Blocks are sealed, a passphrase is needed to mount
+static KNOWN_FEATURES: u32 = FEATURE_JOURNAL
+    | FEATURE_BITMAPS
+    | FEATURE_POINTERS
+    | FEATURE_GEOMETRY
+    | FEATURE_BACKUP_SUPER_BLOCKS
+    | FEATURE_SNAPSHOTS
+    | FEATURE_COMPRESSION
+    | FEATURE_ENCRYPTION;
+// A header and room for what a small operation changes in blocks already in use,
+// i.e rewriting a few data blocks along with its inode, bitmaps and directory
+static MIN_JOURNAL_BLOCKS: u32 = 12;
+// Copies of the superblock kept in the last blocks of the disk
+// They're found from the end of the device, so a broken superblock isn't needed to find them
+pub static BACKUP_SUPER_BLOCKS: u32 = 2;
+
+// Binary block layout, all numbers little endian
+// [number: u32][type: u8][next: u32][data length: u32][data][checksum: u32]
+// The checksum covers everything before it. Blocks on disks from before CHECKSUM_VERSION
+// may end after their data, those are the only ones read without a checksum
+pub static BLOCK_HEADER_SIZE: usize = 13;
+pub static BLOCK_CHECKSUM_SIZE: usize = 4;
+// Superblock layout, it takes up the whole of block 1
+// [magic number: 8 bytes][total blocks: u32][journal blocks: u32]
+// [block bitmap blocks: u32][inode bitmap blocks: u32][layout: u32]
+// [inode percent: u32][block data size: u32][inodes per block: u32]
+// [version: u32][features: u32][backup blocks: u32][snapshot count: u32]
+// then for each snapshot [table block: u32][created: u64][name length: u32][name]
+// then [compression: u32][encryption: u32, 0 none, 1 data, 2 data and metadata]
+// then when encrypted [rounds: u32][salt length: u32][salt][check length: u32][check]
+// then [checksum: u32] covering everything before it, a superblock that fails it
+// is as broken as one that can't be parsed. Older superblocks have none and read it as 0
+// Fields added later are read as 0 from older, shorter superblocks,
+// geometry read as 0 is what disks used before it was recorded
+static SUPER_BLOCK_SIZE: usize = 12;
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Block {
     pub number: u32,
     pub b_type: BlockType,
-    pub data: String,
+    #[serde(with = "block_data")]
+    pub data: Vec<u8>,
+}
+
+// Line files keep block data as text when it is text, anything else as hex
+mod block_data {
+    use super::utils;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Data {
+        Text(String),
+        Bytes { hex: String },
+    }
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(data) {
+            Ok(text) => Data::Text(text.into()),
+            Err(_) => Data::Bytes {
+                hex: utils::to_hex(data),
+            },
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        match Data::deserialize(d)? {
+            Data::Text(text) => Ok(text.into_bytes()),
+            Data::Bytes { hex } => {
+                utils::from_hex(&hex).ok_or_else(|| serde::de::Error::custom("data isn't hex"))
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -23,68 +114,213 @@ pub enum BlockType {
 pub struct SuperBlock {
     pub magic_number: String,
     pub total_blocks: u32,
+    #[serde(default)]
+    pub journal_blocks: u32, // 0 means the disk has no journal
+    #[serde(default)]
+    pub block_bitmap_blocks: u32, // 0 means the disk has no bitmaps
+    #[serde(default)]
+    pub inode_bitmap_blocks: u32,
+    #[serde(default = "default_layout")]
+    pub layout: Layout, // Layout new files get
+    #[serde(default)]
+    pub geometry: Geometry,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub features: u32,
+    #[serde(default)]
+    pub backup_blocks: u32, // Superblock copies at the end of the disk
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression, // Compression new files get
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+}
+
+fn default_layout() -> Layout {
+    Layout::Extents
 }
 
 impl Block {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (tag, next) = match self.b_type {
+            BlockType::Free => (0, 0),
+            BlockType::Next(n) => (1, n),
+            BlockType::End => (2, 0),
+        };
+        let mut bytes =
+            Vec::with_capacity(BLOCK_HEADER_SIZE + self.data.len() + BLOCK_CHECKSUM_SIZE);
+        utils::put_u32(&mut bytes, self.number);
+        bytes.push(tag);
+        utils::put_u32(&mut bytes, next);
+        utils::put_u32(&mut bytes, self.data.len() as u32);
+        bytes.extend_from_slice(&self.data);
+        let checksum = Block::checksum(&bytes);
+        utils::put_u32(&mut bytes, checksum);
+        bytes
+    }
+
+    // 0 is what padding reads as, so it's never used as a checksum
+    pub fn checksum(bytes: &[u8]) -> u32 {
+        utils::crc32(bytes).max(1)
+    }
+
+    // Anything after the checksum is padding and gets ignored
+    // A missing checksum reads as 0 and fails like a wrong one
+    pub fn from_bytes(block_number: u32, bytes: &[u8]) -> FsResult<Block> {
+        Block::parse(block_number, bytes, true)
+    }
+
+    // For disks from before checksums, where blocks may have none
+    pub fn from_unchecked_bytes(block_number: u32, bytes: &[u8]) -> FsResult<Block> {
+        Block::parse(block_number, bytes, false)
+    }
+
+    // checked is whether a block without a checksum is broken
+    pub fn parse(block_number: u32, bytes: &[u8], checked: bool) -> FsResult<Block> {
+        let corrupt = FsError::Corrupt {
+            block: block_number,
+        };
+        let number = utils::get_u32(bytes, 0).ok_or_else(|| corrupt.clone())?;
+        let next = utils::get_u32(bytes, 5).ok_or_else(|| corrupt.clone())?;
+        let b_type = match bytes.get(4) {
+            Some(0) => BlockType::Free,
+            Some(1) => BlockType::Next(next),
+            Some(2) => BlockType::End,
+            _ => return Err(corrupt),
+        };
+        let len = utils::get_u32(bytes, 9).ok_or_else(|| corrupt.clone())? as usize;
+        let data = bytes
+            .get(BLOCK_HEADER_SIZE..BLOCK_HEADER_SIZE + len)
+            .ok_or_else(|| corrupt.clone())?
+            .to_vec();
+        let checksum = utils::get_u32(bytes, BLOCK_HEADER_SIZE + len).unwrap_or(0);
+        let unchecked = checksum == 0 && !checked;
+        if !unchecked && checksum != Block::checksum(&bytes[..BLOCK_HEADER_SIZE + len]) {
+            return Err(FsError::ChecksumMismatch(block_number));
+        }
+        if number != block_number {
+            return Err(corrupt);
+        }
+        Ok(Block {
+            number,
+            b_type,
+            data,
+        })
+    }
+
     pub fn free(self) -> Block {
         Block {
             number: self.number,
             b_type: BlockType::Free,
-            data: String::new(),
+            data: vec![],
         }
     }
 
-    pub fn get_block<'a>(block_number: u32) -> DiskAction<'a, Option<Block>> {
-        let d = line_handler::read(block_number);
-        let parse = Box::new(move |s: String| serde_json::from_str::<Block>(&s[..]).ok());
-        let d = map(d, utils::lift(parse));
-        map(d, Box::new(|x| x.flatten()))
+    pub fn get_block<'a>(block_number: u32) -> DiskAction<'a, FsResult<Block>> {
+        let d = read_block(block_number);
+        Box::new(move |disk| {
+            let (x, disk) = d(disk);
+            let block = x.and_then(|s| Block::parse(block_number, &s, disk.checksums));
+            (block, disk)
+        })
     }
 
-    pub fn blocks_to_data(a: Vec<Block>) -> String {
-        a.into_iter().fold("".into(), |acc, curr| acc + &curr.data)
+    pub fn blocks_to_data(a: Vec<Block>) -> Vec<u8> {
+        a.into_iter().flat_map(|b| b.data).collect()
     }
 
-    pub fn get_all_blocks<'a>() -> DiskAction<'a, Vec<Block>> {
+    pub fn get_all_blocks<'a>() -> DiskAction<'a, FsResult<Vec<Block>>> {
         let d = SuperBlock::get_super_block();
-
-        let d = map(d, utils::lift(Box::new(|x| x.get_storage_block_range())));
-        let d = flat_map(
+        flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(|storage_range| {
-                let reads = storage_range
-                    .into_iter()
-                    .map(|x| Block::get_block(x))
-                    .collect();
-                sequence(reads)
-            })),
-        );
-        let d = map(d, utils::lift(Box::new(utils::remove_options)));
-        map(d, Box::new(|x| x.unwrap_or(vec![])))
+            Box::new(|sb| {
+                let reads = sb.get_storage_block_range().map(Block::get_block).collect();
+                sequence_result(reads)
+            }),
+        )
     }
 
-    pub fn get_all_free_data_blocks<'a>() -> DiskAction<'a, Vec<Block>> {
-        let d = Block::get_all_blocks();
-        map(
+    // The block bitmap says which blocks are free without reading them
+    // Disks without one have every storage block read instead
+    pub fn get_all_free_data_blocks<'a>() -> DiskAction<'a, FsResult<Vec<Block>>> {
+        let d = SuperBlock::get_super_block();
+        flat_map_result(
             d,
-            Box::new(move |x| {
-                x.into_iter()
-                    .filter(|a| a.b_type == BlockType::Free)
-                    .collect()
+            Box::new(|sb: SuperBlock| {
+                let storage = sb.get_storage_block_range();
+                let d = Bitmap::read(&sb, BitmapKind::Blocks);
+                flat_map_result(
+                    d,
+                    Box::new(move |bitmap| match bitmap {
+                        Some(bitmap) => unit(Ok(bitmap
+                            .clear_in(storage.clone())
+                            .into_iter()
+                            .map(|number| Block {
+                                number,
+                                b_type: BlockType::Free,
+                                data: vec![],
+                            })
+                            .collect())),
+                        None => map_result(
+                            sequence_result(storage.clone().map(Block::get_block).collect()),
+                            Box::new(|x| {
+                                x.into_iter()
+                                    .filter(|a| a.b_type == BlockType::Free)
+                                    .collect()
+                            }),
+                        ),
+                    }),
+                )
             }),
         )
     }
 
-    pub fn get_free_data_blocks<'a>(num: usize) -> DiskAction<'a, Vec<Block>> {
+    // Fails with NoSpace when there aren't num free blocks
+    pub fn get_free_data_blocks<'a>(num: usize) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        Block::get_free_data_blocks_near(num, None, vec![])
+    }
+
+    // Contiguous runs are picked where possible, starting straight after near
+    // Blocks in exclude are taken already, but aren't marked in use yet
+    pub fn get_free_data_blocks_near<'a>(
+        num: usize,
+        near: Option<u32>,
+        exclude: Vec<u32>,
+    ) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        if num == 0 {
+            return unit(Ok(vec![]));
+        }
         let d = Block::get_all_free_data_blocks();
-        map(d, Box::new(move |x| x.into_iter().take(num).collect()))
+        map(
+            d,
+            Box::new(move |x| {
+                x.and_then(|blocks| {
+                    let free: Vec<u32> = blocks
+                        .iter()
+                        .map(|b| b.number)
+                        .filter(|n| !exclude.contains(n))
+                        .collect();
+                    let picked = Extent::pick_free(&free, num, near).ok_or(FsError::NoSpace)?;
+                    Ok(picked
+                        .into_iter()
+                        .map(|number| Block {
+                            number,
+                            b_type: BlockType::Free,
+                            data: vec![],
+                        })
+                        .collect())
+                })
+            }),
+        )
     }
 
-    pub fn set_data_blocks_data(d: (Vec<Block>, Vec<String>)) -> Vec<Block> {
+    pub fn set_data_blocks_data(d: (Vec<Block>, Vec<Vec<u8>>)) -> Vec<Block> {
         let (blocks, data) = d;
         blocks
             .into_iter()
-            .zip(data.into_iter())
+            .zip(data)
             .map(|(mut block, datum)| {
                 block.data = datum;
                 block
@@ -92,90 +328,628 @@ impl Block {
             .collect()
     }
 
-    pub fn write_block<'a>(b: Block) -> DiskAction<'a, Option<Block>> {
-        let d = line_handler::write(
-            b.number,
-            serde_json::to_string(&b).ok().unwrap_or("".into()),
-        );
-        let d = map(d, utils::lift(Box::new(|x| serde_json::from_str(&x).ok())));
-        map(d, Box::new(|x| x.flatten()))
+    pub fn write_block<'a>(b: Block) -> DiskAction<'a, FsResult<Block>> {
+        let d = write_block(b.number, b.to_bytes());
+        map_result(d, Box::new(move |_| b.clone()))
     }
 
-    pub fn free_blocks<'a>(b: Vec<Block>) -> DiskAction<'a, Vec<Block>> {
+    // Blocks a snapshot holds stay as they are, gives back the ones freed
+    pub fn free_blocks<'a>(b: Vec<Block>) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        let d = snapshot::held_blocks();
+        flat_map_result(
+            d,
+            Box::new(move |held| Block::free_blocks_except(b.clone(), &held)),
+        )
+    }
+
+    // free_blocks with the held blocks already read, i.e by an operation freeing more than once
+    pub fn free_blocks_except<'a>(
+        b: Vec<Block>,
+        held: &HashSet<u32>,
+    ) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        let b: Vec<Block> = b
+            .into_iter()
+            .filter(|x| !held.contains(&x.number))
+            .collect();
+        let numbers = b.iter().map(|x| x.number).collect();
         let d = b
             .into_iter()
             .map(|x| x.free())
-            .map(|x| Block::write_block(x))
+            .map(Block::write_block)
             .collect();
-        let d = sequence(d);
-
-        map(d, Box::new(utils::remove_options))
+        let d = sequence_result(d);
+        let mark = Bitmap::mark(BitmapKind::Blocks, numbers, false);
+        map2_result(d, mark, Box::new(|a, _| a))
     }
 }
 
+// Whether blocks on a device must have checksums, only an old superblock says they needn't
+pub fn checksums_required(device: &dyn BlockDevice) -> bool {
+    device
+        .read_block(1)
+        .ok()
+        .and_then(|b| SuperBlock::from_bytes(&b))
+        .is_none_or(|sb| sb.has_checksums())
+}
+
 impl SuperBlock {
+    pub fn has_checksums(&self) -> bool {
+        self.version >= CHECKSUM_VERSION
+    }
+
     pub fn valid_super_block(&self) -> bool {
         self.magic_number == MAGIC_NUMBER
     }
 
+    // Disks from a newer build, or using features this one doesn't know, can't be mounted
+    pub fn check_supported(&self) -> FsResult<()> {
+        let unknown = self.features & !KNOWN_FEATURES;
+        if self.version > FORMAT_VERSION {
+            Err(FsError::UnsupportedFormat(self.version))
+        } else if unknown != 0 {
+            Err(FsError::UnsupportedFeatures(unknown))
+        } else {
+            Ok(())
+        }
+    }
+
+    // The features this superblock's layout needs
+    pub fn features_in_use(&self) -> u32 {
+        let mut features = 0;
+        if self.journal_blocks > 0 {
+            features |= FEATURE_JOURNAL;
+        }
+        if self.block_bitmap_blocks > 0 {
+            features |= FEATURE_BITMAPS;
+        }
+        if self.layout == Layout::Pointers {
+            features |= FEATURE_POINTERS;
+        }
+        if self.geometry != Geometry::default() {
+            features |= FEATURE_GEOMETRY;
+        }
+        if self.backup_blocks > 0 {
+            features |= FEATURE_BACKUP_SUPER_BLOCKS;
+        }
+        if !self.snapshots.is_empty() {
+            features |= FEATURE_SNAPSHOTS;
+        }
+        // Stays once any file has been compressed, whatever new files get
+        if !self.compression.is_none() || self.features & FEATURE_COMPRESSION != 0 {
+            features |= FEATURE_COMPRESSION;
+        }
+        if self.encryption.is_some() {
+            features |= FEATURE_ENCRYPTION;
+        }
+        features
+    }
+
+    // Stamp the current version along with the features in use
+    pub fn with_current_version(self) -> SuperBlock {
+        SuperBlock {
+            version: FORMAT_VERSION,
+            features: self.features_in_use(),
+            ..self
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes_unchecked();
+        let checksum = Block::checksum(&bytes);
+        utils::put_u32(&mut bytes, checksum);
+        bytes
+    }
+
+    // Everything the checksum covers
+    fn to_bytes_unchecked(&self) -> Vec<u8> {
+        let magic =
+            u64::from_str_radix(self.magic_number.trim_start_matches("0x"), 16).unwrap_or_default();
+        let mut bytes = magic.to_be_bytes().to_vec();
+        utils::put_u32(&mut bytes, self.total_blocks);
+        utils::put_u32(&mut bytes, self.journal_blocks);
+        utils::put_u32(&mut bytes, self.block_bitmap_blocks);
+        utils::put_u32(&mut bytes, self.inode_bitmap_blocks);
+        utils::put_u32(&mut bytes, self.layout.to_u32());
+        utils::put_u32(&mut bytes, self.geometry.inode_percent);
+        utils::put_u32(&mut bytes, self.geometry.block_data_size);
+        utils::put_u32(&mut bytes, self.geometry.inodes_per_block);
+        utils::put_u32(&mut bytes, self.version);
+        utils::put_u32(&mut bytes, self.features);
+        utils::put_u32(&mut bytes, self.backup_blocks);
+        utils::put_u32(&mut bytes, self.snapshots.len() as u32);
+        for s in self.snapshots.iter() {
+            utils::put_u32(&mut bytes, s.table);
+            utils::put_u64(&mut bytes, s.created);
+            utils::put_u32(&mut bytes, s.name.len() as u32);
+            bytes.extend_from_slice(s.name.as_bytes());
+        }
+        utils::put_u32(&mut bytes, self.compression.to_u32());
+        match &self.encryption {
+            None => utils::put_u32(&mut bytes, 0),
+            Some(e) => {
+                utils::put_u32(&mut bytes, if e.metadata { 2 } else { 1 });
+                utils::put_u32(&mut bytes, e.rounds);
+                utils::put_u32(&mut bytes, e.salt.len() as u32);
+                bytes.extend_from_slice(&e.salt);
+                utils::put_u32(&mut bytes, e.check.len() as u32);
+                bytes.extend_from_slice(&e.check);
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<SuperBlock> {
+        if bytes.len() < SUPER_BLOCK_SIZE {
+            return None;
+        }
+        let mut magic = [0; 8];
+        magic.copy_from_slice(&bytes[0..8]);
+        let default = Geometry::default();
+        let or_default = |offset, x| match utils::get_u32(bytes, offset) {
+            Some(0) | None => x,
+            Some(n) => n,
+        };
+        let (snapshots, end) = SuperBlock::snapshots_from_bytes(bytes)?;
+        let sb = SuperBlock {
+            magic_number: format!("0x{:016X}", u64::from_be_bytes(magic)),
+            total_blocks: utils::get_u32(bytes, 8)?,
+            journal_blocks: utils::get_u32(bytes, 12).unwrap_or(0),
+            block_bitmap_blocks: utils::get_u32(bytes, 16).unwrap_or(0),
+            inode_bitmap_blocks: utils::get_u32(bytes, 20).unwrap_or(0),
+            layout: Layout::from_u32(utils::get_u32(bytes, 24).unwrap_or(0))?,
+            geometry: Geometry {
+                inode_percent: or_default(28, default.inode_percent),
+                block_data_size: or_default(32, default.block_data_size),
+                inodes_per_block: or_default(36, default.inodes_per_block),
+            },
+            version: utils::get_u32(bytes, 40).unwrap_or(0),
+            features: utils::get_u32(bytes, 44).unwrap_or(0),
+            backup_blocks: utils::get_u32(bytes, 48).unwrap_or(0),
+            snapshots,
+            compression: Compression::from_u32(utils::get_u32(bytes, end).unwrap_or(0))?,
+            encryption: SuperBlock::encryption_from_bytes(bytes, end + 4)?,
+        };
+        // The checksum comes straight after what was parsed
+        let end = sb.to_bytes_unchecked().len();
+        match utils::get_u32(bytes, end).unwrap_or(0) {
+            0 if sb.version < CHECKSUM_VERSION => Some(sb),
+            checksum if checksum == Block::checksum(bytes.get(..end)?) => Some(sb),
+            _ => None,
+        }
+    }
+
+    // Some(None) when the disk isn't encrypted, None when it can't be read
+    fn encryption_from_bytes(bytes: &[u8], offset: usize) -> Option<Option<Encryption>> {
+        let metadata = match utils::get_u32(bytes, offset).unwrap_or(0) {
+            0 => return Some(None),
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        let rounds = utils::get_u32(bytes, offset + 4)?;
+        let salt_len = utils::get_u32(bytes, offset + 8)? as usize;
+        let salt = bytes.get(offset + 12..offset + 12 + salt_len)?.to_vec();
+        let offset = offset + 12 + salt_len;
+        let check_len = utils::get_u32(bytes, offset)? as usize;
+        let check = bytes.get(offset + 4..offset + 4 + check_len)?.to_vec();
+        Some(Some(Encryption {
+            metadata,
+            rounds,
+            salt,
+            check,
+        }))
+    }
+
+    // Snapshots along with where the bytes after them start
+    fn snapshots_from_bytes(bytes: &[u8]) -> Option<(Vec<Snapshot>, usize)> {
+        let count = utils::get_u32(bytes, 52).unwrap_or(0);
+        let mut offset = 56;
+        let mut snapshots = vec![];
+        for _ in 0..count {
+            let table = utils::get_u32(bytes, offset)?;
+            let created = utils::get_u64(bytes, offset + 4)?;
+            let len = utils::get_u32(bytes, offset + 12)? as usize;
+            let name = bytes.get(offset + 16..offset + 16 + len)?;
+            snapshots.push(Snapshot {
+                name: String::from_utf8(name.to_vec()).ok()?,
+                created,
+                table,
+            });
+            offset += 16 + len;
+        }
+        Some((snapshots, offset))
+    }
+
     // Return the super_block for a disk
-    pub fn get_super_block<'a>() -> DiskAction<'a, Option<SuperBlock>> {
-        let d = Block::get_block(1);
-        let parse = Box::new(move |s: Block| serde_json::from_str::<SuperBlock>(&s.data).ok());
-        let d = map(d, utils::lift(parse));
-        map(d, Box::new(|x| x.flatten()))
+    // A first block that can't be parsed or fails its checksum is reported as InvalidSuperBlock
+    pub fn get_super_block<'a>() -> DiskAction<'a, FsResult<SuperBlock>> {
+        let d = read_block(1);
+        map(
+            d,
+            Box::new(|x| match x {
+                Ok(b) => SuperBlock::from_bytes(&b).ok_or(FsError::InvalidSuperBlock),
+                Err(FsError::OutOfRange(_)) => Err(FsError::InvalidSuperBlock),
+                Err(e) => Err(e),
+            }),
+        )
+    }
+
+    // Every copy is written, so the backups never fall behind
+    pub fn write_super_block<'a>(sb: SuperBlock) -> DiskAction<'a, FsResult<SuperBlock>> {
+        let bytes = sb.to_bytes();
+        let writes = std::iter::once(1)
+            .chain(sb.get_backup_block_range())
+            .map(|n| write_block(n, bytes.clone()))
+            .collect();
+        map_result(sequence_result(writes), Box::new(move |_| sb.clone()))
+    }
+
+    // Look for a good copy in the last blocks of the device, for when block 1 is broken
+    pub fn get_backup_super_block<'a>() -> DiskAction<'a, FsResult<SuperBlock>> {
+        Box::new(|disk| {
+            let mut disk = disk;
+            let len = match disk.device.len() {
+                Ok(len) => len,
+                Err(e) => return (Err(e), disk),
+            };
+            for n in (len.saturating_sub(BACKUP_SUPER_BLOCKS) + 1..len + 1).rev() {
+                let (b, disk2) = read_block(n)(disk);
+                disk = disk2;
+                let sb = b.ok().and_then(|b| SuperBlock::from_bytes(&b));
+                match sb {
+                    Some(sb)
+                        if sb.valid_super_block() && sb.get_backup_block_range().contains(&n) =>
+                    {
+                        return (Ok(sb), disk)
+                    }
+                    _ => (),
+                }
+            }
+            (Err(FsError::InvalidSuperBlock), disk)
+        })
+    }
+
+    // Journal comes straight after the superblock
+    pub fn get_journal_block_range(&self) -> std::ops::Range<u32> {
+        2..2 + self.journal_blocks
     }
 
-    // Inode table blocks take up 10% of available blocks
+    // Bitmaps come after the journal, blocks then inodes
+    pub fn get_block_bitmap_range(&self) -> std::ops::Range<u32> {
+        let start = self.get_journal_block_range().end;
+        start..start + self.block_bitmap_blocks
+    }
+
+    pub fn get_inode_bitmap_range(&self) -> std::ops::Range<u32> {
+        let start = self.get_block_bitmap_range().end;
+        start..start + self.inode_bitmap_blocks
+    }
+
+    // Inode table blocks take up inode percent of the disk's blocks
     pub fn get_inode_table_block_range(&self) -> std::ops::Range<u32> {
-        (2..(self.total_blocks as f32 * 0.10) as u32 + 2)
+        let inodes_start = self.get_inode_bitmap_range().end;
+        inodes_start..inodes_start + self.geometry.inode_table_blocks(self.total_blocks)
     }
 
     pub fn get_storage_block_range(&self) -> std::ops::Range<u32> {
-        let inodes_end = (self.total_blocks as f32 * 0.10) as u32 + 2;
-        (inodes_end..self.total_blocks + 1)
+        let inodes_end = self.get_inode_table_block_range().end;
+        inodes_end..self.get_backup_block_range().start
+    }
+
+    // Backups come after storage, in the very last blocks
+    pub fn get_backup_block_range(&self) -> std::ops::Range<u32> {
+        let end = self.total_blocks + 1;
+        end.saturating_sub(self.backup_blocks)..end
     }
 
-    pub fn get_inode_count(&self) -> u32 {
-        let inode_table_blocks = (self.total_blocks as f32 * 0.10) as u32;
-        inode_table_blocks * self.total_blocks
+    // How many inodes the inode table can actually hold
+    pub fn get_inode_capacity(&self) -> u32 {
+        self.get_inode_table_block_range().len() as u32 * self.geometry.inodes_per_block
+    }
+
+    // Most bytes a file with layout can hold, files are worked on whole in memory,
+    // so a compressed one can't be bigger than it would be uncompressed either
+    pub fn max_file_size(&self, layout: Layout) -> u64 {
+        let blocks = self.get_storage_block_range().len() as u64;
+        let blocks = match layout {
+            Layout::Pointers => blocks.min(Pointers::max_blocks() as u64),
+            Layout::Extents | Layout::Chain => blocks,
+        };
+        blocks * self.geometry.block_data_size as u64
     }
 
     pub fn new(size: u32) -> SuperBlock {
         SuperBlock {
             magic_number: MAGIC_NUMBER.to_owned(),
             total_blocks: size,
+            journal_blocks: 0,
+            block_bitmap_blocks: 0,
+            inode_bitmap_blocks: 0,
+            layout: Layout::Extents,
+            geometry: Geometry::default(),
+            version: 0,
+            features: 0,
+            backup_blocks: 0,
+            snapshots: vec![],
+            compression: Compression::None,
+            encryption: None,
+        }
+    }
+
+    // Journal gets 10% of the disk, but enough for any one small operation.
+    // Disks too small to give it that without losing most of their blocks go without
+    pub fn with_journal(size: u32) -> SuperBlock {
+        let journal = (size / 10).max(MIN_JOURNAL_BLOCKS);
+        SuperBlock {
+            journal_blocks: if journal <= size / 4 { journal } else { 0 },
+            ..SuperBlock::new(size)
+        }
+    }
+
+    // Reserve room for allocation bitmaps
+    // Geometry changes how many inodes there are, so set it first.
+    // Rollback and fsck --repair can rewrite every bitmap and inode table block at once,
+    // so the journal grows to hold them and the superblock when the disk can spare it
+    pub fn with_bitmaps(self) -> SuperBlock {
+        let inodes = self.get_inode_capacity();
+        let block_bitmap_blocks = Bitmap::blocks_needed(self.total_blocks);
+        let inode_bitmap_blocks = Bitmap::blocks_needed(inodes);
+        let metadata =
+            2 + block_bitmap_blocks + inode_bitmap_blocks + inodes / self.geometry.inodes_per_block;
+        let journal_blocks = match self.journal_blocks {
+            0 => 0,
+            j if metadata <= self.total_blocks / 4 => j.max(metadata),
+            j => j,
+        };
+        SuperBlock {
+            journal_blocks,
+            block_bitmap_blocks,
+            inode_bitmap_blocks,
+            ..self
+        }
+    }
+
+    pub fn with_backups(self) -> SuperBlock {
+        SuperBlock {
+            backup_blocks: BACKUP_SUPER_BLOCKS,
+            ..self
+        }
+    }
+
+    pub fn with_geometry(self, geometry: Geometry) -> SuperBlock {
+        SuperBlock { geometry, ..self }
+    }
+
+    pub fn with_layout(self, layout: Layout) -> SuperBlock {
+        SuperBlock { layout, ..self }
+    }
+
+    pub fn with_compression(self, compression: Compression) -> SuperBlock {
+        SuperBlock {
+            compression,
+            ..self
+        }
+    }
+
+    pub fn with_encryption(self, encryption: Encryption) -> SuperBlock {
+        SuperBlock {
+            encryption: Some(encryption),
+            ..self
+        }
+    }
+
+    // Record a feature once something on the disk starts using it
+    pub fn add_feature<'a>(feature: u32) -> DiskAction<'a, FsResult<SuperBlock>> {
+        let d = SuperBlock::get_super_block();
+        flat_map_result(
+            d,
+            Box::new(move |sb: SuperBlock| match sb.features & feature {
+                0 => SuperBlock::write_super_block(SuperBlock {
+                    features: sb.features | feature,
+                    ..sb
+                }),
+                _ => unit(Ok(sb)),
+            }),
+        )
+    }
+
+    // The feature bit comes and goes with the snapshots
+    pub fn with_snapshots(self, snapshots: Vec<Snapshot>) -> SuperBlock {
+        let features = match snapshots.is_empty() {
+            true => self.features & !FEATURE_SNAPSHOTS,
+            false => self.features | FEATURE_SNAPSHOTS,
+        };
+        SuperBlock {
+            snapshots,
+            features,
+            ..self
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::inode::Inode;
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
     use super::*;
 
     #[test]
     fn get_block_should_return_expected() {
         let expected_block = Block {
-            number: 1,
-            data: "{\"magic_number\":\"0x70736575646F4653\",\"total_blocks\":10}".into(),
-            b_type: BlockType::End,
+            number: 4,
+            data: "Somebody".into(),
+            b_type: BlockType::Next(6),
         };
-        let disk = Disk::new("./test-files/sda1");
-        let (data, disk) = Block::get_block(1)(disk);
-        assert_eq!(data, Some(expected_block));
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, disk) = Block::get_block(4)(disk);
+        assert_eq!(data, Ok(expected_block));
         assert_eq!(disk.reads, 1);
     }
 
+    #[test]
+    fn block_bytes_should_round_trip() {
+        let block = Block {
+            number: 7,
+            data: "Shrek".into(),
+            b_type: BlockType::Next(9),
+        };
+        let mut bytes = block.to_bytes();
+        assert_eq!(bytes.len(), BLOCK_HEADER_SIZE + 5 + BLOCK_CHECKSUM_SIZE);
+        bytes.resize(64, 0);
+        assert_eq!(Block::from_bytes(7, &bytes), Ok(block.clone()));
+        // Blocks from before checksums still read, but only when asked for
+        assert_eq!(
+            Block::from_unchecked_bytes(7, &bytes[0..BLOCK_HEADER_SIZE + 5]),
+            Ok(block.clone())
+        );
+        assert_eq!(
+            Block::from_bytes(7, &bytes[0..BLOCK_HEADER_SIZE + 5]),
+            Err(FsError::ChecksumMismatch(7))
+        );
+        // A zeroed trailer is no excuse either
+        let mut zeroed = bytes.clone();
+        zeroed[BLOCK_HEADER_SIZE + 5..].fill(0);
+        assert_eq!(
+            Block::from_bytes(7, &zeroed),
+            Err(FsError::ChecksumMismatch(7))
+        );
+        assert_eq!(Block::from_unchecked_bytes(7, &zeroed), Ok(block));
+        assert_eq!(
+            Block::from_bytes(8, &bytes),
+            Err(FsError::Corrupt { block: 8 })
+        );
+        assert_eq!(
+            Block::from_bytes(7, &bytes[0..10]),
+            Err(FsError::Corrupt { block: 7 })
+        );
+    }
+
+    #[test]
+    fn altered_blocks_should_fail_their_checksum() {
+        let block = Block {
+            number: 7,
+            data: "Shrek".into(),
+            b_type: BlockType::End,
+        };
+        let mut bytes = block.to_bytes();
+        bytes[BLOCK_HEADER_SIZE] = b'D';
+        assert_eq!(
+            Block::from_bytes(7, &bytes),
+            Err(FsError::ChecksumMismatch(7))
+        );
+        // The type is covered too
+        let mut bytes = block.to_bytes();
+        bytes[4] = 0;
+        assert_eq!(
+            Block::from_bytes(7, &bytes),
+            Err(FsError::ChecksumMismatch(7))
+        );
+    }
+
+    #[test]
+    fn zeroed_trailer_should_fail_on_a_disk_with_checksums() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        assert!(disk.checksums);
+        let (i, disk) = FileSystem::save_as_file("a".into(), "Shrek".into())(disk);
+        let (i, disk) = Inode::get_inode(i.unwrap())(disk);
+        let n = i.unwrap().start_block.unwrap();
+        let mut bytes = device.read_block(n).unwrap();
+        let end = BLOCK_HEADER_SIZE + 5;
+        bytes[end..].fill(0);
+        device.write_block(n, &bytes).unwrap();
+        let (res, _) = Block::get_block(n)(disk);
+        assert_eq!(res, Err(FsError::ChecksumMismatch(n)));
+
+        // Disks from before checksums say so in their superblock
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        assert!(!disk.checksums);
+        let (res, _) = Block::get_block(9)(disk);
+        assert_eq!(res.map(|b| b.data), Ok(b"Me".to_vec()));
+    }
+
+    #[test]
+    fn superblock_bytes_should_round_trip() {
+        let sb = SuperBlock::new(42);
+        let bytes = sb.to_bytes();
+        assert_eq!(&bytes[0..8], b"pseudoFS");
+        assert_eq!(SuperBlock::from_bytes(&bytes), Some(sb));
+        assert_eq!(SuperBlock::from_bytes(b"Yeah"), None);
+        // Superblocks from before the journal existed
+        assert_eq!(
+            SuperBlock::from_bytes(&bytes[0..12]),
+            Some(SuperBlock::new(42))
+        );
+        let sb = SuperBlock::new(42)
+            .with_layout(Layout::Pointers)
+            .with_geometry(Geometry {
+                inode_percent: 20,
+                block_data_size: 100,
+                inodes_per_block: 3,
+            })
+            .with_current_version();
+        assert_eq!(sb.features, FEATURE_POINTERS | FEATURE_GEOMETRY);
+        assert_eq!(SuperBlock::from_bytes(&sb.to_bytes()), Some(sb));
+        let (encryption, _) = Encryption::new("donkey", true, 10).unwrap();
+        let sb = SuperBlock::new(42)
+            .with_encryption(encryption)
+            .with_current_version();
+        assert_eq!(sb.features, FEATURE_ENCRYPTION);
+        assert_eq!(SuperBlock::from_bytes(&sb.to_bytes()), Some(sb));
+    }
+
+    #[test]
+    fn bitmaps_should_come_between_journal_and_inode_table() {
+        let sb = SuperBlock::with_journal(50).with_bitmaps();
+        assert_eq!(sb.get_journal_block_range(), 2..14);
+        assert_eq!(sb.get_block_bitmap_range(), 14..15);
+        assert_eq!(sb.get_inode_bitmap_range(), 15..16);
+        assert_eq!(sb.get_inode_table_block_range(), 16..21);
+        assert_eq!(sb.get_storage_block_range(), 21..51);
+        assert_eq!(SuperBlock::from_bytes(&sb.to_bytes()), Some(sb));
+
+        // The journal grows to hold every bitmap and inode table block
+        let sb = SuperBlock::with_journal(200).with_bitmaps();
+        let metadata = sb.get_block_bitmap_range().start..sb.get_inode_table_block_range().end;
+        assert_eq!(sb.journal_blocks as usize, metadata.len() + 2);
+    }
+
+    #[test]
+    fn journal_should_come_before_inode_table() {
+        let sb = SuperBlock::with_journal(50);
+        assert_eq!(sb.get_journal_block_range(), 2..14);
+        assert_eq!(sb.get_inode_table_block_range(), 14..19);
+        assert_eq!(sb.get_storage_block_range(), 19..51);
+        assert_eq!(SuperBlock::with_journal(200).journal_blocks, 20);
+        // Too small for a journal that can hold anything
+        assert_eq!(SuperBlock::with_journal(10).journal_blocks, 0);
+    }
+
     #[test]
     fn get_superblock_should_return_expected() {
         let expected_superblock = SuperBlock {
             magic_number: "0x70736575646F4653".into(),
             total_blocks: 10,
+            journal_blocks: 0,
+            block_bitmap_blocks: 0,
+            inode_bitmap_blocks: 0,
+            layout: Layout::Extents,
+            geometry: Geometry::default(),
+            version: 0,
+            features: 0,
+            backup_blocks: 0,
+            snapshots: vec![],
+            compression: Compression::None,
+            encryption: None,
         };
 
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = SuperBlock::get_super_block()(disk);
-        assert_eq!(data, Some(expected_superblock));
+        assert_eq!(data, Ok(expected_superblock));
     }
 
     #[test]
@@ -185,6 +959,17 @@ mod tests {
         let s = SuperBlock {
             magic_number: "".into(),
             total_blocks: 10,
+            journal_blocks: 0,
+            block_bitmap_blocks: 0,
+            inode_bitmap_blocks: 0,
+            layout: Layout::Extents,
+            geometry: Geometry::default(),
+            version: 0,
+            features: 0,
+            backup_blocks: 0,
+            snapshots: vec![],
+            compression: Compression::None,
+            encryption: None,
         };
         assert_eq!(s.get_inode_table_block_range(), expected);
     }
@@ -192,11 +977,21 @@ mod tests {
     #[test]
     fn get_free_data_blocks_should_return_amount_expected_and_be_free() {
         let expected_count = 2;
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = Block::get_free_data_blocks(expected_count)(disk);
+        let data = data.unwrap();
         assert_eq!(data.len(), expected_count);
         let all_free = data.into_iter().all(|x| x.b_type == BlockType::Free);
-        assert_eq!(all_free, true);
+        assert!(all_free);
+    }
+
+    #[test]
+    fn get_free_data_blocks_not_enough_free_should_return_no_space() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, _) = Block::get_free_data_blocks(4)(disk);
+        assert_eq!(data, Err(FsError::NoSpace));
     }
 
     #[test]
@@ -205,6 +1000,17 @@ mod tests {
         let sb = SuperBlock {
             magic_number: "asdfas".into(),
             total_blocks: 10,
+            journal_blocks: 0,
+            block_bitmap_blocks: 0,
+            inode_bitmap_blocks: 0,
+            layout: Layout::Extents,
+            geometry: Geometry::default(),
+            version: 0,
+            features: 0,
+            backup_blocks: 0,
+            snapshots: vec![],
+            compression: Compression::None,
+            encryption: None,
         };
         assert_eq!(sb.get_storage_block_range(), expected);
     }
@@ -0,0 +1,201 @@
+use super::error::*;
+use super::inode::Inode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// How a file keeps its data in its blocks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz, // LZSS, text shrinks well with it
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    // Superblocks keep the compression new files get as a number
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz => 1,
+        }
+    }
+
+    pub fn from_u32(n: u32) -> Option<Compression> {
+        match n {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz),
+            _ => None,
+        }
+    }
+}
+
+// Compressed data starts with a tag for how the rest is kept,
+// data that doesn't get any smaller is kept as it is
+const RAW: u8 = 0;
+const LZ: u8 = 1;
+
+// Every 8 tokens are led by a flag byte, a set bit is a match and a clear one
+// a byte as it is. Matches are 2 bytes, [offset - 1: 12 bits][length - 3: 4 bits]
+const WINDOW: usize = 4096;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 18;
+// Earlier positions with the same 3 bytes that are tried for a match
+const MAX_CANDIDATES: usize = 32;
+
+// Longest earlier match for the bytes at pos, as (offset back, length)
+fn longest_match(data: &[u8], pos: usize, recent: &HashMap<&[u8], Vec<usize>>) -> (usize, usize) {
+    let candidates = match data.get(pos..pos + MIN_MATCH).and_then(|k| recent.get(k)) {
+        Some(c) => c,
+        None => return (0, 0),
+    };
+    let most = MAX_MATCH.min(data.len() - pos);
+    let mut best = (0, 0);
+    for &c in candidates.iter().rev().filter(|c| pos - **c <= WINDOW) {
+        let len = (0..most)
+            .take_while(|k| data[c + k] == data[pos + k])
+            .count();
+        if len > best.1 {
+            best = (pos - c, len);
+        }
+    }
+    best
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut recent: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut flags = 0;
+    let mut bit = 8;
+    let mut pos = 0;
+    while pos < data.len() {
+        if bit == 8 {
+            flags = out.len();
+            out.push(0);
+            bit = 0;
+        }
+        let (offset, len) = longest_match(data, pos, &recent);
+        let step = if len >= MIN_MATCH {
+            out[flags] |= 1 << bit;
+            let token = ((offset - 1) << 4 | (len - MIN_MATCH)) as u16;
+            out.extend_from_slice(&token.to_le_bytes());
+            len
+        } else {
+            out.push(data[pos]);
+            1
+        };
+        for p in pos..pos + step {
+            if let Some(key) = data.get(p..p + MIN_MATCH) {
+                let seen = recent.entry(key).or_default();
+                seen.push(p);
+                if seen.len() > MAX_CANDIDATES {
+                    seen.remove(0);
+                }
+            }
+        }
+        pos += step;
+        bit += 1;
+    }
+    out
+}
+
+// None when a match points back before the start
+pub fn decompress(packed: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < packed.len() {
+        let flags = packed[i];
+        i += 1;
+        for bit in 0..8 {
+            if i >= packed.len() {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                out.push(packed[i]);
+                i += 1;
+                continue;
+            }
+            let token = u16::from_le_bytes([packed[i], *packed.get(i + 1)?]) as usize;
+            i += 2;
+            let start = out.len().checked_sub((token >> 4) + 1)?;
+            for k in 0..(token & 0xF) + MIN_MATCH {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    Some(out)
+}
+
+// What gets stored in the blocks for data
+pub fn encode(compression: Compression, data: Vec<u8>) -> Vec<u8> {
+    match compression {
+        Compression::None => data,
+        Compression::Lz => {
+            let packed = compress(&data);
+            match packed.len() < data.len() {
+                true => [&[LZ][..], &packed].concat(),
+                false => [&[RAW][..], &data].concat(),
+            }
+        }
+    }
+}
+
+// An inode's data back from what's stored in its blocks
+pub fn decode(i: &Inode, stored: Vec<u8>) -> FsResult<Vec<u8>> {
+    let corrupt = FsError::Corrupt {
+        block: i.start_block.unwrap_or(0),
+    };
+    if i.compression.is_none() {
+        return Ok(stored);
+    }
+    match stored.split_first() {
+        None => Ok(vec![]),
+        Some((&tag, rest)) if tag == RAW => Ok(rest.to_vec()),
+        Some((&tag, rest)) if tag == LZ => decompress(rest).ok_or(corrupt),
+        Some(_) => Err(corrupt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_should_round_trip() {
+        let text = b"Ogres are like onions. Onions have layers, ogres have layers.".repeat(20);
+        let binary: Vec<u8> = (0..5000u32).map(|x| (x * 7919 % 251) as u8).collect();
+        for data in [text.clone(), binary, vec![], vec![b'a'; 1000]] {
+            assert_eq!(decompress(&compress(&data)), Some(data));
+        }
+        assert!(compress(&text).len() < text.len() / 4);
+    }
+
+    #[test]
+    fn encode_should_keep_data_that_does_not_shrink() {
+        let data = b"Shrek".to_vec();
+        let stored = encode(Compression::Lz, data.clone());
+        assert_eq!(stored, [&[RAW][..], &data].concat());
+        let i = Inode {
+            compression: Compression::Lz,
+            ..Inode::default()
+        };
+        assert_eq!(decode(&i, stored), Ok(data));
+        assert_eq!(encode(Compression::None, b"x".to_vec()), b"x".to_vec());
+    }
+
+    #[test]
+    fn decode_should_fail_on_bad_data() {
+        let i = Inode {
+            start_block: Some(9),
+            compression: Compression::Lz,
+            ..Inode::default()
+        };
+        // A match going back further than anything written
+        let bad = vec![LZ, 0b1, 0xFF, 0xFF];
+        assert_eq!(decode(&i, bad), Err(FsError::Corrupt { block: 9 }));
+        assert_eq!(decode(&i, vec![7]), Err(FsError::Corrupt { block: 9 }));
+    }
+}
@@ -0,0 +1,225 @@
+use super::block::*;
+use super::directory::Directory;
+use super::disk::*;
+use super::error::*;
+use super::extent::Extent;
+use super::inode::*;
+use super::journal::transaction;
+use super::snapshot;
+use super::{chunk_data, write_inode_data};
+use serde::Serialize;
+use std::collections::HashSet;
+
+// How scattered the data blocks of a file or directory are
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Fragmentation {
+    pub path: String,
+    pub inode: u32,
+    pub blocks: u32,
+    pub fragments: u32, // Runs of consecutive blocks, 1 once it is in one piece
+}
+
+impl Fragmentation {
+    pub fn is_fragmented(&self) -> bool {
+        self.fragments > 1
+    }
+}
+
+// What a defragment run did
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Defrag {
+    pub defragmented: Vec<String>, // Now in one piece
+    pub moved: u32,                // Data blocks written somewhere else
+    pub remaining: u32, // Still fragmented, out of budget, no free run big enough or too big to journal
+}
+
+// Every file and directory reachable from the root, parents first
+fn walk(disk: &mut Disk) -> FsResult<Vec<(String, Inode)>> {
+    let root = run(disk, Inode::get_inode(ROOT_INODE))?;
+    let mut found = vec![("/".to_string(), root)];
+    let mut seen = HashSet::from([ROOT_INODE]);
+    let mut next = 0;
+    while next < found.len() {
+        let (path, i) = found[next].clone();
+        next += 1;
+        if !i.is_directory() {
+            continue;
+        }
+        let d = run(disk, Directory::get_directory_at(i.number))?;
+        for (name, number) in d.entries() {
+            let child = run(disk, Inode::get_inode(number))?;
+            if child.start_block.is_some() && seen.insert(number) {
+                found.push((format!("{}/{}", path.trim_end_matches('/'), name), child));
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn measure(disk: &mut Disk) -> FsResult<Vec<Fragmentation>> {
+    let mut measured = vec![];
+    for (path, i) in walk(disk)? {
+        let numbers = run(disk, Inode::get_data_numbers(i, 0, usize::MAX))?;
+        measured.push(Fragmentation {
+            path,
+            inode: i.number,
+            blocks: numbers.len() as u32,
+            fragments: Extent::from_numbers(&numbers).len() as u32,
+        });
+    }
+    Ok(measured)
+}
+
+// Fragmentation of every file and directory
+pub fn fragmentation<'a>() -> DiskAction<'a, FsResult<Vec<Fragmentation>>> {
+    Box::new(|disk| {
+        let mut disk = disk;
+        let measured = measure(&mut disk);
+        (measured, disk)
+    })
+}
+
+// Rewrite an inode's data into the first free run big enough for all of it
+// Its own blocks are freed first, so they can be part of that run
+fn relocate<'a>(i: Inode, held: HashSet<u32>) -> DiskAction<'a, FsResult<Inode>> {
+    let d = Inode::get_inode_blocks(i);
+    let d = flat_map_result(
+        d,
+        Box::new(move |(i, blocks): (Inode, Vec<Block>)| {
+            let lens: Vec<usize> = blocks.iter().map(|b| b.data.len()).collect();
+            let data = Block::blocks_to_data(blocks.clone());
+            let d = Block::free_blocks_except(blocks, &held);
+            let d = flat_map_result(d, Box::new(move |_| chunk_data(data.clone(), lens.clone())));
+            flat_map_result(
+                d,
+                Box::new(move |chunks| write_inode_data(i, vec![], i, chunks)),
+            )
+        }),
+    );
+    transaction(d)
+}
+
+fn defragment_disk(disk: &mut Disk, budget: Option<u32>) -> FsResult<Defrag> {
+    let mut report = Defrag::default();
+    let mut measured: Vec<Fragmentation> = measure(disk)?
+        .into_iter()
+        .filter(|f| f.is_fragmented())
+        .collect();
+    measured.sort_by(|a, b| b.fragments.cmp(&a.fragments).then(a.path.cmp(&b.path)));
+    let mut left = budget.unwrap_or(u32::MAX);
+    // A file's own blocks only come free when no snapshot holds them
+    let held = run(disk, snapshot::held_blocks())?;
+    for f in measured {
+        if f.blocks > left {
+            report.remaining += 1;
+            continue;
+        }
+        let i = run(disk, Inode::get_inode(f.inode))?;
+        let own = run(disk, Inode::get_data_numbers(i, 0, usize::MAX))?;
+        let mut free: Vec<u32> = run(disk, Block::get_all_free_data_blocks())?
+            .iter()
+            .map(|b| b.number)
+            .chain(own.into_iter().filter(|n| !held.contains(n)))
+            .collect();
+        free.sort_unstable();
+        let fits = Extent::from_numbers(&free)
+            .iter()
+            .any(|e| e.len >= f.blocks);
+        if !fits {
+            report.remaining += 1;
+            continue;
+        }
+        // Blocks it takes over from itself are journaled, a big file might not fit
+        match run(disk, relocate(i, held.clone())) {
+            Err(FsError::TransactionTooLarge(_)) => {
+                report.remaining += 1;
+                continue;
+            }
+            x => x?,
+        };
+        left -= f.blocks;
+        report.moved += f.blocks;
+        report.defragmented.push(f.path);
+    }
+    Ok(report)
+}
+
+// Move the most fragmented files into one piece each, each in its own transaction
+// A budget caps how many data blocks get moved, so it can run a little at a time
+pub fn defragment<'a>(budget: Option<u32>) -> DiskAction<'a, FsResult<Defrag>> {
+    Box::new(move |disk| {
+        let mut disk = disk;
+        let report = defragment_disk(&mut disk, budget);
+        (report, disk)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
+    use super::*;
+
+    #[test]
+    fn fragmentation_should_count_runs_of_blocks() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (measured, _) = fragmentation()(disk);
+        let secret = Fragmentation {
+            path: "/secret.txt".into(),
+            inode: 3,
+            blocks: 4,
+            fragments: 3, // 4, 6, then 8 and 9
+        };
+        assert!(measured.unwrap().contains(&secret));
+    }
+
+    #[test]
+    fn defragment_should_put_chains_in_one_piece() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (report, disk) = defragment(None)(disk);
+        let report = report.unwrap();
+        assert_eq!(report.defragmented, vec!["/secret.txt".to_string()]);
+        assert_eq!((report.moved, report.remaining), (4, 0));
+
+        let (i, disk) = Inode::get_inode(3)(disk);
+        let (numbers, disk) = Inode::get_data_numbers(i.unwrap(), 0, usize::MAX)(disk);
+        assert_eq!(numbers, Ok(vec![6, 7, 8, 9]));
+        let (data, _) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok("SomebodyOnceToldMe".into()));
+    }
+
+    #[test]
+    fn defragment_should_stop_at_the_budget() {
+        let device = MemDisk::formatted(80).unwrap();
+        let mut disk = Disk::new(&device);
+        // Growing both files in turn interleaves their blocks
+        for name in ["shrek.txt", "donkey.txt"] {
+            run(&mut disk, FileSystem::save_as_file(name.into(), vec![])).unwrap();
+        }
+        for _ in 0..3 {
+            for name in ["shrek.txt", "donkey.txt"] {
+                run(&mut disk, FileSystem::append(name.into(), vec![b'x'; 50])).unwrap();
+            }
+        }
+        let measured = run(&mut disk, fragmentation()).unwrap();
+        // The root directory grew a block at a time as well
+        assert_eq!(measured.iter().filter(|f| f.is_fragmented()).count(), 3);
+
+        // The most fragmented go first, the rest wait for the next run
+        let report = run(&mut disk, defragment(Some(4))).unwrap();
+        assert_eq!(report.defragmented, vec!["/donkey.txt".to_string()]);
+        assert_eq!((report.moved, report.remaining), (3, 2));
+        let report = run(&mut disk, defragment(None)).unwrap();
+        assert_eq!((report.defragmented.len(), report.remaining), (2, 0));
+        let measured = run(&mut disk, fragmentation()).unwrap();
+        assert!(measured.iter().all(|f| !f.is_fragmented()));
+        for name in ["shrek.txt", "donkey.txt"] {
+            let data = run(&mut disk, FileSystem::read_file(name.into()));
+            assert_eq!(data, Ok(vec![b'x'; 150]));
+        }
+        let report = run(&mut disk, FileSystem::fsck(false)).unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -1,39 +1,96 @@
+use super::bitmap::*;
 use super::block::*;
+use super::compress::Compression;
 use super::disk::*;
+use super::error::*;
 use super::inode::*;
+use serde::Serialize;
 
-#[derive(Copy, PartialEq, Clone, Debug)]
+#[derive(Copy, PartialEq, Clone, Debug, Serialize)]
 pub struct DiskDiagnostics {
     valid_magic_number: bool,
+    format_version: u32, // 0 for disks from before versions were recorded
     total_reads: u32,
     total_writes: u32,
     total_blocks: u32,
     total_inodes: u32,
     total_free_inodes: u32,
     total_free_blocks: u32,
+    total_file_bytes: u64,   // Sum of every file's size
+    total_stored_bytes: u64, // What those files take up in blocks, less when compressed
+}
+
+fn files(inodes: &[Inode]) -> impl Iterator<Item = &Inode> {
+    inodes
+        .iter()
+        .filter(|i| i.start_block.is_some() && !i.is_directory())
+}
+
+// Bytes a file holds and what they take up in its blocks
+// Older disks never recorded a size, so like stat it's worked out from the blocks
+fn file_bytes<'a>(i: Inode) -> DiskAction<'a, FsResult<(u64, u64)>> {
+    if i.size > 0 {
+        let stored = match i.compression {
+            Compression::None => i.size,
+            _ => i.stored,
+        };
+        return unit(Ok((i.size, stored)));
+    }
+    let d = Inode::get_inode_blocks(i);
+    map_result(
+        d,
+        Box::new(|(i, blocks)| {
+            let stat = i.to_stat(&blocks);
+            (stat.size, stat.stored)
+        }),
+    )
 }
 
 impl DiskDiagnostics {
-    pub fn get_diagnostics<'a>() -> DiskAction<'a, Option<DiskDiagnostics>> {
-        let sb = SuperBlock::get_super_block();
+    pub fn get_diagnostics<'a>() -> DiskAction<'a, FsResult<DiskDiagnostics>> {
+        // The inode bitmap counts free inodes, disks without one count them in the table.
+        // Inodes are counted from the table too, older tables don't fill their blocks
+        let sb = flat_map_result(
+            SuperBlock::get_super_block(),
+            Box::new(|sb: SuperBlock| {
+                let d = Bitmap::read(&sb, BitmapKind::Inodes);
+                map_result(d, Box::new(move |b| (sb.clone(), b)))
+            }),
+        );
         let free_blocks = Block::get_all_free_data_blocks();
-        let free_inodes = Inode::get_free_inodes();
+        let inodes = flat_map_result(
+            Inode::get_inode_table(),
+            Box::new(|inodes: Vec<Inode>| {
+                let reads = files(&inodes).copied().map(file_bytes).collect();
+                map_result(
+                    sequence_result(reads),
+                    Box::new(move |bytes| (inodes.clone(), bytes)),
+                )
+            }),
+        );
 
-        let d = map2(sb, free_blocks, Box::new(|a, b| (a, b)));
-        let d = map2(d, free_inodes, Box::new(|(a, b), c| (a, b, c)));
+        let d = map2_result(sb, free_blocks, Box::new(|a, b| (a, b)));
+        let d = map2_result(d, inodes, Box::new(|(a, b), c| (a, b, c)));
 
         Box::new(move |disk| {
             let (t, disk2) = d(disk);
-            let (sb, blocks, inodes) = t;
-            let res = sb.map(|x| DiskDiagnostics {
-                valid_magic_number: x.valid_super_block(),
-                total_reads: disk2.reads,
-                total_writes: disk2.writes,
-                total_blocks: x.total_blocks,
-                total_inodes: x.get_inode_count(),
-                total_free_inodes: inodes.len() as u32,
-                total_free_blocks: blocks.len() as u32,
-            });
+            let res = t.map(
+                |((x, inode_bitmap), blocks, (inodes, bytes))| DiskDiagnostics {
+                    valid_magic_number: x.valid_super_block(),
+                    format_version: x.version,
+                    total_reads: disk2.reads,
+                    total_writes: disk2.writes,
+                    total_blocks: x.total_blocks,
+                    total_inodes: inodes.len() as u32,
+                    total_free_inodes: match inode_bitmap {
+                        Some(b) => b.clear_in(1..x.get_inode_capacity() + 1).len() as u32,
+                        None => inodes.iter().filter(|i| i.start_block.is_none()).count() as u32,
+                    },
+                    total_free_blocks: blocks.len() as u32,
+                    total_file_bytes: bytes.iter().map(|(size, _)| size).sum(),
+                    total_stored_bytes: bytes.iter().map(|(_, stored)| stored).sum(),
+                },
+            );
             (res, disk2)
         })
     }
@@ -41,22 +98,46 @@ impl DiskDiagnostics {
 
 #[cfg(test)]
 mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
     use super::*;
 
     #[test]
     fn get_diagnostics_should_return_expected() {
         let expected = DiskDiagnostics {
             valid_magic_number: true,
-            total_reads: 12,
+            format_version: 0,
+            total_reads: 16, // secret.txt has its four blocks read for its size
             total_writes: 0,
             total_blocks: 10,
-            total_inodes: 10,
+            // The table's block has room for 5, but only 3 were ever written to it
+            total_inodes: 3,
             total_free_inodes: 1,
             total_free_blocks: 3,
+            // secret.txt was written before inodes had sizes
+            total_file_bytes: 18,
+            total_stored_bytes: 18,
         };
 
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (res, _) = DiskDiagnostics::get_diagnostics()(disk);
+        assert_eq!(res, Ok(expected));
+    }
+
+    #[test]
+    fn get_diagnostics_should_show_what_compression_saved() {
+        let sb = SuperBlock::with_journal(100)
+            .with_bitmaps()
+            .with_compression(Compression::Lz);
+        let device = MemDisk::new();
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = Disk::new(&device);
+        let text = b"Ogres are like onions. ".repeat(40);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), text.clone())(disk);
         let (res, _) = DiskDiagnostics::get_diagnostics()(disk);
-        assert_eq!(res, Some(expected));
+        let res = res.unwrap();
+        assert_eq!(res.total_file_bytes, text.len() as u64);
+        assert!(res.total_stored_bytes < res.total_file_bytes / 4);
     }
 }
@@ -1,11 +1,14 @@
 use super::super::file_system;
 use super::block::*;
 use super::disk::*;
-use super::inode;
+use super::error::*;
+use super::inode::{Inode, InodeKind, ROOT_INODE};
 use super::utils;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+// Entries map a name to an inode number
+// Every directory has a "." entry for itself and ".." for its parent
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Directory {
     directory: HashMap<String, u32>,
@@ -13,126 +16,241 @@ pub struct Directory {
 
 impl Directory {
     pub fn find(&self, s: &str) -> Option<u32> {
-        self.directory.get(s).map(|x| *x)
+        self.directory.get(s).copied()
     }
 
     pub fn default() -> Directory {
+        Directory::new(ROOT_INODE, ROOT_INODE)
+    }
+
+    pub fn new(inode_number: u32, parent: u32) -> Directory {
         let mut h = HashMap::new();
-        h.insert(".".into(), 1);
-        h.insert("/".into(), 1);
+        h.insert(".".into(), inode_number);
+        h.insert("..".into(), parent);
         Directory { directory: h }
     }
 
-    // Read the superblock, if no magic number then none
+    // Everything but "." and ".."
+    pub fn entries(&self) -> Vec<(String, u32)> {
+        let mut entries: Vec<(String, u32)> = self
+            .directory
+            .iter()
+            .filter(|(name, _)| *name != "." && *name != "..")
+            .map(|(name, i)| (name.clone(), *i))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    // The same entries with inode numbers swapped for the ones in numbers
+    pub fn renumbered(&self, numbers: &HashMap<u32, u32>) -> Directory {
+        let directory = self
+            .directory
+            .iter()
+            .map(|(name, i)| (name.clone(), *numbers.get(i).unwrap_or(i)))
+            .collect();
+        Directory { directory }
+    }
+
+    // Read the superblock, if no magic number then error
     // Read First Inode
     // Construct data Blocks
     // Parse into Directory
-    pub fn get_directory<'a>() -> DiskAction<'a, Option<Directory>> {
+    pub fn get_directory<'a>() -> DiskAction<'a, FsResult<Directory>> {
         // first inode in directory inode
-        let d = inode::Inode::get_inode(1);
-        let d = flat_map(
+        Directory::get_directory_at(ROOT_INODE)
+    }
+
+    pub fn get_directory_at<'a>(inode_number: u32) -> DiskAction<'a, FsResult<Directory>> {
+        let d = Inode::get_inode(inode_number);
+        let d = flat_map_result(d, Box::new(Inode::get_inode_blocks));
+        map(
             d,
-            utils::lift_disk_action(Box::new(inode::Inode::get_inode_blocks)),
-        );
-        let d = flatten_option(d);
-        let d = map(d, utils::lift(Box::new(|(_, b)| Block::blocks_to_data(b))));
-        let d = map(d, utils::lift(Box::new(Directory::parse_directory)));
-        flatten_option(d)
+            Box::new(|x| {
+                x.and_then(|(i, b)| {
+                    Directory::parse_directory(Block::blocks_to_data(b)).ok_or(FsError::Corrupt {
+                        block: i.start_block.unwrap_or(0),
+                    })
+                })
+            }),
+        )
     }
-    fn parse_directory(s: String) -> Option<Directory> {
-        serde_json::from_str(&s).ok()
+    pub fn parse_directory(s: Vec<u8>) -> Option<Directory> {
+        serde_json::from_slice(&s).ok()
+    }
+
+    // Walk the tree from the root inode, returning the inode number at the end of path
+    // Relative paths are resolved from the root
+    pub fn resolve<'a>(path: String) -> DiskAction<'a, FsResult<u32>> {
+        Box::new(move |disk| {
+            let mut current = ROOT_INODE;
+            let mut disk = disk;
+            for name in utils::path_components(&path) {
+                let (i, disk2) = Inode::get_inode(current)(disk);
+                disk = disk2;
+                match i {
+                    Ok(i) if i.is_directory() => (),
+                    Ok(_) => return (Err(FsError::NotADirectory(path.clone())), disk),
+                    Err(e) => return (Err(e), disk),
+                }
+                let (d, disk2) = Directory::get_directory_at(current)(disk);
+                disk = disk2;
+                let next = d.and_then(|d| match d.find(name) {
+                    Some(n) => Ok(n),
+                    // Older root directories don't have "." or ".." entries
+                    None if name == "." || name == ".." => Ok(current),
+                    None => Err(FsError::NotFound(path.clone())),
+                });
+                match next {
+                    Ok(n) => current = n,
+                    Err(e) => return (Err(e), disk),
+                }
+            }
+            (Ok(current), disk)
+        })
+    }
+
+    // Resolve the directory a path lives in, along with the final name of the path
+    pub fn resolve_parent<'a>(path: String) -> DiskAction<'a, FsResult<(u32, String)>> {
+        let (parent, name) = match utils::split_path(&path) {
+            Some(x) => x,
+            None => return unit(Err(FsError::InvalidName(path))),
+        };
+        let d = Directory::resolve(parent);
+        let d = flat_map_result(d, Box::new(Inode::get_inode));
+        map(
+            d,
+            Box::new(move |x| {
+                x.and_then(|i| {
+                    if i.is_directory() {
+                        Ok((i.number, name.clone()))
+                    } else {
+                        Err(FsError::NotADirectory(path.clone()))
+                    }
+                })
+            }),
+        )
     }
 
     pub fn write_file_name<'a>(
         inode_number: u32,
         file_name: String,
-    ) -> DiskAction<'a, Option<u32>> {
-        let d = Directory::get_directory();
+    ) -> DiskAction<'a, FsResult<u32>> {
+        Directory::write_file_name_at(ROOT_INODE, inode_number, file_name)
+    }
+
+    pub fn write_file_name_at<'a>(
+        directory_inode: u32,
+        inode_number: u32,
+        file_name: String,
+    ) -> DiskAction<'a, FsResult<u32>> {
+        let d = Directory::get_directory_at(directory_inode);
         let d = map(
             d,
-            utils::lift(Box::new(move |mut x: Directory| {
-                x.directory.insert(file_name.clone(), inode_number);
-                x
-            })),
+            Box::new(move |x| {
+                x.and_then(|mut x: Directory| {
+                    if x.directory.contains_key(&file_name) {
+                        return Err(FsError::AlreadyExists(file_name.clone()));
+                    }
+                    x.directory.insert(file_name.clone(), inode_number);
+                    Ok(x)
+                })
+            }),
         );
-        let wipe = Directory::wipe_directory_blocks();
-        let d = map2(d, wipe, Box::new(|a, _| a));
-
-        let d = flat_map(
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(Directory::save_directory)),
+            Box::new(move |x| Directory::rewrite_directory(directory_inode, x)),
         );
-        map(d, utils::lift(Box::new(move |_| inode_number)))
+        map_result(d, Box::new(move |_| inode_number))
     }
 
     // prevent root directory from being remove
-    pub fn remove_file_name<'a>(file_name: String) -> DiskAction<'a, Option<bool>> {
-        // make sure it's not root directory
-        let d = Directory::get_directory();
+    pub fn remove_file_name<'a>(file_name: String) -> DiskAction<'a, FsResult<()>> {
+        Directory::remove_file_name_at(ROOT_INODE, file_name)
+    }
+
+    pub fn remove_file_name_at<'a>(
+        directory_inode: u32,
+        file_name: String,
+    ) -> DiskAction<'a, FsResult<()>> {
+        if file_name == "." || file_name == ".." {
+            return unit(Err(FsError::InvalidName(file_name)));
+        }
+        let d = Directory::get_directory_at(directory_inode);
         let d = map(
             d,
-            utils::lift(Box::new(move |mut x: Directory| {
-                x.directory.remove(&file_name);
-                x
-            })),
+            Box::new(move |x| {
+                x.and_then(|mut x: Directory| {
+                    x.directory
+                        .remove(&file_name)
+                        .ok_or_else(|| FsError::NotFound(file_name.clone()))?;
+                    Ok(x)
+                })
+            }),
         );
-        let wipe = Directory::wipe_directory_blocks();
-        let d = map2(d, wipe, Box::new(|a, _| a));
-
-        let d = flat_map(
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(Directory::save_directory)),
+            Box::new(move |x| Directory::rewrite_directory(directory_inode, x)),
         );
-        map(d, utils::lift(Box::new(move |_| true)))
+        map_result(d, Box::new(|_| ()))
     }
 
-    pub fn save_directory<'a>(d: Directory) -> DiskAction<'a, Option<Directory>> {
-        let ds = serde_json::to_string(&d)
-            .ok()
-            .expect("Directory failed to to_string");
-        // Make sure to point inode 1 to first data block
-        let blocks_data = utils::string_to_block_data_chunks(ds);
-        let inode_1 = inode::Inode::get_inode(1);
-
-        let blocks = Block::get_free_data_blocks(blocks_data.len());
-        let blocks = map(
-            blocks,
-            Box::new(move |x| Block::set_data_blocks_data((x, blocks_data.clone()))),
-        );
-        let d = map2(inode_1, blocks, Box::new(inode::Inode::set_inode_blocks));
-        let d = flat_map(
+    // Save the directory back over the blocks it already has
+    fn rewrite_directory<'a>(
+        directory_inode: u32,
+        directory: Directory,
+    ) -> DiskAction<'a, FsResult<Directory>> {
+        let d = Inode::get_inode(directory_inode);
+        flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(file_system::write_inode_and_blocks)),
-        );
-
-        let d = flatten_option(d);
-        map2(d, Directory::get_directory(), Box::new(|_, b| b))
+            Box::new(move |i| Directory::save_directory(i, directory.clone())),
+        )
     }
-    pub fn wipe_directory_blocks<'a>() -> DiskAction<'a, Option<Vec<Block>>> {
-        let d = inode::Inode::get_inode(1);
-        let d = flat_map(
-            d,
-            utils::lift_disk_action(Box::new(inode::Inode::get_inode_blocks)),
+
+    // Save a directory into the blocks its inode already has, or free ones
+    pub fn save_directory<'a>(i: Inode, d: Directory) -> DiskAction<'a, FsResult<Directory>> {
+        let ds = serde_json::to_string(&d).expect("Directory failed to to_string");
+        let size = ds.len() as u64;
+        let new = Inode {
+            kind: InodeKind::Directory,
+            size,
+            modified: utils::now(),
+            ..i
+        };
+
+        let old = match i.start_block {
+            Some(_) => Inode::get_inode_blocks(i),
+            None => unit(Ok((i, vec![]))),
+        };
+        let d = map2_result(
+            old,
+            file_system::chunk_data(ds.into_bytes(), vec![]),
+            Box::new(|a, c| (a, c)),
         );
-        let d = flatten_option(d);
-        // Change all blocks to free, and save them
-        flat_map(
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(|(_, b)| Block::free_blocks(b))),
-        )
+            Box::new(move |((i, b), chunks)| file_system::write_inode_data(i, b, new, chunks)),
+        );
+        flat_map_result(d, Box::new(|i| Directory::get_directory_at(i.number)))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::mem_disk::MemDisk;
     use super::*;
     #[test]
     fn get_directory_should_return_expected() {
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
 
         let mut f = HashMap::new();
         f.insert("secret.txt".into(), 3);
-        let expected = Some(Directory { directory: f });
+        let expected = Ok(Directory { directory: f });
 
         let (directory, _) = Directory::get_directory()(disk);
         assert_eq!(directory, expected);
@@ -140,14 +258,51 @@ mod tests {
 
     #[test]
     fn save_file_should_return_expected() {
-        use std::fs;
-        let file_data = fs::read_to_string("./test-files/sda1").unwrap_or("".into());
-        fs::write("./test-files/directory_save_test", file_data).unwrap();
-        let disk = Disk::new("./test-files/directory_save_test");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let file_name: String = "plz_work.md".into();
         let (data, disk) = Directory::write_file_name(5, file_name.clone())(disk);
-        assert_eq!(data, Some(5));
+        assert_eq!(data, Ok(5));
         let (data, _) = Directory::get_directory()(disk);
         assert!(data.unwrap().directory.contains_key(&file_name));
     }
+
+    #[test]
+    fn save_existing_file_name_should_return_already_exists() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, _) = Directory::write_file_name(2, "secret.txt".into())(disk);
+        assert_eq!(data, Err(FsError::AlreadyExists("secret.txt".into())));
+    }
+
+    #[test]
+    fn resolve_should_return_expected() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, disk) = Directory::resolve("/secret.txt".into())(disk);
+        assert_eq!(data, Ok(3));
+        let (data, disk) = Directory::resolve("/./../secret.txt".into())(disk);
+        assert_eq!(data, Ok(3));
+        let (data, _) = Directory::resolve("/".into())(disk);
+        assert_eq!(data, Ok(ROOT_INODE));
+    }
+
+    #[test]
+    fn resolve_missing_should_return_not_found() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, _) = Directory::resolve("/docs/notes.txt".into())(disk);
+        assert_eq!(data, Err(FsError::NotFound("/docs/notes.txt".into())));
+    }
+
+    #[test]
+    fn resolve_through_file_should_return_not_a_directory() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (data, _) = Directory::resolve("/secret.txt/notes.txt".into())(disk);
+        assert_eq!(
+            data,
+            Err(FsError::NotADirectory("/secret.txt/notes.txt".into()))
+        );
+    }
 }
@@ -1,43 +1,91 @@
+use super::block::checksums_required;
+use super::error::*;
+use std::fmt::Debug;
+
+// Storage a disk is kept on, blocks are numbered from 1
+// Writing past the end of a device grows it
+pub trait BlockDevice: Debug {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>>;
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()>;
+    // How many blocks the device is holding
+    fn len(&self) -> FsResult<u32>;
+    fn is_empty(&self) -> FsResult<bool> {
+        self.len().map(|x| x == 0)
+    }
+    fn flush(&self) -> FsResult<()>;
+    // Drop every block after len, a device that is already shorter is left alone
+    fn truncate(&self, len: u32) -> FsResult<()>;
+    // Encrypted disks only mount through a device that decrypts them
+    fn decrypts(&self) -> bool {
+        false
+    }
+}
+
 #[derive(Copy, Debug, Clone)]
 pub struct Disk<'a> {
-    pub file: &'a str,
+    pub device: &'a dyn BlockDevice,
     pub reads: u32,
     pub writes: u32,
+    pub checksums: bool, // Blocks without a checksum are broken, false for disks from before them
 }
 
 impl<'a> Disk<'a> {
-    pub fn new(file_name: &str) -> Disk {
+    pub fn new(device: &'a dyn BlockDevice) -> Disk<'a> {
         Disk {
-            file: file_name,
+            device,
             reads: 0,
             writes: 0,
+            checksums: checksums_required(device),
         }
     }
     pub fn read(self) -> Disk<'a> {
         Disk {
-            file: self.file,
             reads: self.reads + 1,
-            writes: self.writes,
+            ..self
         }
     }
     pub fn write(self) -> Disk<'a> {
         Disk {
-            file: self.file,
-            reads: self.reads,
             writes: self.writes + 1,
+            ..self
         }
     }
 }
 
+// Copy every block from one device to another, returning how many were copied
+pub fn copy_blocks(from: &dyn BlockDevice, to: &dyn BlockDevice) -> FsResult<u32> {
+    let len = from.len()?;
+    for i in 1..len + 1 {
+        to.write_block(i, &from.read_block(i)?)?;
+    }
+    to.flush()?;
+    Ok(len)
+}
+
 pub type DiskAction<'a, A> = Box<dyn Fn(Disk) -> (A, Disk) + 'a>;
 
-pub fn unit<'a, A: 'a>(a: A) -> DiskAction<'a, A>
-where
-    A: Clone,
-{
+// Run an action in the middle of imperative code, keeping the disk counts
+pub fn run<A>(disk: &mut Disk, action: DiskAction<A>) -> A {
+    let (a, d) = action(*disk);
+    *disk = d;
+    a
+}
+
+pub fn unit<'a, A: 'a + Clone>(a: A) -> DiskAction<'a, A> {
     Box::new(move |d| (a.clone(), d))
 }
 
+pub fn read_block<'a>(number: u32) -> DiskAction<'a, FsResult<Vec<u8>>> {
+    Box::new(move |disk: Disk| (disk.device.read_block(number), disk.read()))
+}
+
+pub fn write_block<'a>(number: u32, data: Vec<u8>) -> DiskAction<'a, FsResult<Vec<u8>>> {
+    Box::new(move |disk: Disk| {
+        let r = disk.device.write_block(number, &data).map(|_| data.clone());
+        (r, disk.write())
+    })
+}
+
 pub fn map<'a, A: 'a, B: 'a>(d: DiskAction<'a, A>, f: Box<dyn Fn(A) -> B>) -> DiskAction<'a, B> {
     Box::new(move |disk| {
         let (a, b) = d(disk);
@@ -77,10 +125,7 @@ pub fn flatten_option<'a, A: 'a>(
     map(d, Box::new(|x| x.flatten()))
 }
 
-pub fn sequence<'a, A: 'a>(a: Vec<DiskAction<'a, A>>) -> DiskAction<'a, Vec<A>>
-where
-    A: Clone,
-{
+pub fn sequence<'a, A: 'a + Clone>(a: Vec<DiskAction<'a, A>>) -> DiskAction<'a, Vec<A>> {
     a.into_iter().fold(unit(Vec::<A>::new()), |acc, curr| {
         map2(
             acc,
@@ -92,3 +137,73 @@ where
         )
     })
 }
+
+// Result versions of the combinators above
+// These short circuit, once an action fails the actions after it are never run
+
+pub fn map_result<'a, A: 'a, B: 'a>(
+    d: DiskAction<'a, FsResult<A>>,
+    f: Box<dyn Fn(A) -> B>,
+) -> DiskAction<'a, FsResult<B>> {
+    Box::new(move |disk| {
+        let (a, disk2) = d(disk);
+        (a.map(&f), disk2)
+    })
+}
+
+pub fn flat_map_result<'a, A: 'a, B: 'a>(
+    d: DiskAction<'a, FsResult<A>>,
+    f: Box<dyn Fn(A) -> DiskAction<'a, FsResult<B>> + 'a>,
+) -> DiskAction<'a, FsResult<B>> {
+    Box::new(move |disk| {
+        let (a, disk2) = d(disk);
+        match a {
+            Ok(a) => f(a)(disk2),
+            Err(e) => (Err(e), disk2),
+        }
+    })
+}
+
+pub fn map2_result<'a, A: 'a, B: 'a, C: 'a>(
+    d: DiskAction<'a, FsResult<A>>,
+    d2: DiskAction<'a, FsResult<B>>,
+    f: Box<dyn Fn(A, B) -> C + 'a>,
+) -> DiskAction<'a, FsResult<C>> {
+    Box::new(move |disk| {
+        let (res, disk2) = d(disk);
+        match res {
+            Ok(a) => {
+                let (res2, disk3) = d2(disk2);
+                (res2.map(|b| f(a, b)), disk3)
+            }
+            Err(e) => (Err(e), disk2),
+        }
+    })
+}
+
+pub fn sequence_result<'a, A: 'a>(
+    a: Vec<DiskAction<'a, FsResult<A>>>,
+) -> DiskAction<'a, FsResult<Vec<A>>> {
+    Box::new(move |disk| {
+        let mut results = Vec::<A>::new();
+        let mut disk = disk;
+        for action in a.iter() {
+            let (res, disk2) = action(disk);
+            disk = disk2;
+            match res {
+                Ok(x) => results.push(x),
+                Err(e) => return (Err(e), disk),
+            }
+        }
+        (Ok(results), disk)
+    })
+}
+
+pub fn flatten_result<'a, A: 'a>(
+    d: DiskAction<'a, FsResult<FsResult<A>>>,
+) -> DiskAction<'a, FsResult<A>> {
+    Box::new(move |disk| {
+        let (a, disk2) = d(disk);
+        (a.and_then(|a| a), disk2)
+    })
+}
@@ -0,0 +1,401 @@
+use super::block::*;
+use super::disk::*;
+use super::error::*;
+use super::image::BLOCK_SIZE;
+use super::utils;
+use super::FileSystem;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::fmt;
+
+// Encrypted disks keep every block but the superblock and its backups sealed with
+// XChaCha20-Poly1305, under a key made from a passphrase with PBKDF2.
+// A sealed block is [nonce][ciphertext of the whole block][tag] kept as the data of
+// an ordinary block, so line files can still hold it. The block number is
+// authenticated along with it, so sealed blocks can't be moved around either.
+
+// PBKDF2 rounds new disks use, tests don't need to wait on them
+#[cfg(not(test))]
+static DEFAULT_ROUNDS: u32 = 100_000;
+#[cfg(test)]
+static DEFAULT_ROUNDS: u32 = 1000;
+static SALT_SIZE: usize = 16;
+static NONCE_SIZE: usize = 24;
+static TAG_SIZE: usize = 16;
+// What the key check opens to with the right key
+static KEY_CHECK: &[u8] = b"pseudoFS key check";
+// Bytes sealing adds to a block
+static SEALED_OVERHEAD: usize = BLOCK_HEADER_SIZE + BLOCK_CHECKSUM_SIZE + NONCE_SIZE + TAG_SIZE;
+
+// How a disk is encrypted, kept in the superblock
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Encryption {
+    pub metadata: bool, // Inode table and bitmaps are sealed too, not just data and the journal
+    pub rounds: u32,
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    #[serde(with = "hex")]
+    pub check: Vec<u8>, // KEY_CHECK sealed with the key, and the parameters above
+}
+
+// Line files show the salt and key check as hex
+mod hex {
+    use super::utils;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&utils::to_hex(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        utils::from_hex(&s).ok_or_else(|| serde::de::Error::custom("not hex"))
+    }
+}
+
+fn random(len: usize) -> FsResult<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes).map_err(|e| FsError::Io(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> XChaCha20Poly1305 {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+    XChaCha20Poly1305::new(&key.into())
+}
+
+// Every seal gets a fresh random nonce, so writing the same block twice looks different
+fn seal(cipher: &XChaCha20Poly1305, aad: &[u8], plain: &[u8]) -> FsResult<Vec<u8>> {
+    let nonce = random(NONCE_SIZE)?;
+    let payload = Payload { msg: plain, aad };
+    let sealed = cipher
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| FsError::Io("couldn't encrypt".into()))?;
+    Ok([nonce, sealed].concat())
+}
+
+// None with the wrong key, or when the sealed bytes were changed
+fn open(cipher: &XChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return None;
+    }
+    let (nonce, msg) = sealed.split_at(NONCE_SIZE);
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg, aad })
+        .ok()
+}
+
+// The key check authenticates how the disk is encrypted along with the key,
+// so none of it can be changed in the superblock without the check failing
+fn key_check_aad(metadata: bool, rounds: u32, salt: &[u8]) -> Vec<u8> {
+    [&[metadata as u8][..], &rounds.to_le_bytes(), salt].concat()
+}
+
+impl Encryption {
+    // New encryption for a disk along with its key
+    pub fn new(
+        passphrase: &str,
+        metadata: bool,
+        rounds: u32,
+    ) -> FsResult<(Encryption, XChaCha20Poly1305)> {
+        let salt = random(SALT_SIZE)?;
+        let cipher = derive_key(passphrase, &salt, rounds);
+        let check = seal(&cipher, &key_check_aad(metadata, rounds, &salt), KEY_CHECK)?;
+        let encryption = Encryption {
+            metadata,
+            rounds,
+            salt,
+            check,
+        };
+        Ok((encryption, cipher))
+    }
+
+    // The key for a passphrase, as long as it opens the key check
+    pub fn unlock(&self, passphrase: &str) -> FsResult<XChaCha20Poly1305> {
+        let cipher = derive_key(passphrase, &self.salt, self.rounds);
+        let aad = key_check_aad(self.metadata, self.rounds, &self.salt);
+        match open(&cipher, &aad, &self.check) {
+            Some(check) if check == KEY_CHECK => Ok(cipher),
+            _ => Err(FsError::WrongPassphrase),
+        }
+    }
+}
+
+// An encrypted disk as plain blocks, mount it like any other device
+// Writes are sealed on the way down and reads opened on the way up
+pub struct EncryptedDevice<'a> {
+    device: &'a dyn BlockDevice,
+    cipher: XChaCha20Poly1305,
+    super_block: RefCell<SuperBlock>, // Says which blocks are kept as they are
+}
+
+// Never show the key
+impl<'a> fmt::Debug for EncryptedDevice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedDevice")
+            .field("device", &self.device)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> EncryptedDevice<'a> {
+    fn new(
+        device: &'a dyn BlockDevice,
+        cipher: XChaCha20Poly1305,
+        sb: SuperBlock,
+    ) -> EncryptedDevice<'a> {
+        EncryptedDevice {
+            device,
+            cipher,
+            super_block: RefCell::new(sb),
+        }
+    }
+
+    // The superblock has to be readable to find the key check, so it and its
+    // backups are never sealed. Without metadata neither are the bitmaps and inode table
+    fn is_plain(&self, number: u32) -> bool {
+        let sb = self.super_block.borrow();
+        let metadata = sb.encryption.as_ref().is_some_and(|e| e.metadata);
+        let tables = sb.get_block_bitmap_range().start..sb.get_inode_table_block_range().end;
+        number == 1
+            || sb.get_backup_block_range().contains(&number)
+            || (!metadata && tables.contains(&number))
+    }
+}
+
+impl<'a> BlockDevice for EncryptedDevice<'a> {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        let bytes = self.device.read_block(number)?;
+        if self.is_plain(number) {
+            return Ok(bytes);
+        }
+        let block = Block::from_bytes(number, &bytes)?;
+        // A changed block fails to open just like it would fail its checksum
+        open(&self.cipher, &number.to_le_bytes(), &block.data)
+            .ok_or(FsError::ChecksumMismatch(number))
+    }
+
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+        if number == 1 {
+            // The disk might have been laid out again, i.e by a resize
+            if let Some(sb) = SuperBlock::from_bytes(data).filter(|sb| sb.valid_super_block()) {
+                *self.super_block.borrow_mut() = sb;
+            }
+        }
+        if self.is_plain(number) {
+            return self.device.write_block(number, data);
+        }
+        let block = Block {
+            number,
+            b_type: BlockType::End,
+            data: seal(&self.cipher, &number.to_le_bytes(), data)?,
+        };
+        self.device.write_block(number, &block.to_bytes())
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        self.device.len()
+    }
+
+    fn flush(&self) -> FsResult<()> {
+        self.device.flush()
+    }
+
+    fn truncate(&self, len: u32) -> FsResult<()> {
+        self.device.truncate(len)
+    }
+
+    fn decrypts(&self) -> bool {
+        true
+    }
+}
+
+// Format a disk with everything but its superblock sealed under the passphrase
+pub fn format(
+    device: &dyn BlockDevice,
+    super_block: SuperBlock,
+    passphrase: &str,
+    metadata: bool,
+) -> FsResult<()> {
+    // Sealed blocks still have to fit in an image block
+    let most = BLOCK_SIZE as usize - BLOCK_HEADER_SIZE - BLOCK_CHECKSUM_SIZE - SEALED_OVERHEAD;
+    let size = super_block.geometry.block_data_size;
+    if size as usize > most {
+        return Err(FsError::InvalidGeometry(format!(
+            "block data size {} leaves no room for encryption, at most {}",
+            size, most
+        )));
+    }
+    let (encryption, cipher) = Encryption::new(passphrase, metadata, DEFAULT_ROUNDS)?;
+    let super_block = super_block.with_encryption(encryption);
+    let encrypted = EncryptedDevice::new(device, cipher, super_block.clone());
+    FileSystem::format_with(&encrypted, super_block)
+}
+
+// Unlock an encrypted disk and mount it, i.e replaying its journal
+// The device that comes back is used with Disk::new
+pub fn mount<'a>(device: &'a dyn BlockDevice, passphrase: &str) -> FsResult<EncryptedDevice<'a>> {
+    let mut disk = Disk::new(device);
+    let sb = match run(&mut disk, SuperBlock::get_super_block()) {
+        Ok(sb) if sb.valid_super_block() => sb,
+        Ok(_) | Err(FsError::InvalidSuperBlock) => {
+            run(&mut disk, SuperBlock::get_backup_super_block())?
+        }
+        Err(e) => return Err(e),
+    };
+    sb.check_supported()?;
+    let encryption = sb.encryption.clone().ok_or(FsError::NotEncrypted)?;
+    let cipher = encryption.unlock(passphrase)?;
+    let encrypted = EncryptedDevice::new(device, cipher, sb);
+    FileSystem::mount(&encrypted)?;
+    Ok(encrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::*;
+
+    fn formatted(metadata: bool) -> MemDisk {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(60).with_bitmaps().with_backups();
+        format(&device, sb, "donkey", metadata).unwrap();
+        device
+    }
+
+    #[test]
+    fn encrypted_disk_should_only_open_with_its_passphrase() {
+        let device = formatted(false);
+        {
+            let encrypted = mount(&device, "donkey").unwrap();
+            let mut disk = Disk::new(&encrypted);
+            let save =
+                FileSystem::save_as_file("secret.txt".into(), b"SomebodyOnceToldMe".to_vec());
+            run(&mut disk, save).unwrap();
+        }
+        assert_eq!(
+            mount(&device, "shrek").map(|_| ()),
+            Err(FsError::WrongPassphrase)
+        );
+        assert_eq!(FileSystem::mount(&device).map(|_| ()), Err(FsError::Locked));
+        let plain = MemDisk::formatted(60).unwrap();
+        assert_eq!(
+            mount(&plain, "donkey").map(|_| ()),
+            Err(FsError::NotEncrypted)
+        );
+
+        let encrypted = mount(&device, "donkey").unwrap();
+        let disk = FileSystem::mount(&encrypted).unwrap();
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(b"SomebodyOnceToldMe".to_vec()));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn changed_encryption_parameters_should_fail_the_key_check() {
+        let device = formatted(true);
+        let sb = SuperBlock::from_bytes(&device.read_block(1).unwrap()).unwrap();
+        let encryption = sb.encryption.clone().unwrap();
+        let changes = [
+            Encryption {
+                metadata: false,
+                ..encryption.clone()
+            },
+            Encryption {
+                rounds: encryption.rounds + 1,
+                ..encryption.clone()
+            },
+        ];
+        for changed in changes {
+            assert_eq!(
+                changed.unlock("donkey").map(|_| ()),
+                Err(FsError::WrongPassphrase)
+            );
+            // Even with the superblock's own checksum made to match
+            let changed = sb.clone().with_encryption(changed);
+            device.write_block(1, &changed.to_bytes()).unwrap();
+            assert_eq!(
+                mount(&device, "donkey").map(|_| ()),
+                Err(FsError::WrongPassphrase)
+            );
+        }
+        device.write_block(1, &sb.to_bytes()).unwrap();
+        assert!(mount(&device, "donkey").is_ok());
+    }
+
+    #[test]
+    fn encrypted_disk_should_not_hold_plain_data() {
+        for metadata in [false, true] {
+            let device = formatted(metadata);
+            let encrypted = mount(&device, "donkey").unwrap();
+            let mut disk = Disk::new(&encrypted);
+            let save =
+                FileSystem::save_as_file("secret.txt".into(), b"SomebodyOnceToldMe".to_vec());
+            run(&mut disk, save).unwrap();
+
+            let sb = run(&mut disk, SuperBlock::get_super_block()).unwrap();
+            let mut found = (false, false);
+            for n in 1..device.len().unwrap() + 1 {
+                let bytes = device.read_block(n).unwrap();
+                let raw = String::from_utf8_lossy(&bytes);
+                assert!(!raw.contains("SomebodyOnceToldMe"));
+                // The name is kept in the root directory, a data block
+                assert!(!raw.contains("secret.txt"));
+                if sb.get_inode_table_block_range().contains(&n) {
+                    found.0 |= raw.contains("start_block");
+                }
+                found.1 |= n == 1 && SuperBlock::from_bytes(&bytes).is_some();
+            }
+            // Inodes are only readable when metadata is left plain
+            assert_eq!(found, (!metadata, true));
+        }
+    }
+
+    #[test]
+    fn changed_encrypted_blocks_should_be_reported() {
+        let device = formatted(true);
+        let encrypted = mount(&device, "donkey").unwrap();
+        let mut disk = Disk::new(&encrypted);
+        let save = FileSystem::save_as_file("secret.txt".into(), b"SomebodyOnceToldMe".to_vec());
+        run(&mut disk, save).unwrap();
+
+        // A sealed block copied over another doesn't open where it was put
+        let sb = run(&mut disk, SuperBlock::get_super_block()).unwrap();
+        let n = sb.get_storage_block_range().start;
+        let moved = device.read_block(n + 5).unwrap();
+        let mut block = Block::from_bytes(n + 5, &moved).unwrap();
+        block.number = n;
+        device.write_block(n, &block.to_bytes()).unwrap();
+        assert_eq!(encrypted.read_block(n), Err(FsError::ChecksumMismatch(n)));
+        let report = run(&mut disk, FileSystem::scrub()).unwrap();
+        assert_eq!(report.damaged, vec![n]);
+    }
+
+    #[test]
+    fn resize_should_keep_an_encrypted_disk_sealed() {
+        let device = formatted(true);
+        let encrypted = mount(&device, "donkey").unwrap();
+        let mut disk = Disk::new(&encrypted);
+        let save = FileSystem::save_as_file("secret.txt".into(), b"SomebodyOnceToldMe".to_vec());
+        run(&mut disk, save).unwrap();
+
+        FileSystem::resize(&encrypted, 120).unwrap();
+        assert_eq!(device.len(), Ok(120));
+        let encrypted = mount(&device, "donkey").unwrap();
+        let mut disk = FileSystem::mount(&encrypted).unwrap();
+        let data = run(&mut disk, FileSystem::read_file("secret.txt".into()));
+        assert_eq!(data, Ok(b"SomebodyOnceToldMe".to_vec()));
+        let report = run(&mut disk, FileSystem::fsck(false));
+        assert!(report.unwrap().is_clean());
+        // The old backup superblocks are storage now, and sealed like the rest of it
+        for n in 59..61 {
+            let bytes = device.read_block(n).unwrap();
+            assert!(!SuperBlock::from_bytes(&bytes).is_some_and(|sb| sb.valid_super_block()));
+        }
+    }
+}
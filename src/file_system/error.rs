@@ -0,0 +1,104 @@
+use std::fmt;
+
+// Everything that can go wrong while working with a disk
+#[derive(Clone, Debug, PartialEq)]
+pub enum FsError {
+    NotFound(String),      // No directory entry with this name
+    AlreadyExists(String), // Directory entry with this name already exists
+    InvalidName(String),   // Name can't be used, i.e "/" or "."
+    InvalidInode(u32),     // Inode doesn't exist or is free
+    NotADirectory(String), // Path goes through something that isn't a directory
+    IsADirectory(String),  // File operation on a directory
+    DirectoryNotEmpty(String),
+    NoSpace,                  // Not enough free blocks
+    NoFreeInodes,             // Every inode is in use
+    OutOfRange(u32),          // Block number past the end of the disk
+    Corrupt { block: u32 },   // Block couldn't be parsed
+    ChecksumMismatch(u32),    // Block was changed after it was written
+    BlockOverflow(u32),       // Block is too big for the device
+    InvalidSuperBlock,        // Superblock is missing or has the wrong magic number
+    InvalidImage,             // File isn't a binary image
+    UnsupportedVersion(u32),  // Image was made by a newer version
+    InvalidGeometry(String),  // Disk can't be laid out like that
+    UnsupportedFormat(u32),   // Disk was formatted by a newer version
+    UnsupportedFeatures(u32), // Disk uses features this version doesn't know
+    ReadOnly,                 // Disk was mounted from a snapshot
+    HasSnapshots,             // Disk can't be laid out again while snapshots need its blocks
+    Locked,                   // Disk is encrypted and was mounted without its passphrase
+    WrongPassphrase,          // Passphrase doesn't open the disk's key check
+    NotEncrypted,             // Passphrase given for a disk that isn't encrypted
+    TransactionTooLarge(u32), // Transaction changes more blocks in use than the journal holds
+    FileTooLarge,             // More data blocks than the inode's pointers can reach
+    Io(String),               // Underlying file couldn't be read or written
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound(name) => write!(f, "{}: no such file", name),
+            FsError::AlreadyExists(name) => write!(f, "{}: file already exists", name),
+            FsError::InvalidName(name) => write!(f, "{}: invalid file name", name),
+            FsError::InvalidInode(number) => write!(f, "inode {} is not in use", number),
+            FsError::NotADirectory(path) => write!(f, "{}: not a directory", path),
+            FsError::IsADirectory(path) => write!(f, "{}: is a directory", path),
+            FsError::DirectoryNotEmpty(path) => write!(f, "{}: directory not empty", path),
+            FsError::NoSpace => write!(f, "not enough free blocks"),
+            FsError::NoFreeInodes => write!(f, "no free inodes"),
+            FsError::OutOfRange(block) => write!(f, "block {} is past the end of the disk", block),
+            FsError::Corrupt { block } => write!(f, "block {} is corrupt", block),
+            FsError::ChecksumMismatch(block) => write!(f, "block {} failed its checksum", block),
+            FsError::BlockOverflow(block) => write!(f, "block {} is too big for the device", block),
+            FsError::InvalidSuperBlock => write!(f, "invalid superblock"),
+            FsError::InvalidImage => write!(f, "not a disk image"),
+            FsError::UnsupportedVersion(v) => write!(f, "unsupported image version {}", v),
+            FsError::InvalidGeometry(message) => write!(f, "invalid geometry: {}", message),
+            FsError::UnsupportedFormat(v) => {
+                write!(
+                    f,
+                    "disk format version {} is newer than this one supports",
+                    v
+                )
+            }
+            FsError::UnsupportedFeatures(bits) => {
+                write!(f, "disk uses unknown features {:#x}", bits)
+            }
+            FsError::ReadOnly => write!(f, "disk is mounted read-only"),
+            FsError::HasSnapshots => write!(f, "disk has snapshots, delete them first"),
+            FsError::Locked => write!(f, "disk is encrypted, a passphrase is needed"),
+            FsError::WrongPassphrase => write!(f, "wrong passphrase"),
+            FsError::NotEncrypted => write!(f, "disk isn't encrypted"),
+            FsError::TransactionTooLarge(blocks) => {
+                write!(f, "{} blocks are too many for the journal", blocks)
+            }
+            FsError::FileTooLarge => write!(f, "file is too large for its block pointers"),
+            FsError::Io(message) => write!(f, "i/o error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+// So FsErrors can come out of the std::io traits
+impl From<FsError> for std::io::Error {
+    fn from(e: FsError) -> std::io::Error {
+        let kind = match e {
+            FsError::NotFound(_) => std::io::ErrorKind::NotFound,
+            FsError::AlreadyExists(_) => std::io::ErrorKind::AlreadyExists,
+            FsError::InvalidName(_) => std::io::ErrorKind::InvalidInput,
+            FsError::NotADirectory(_) => std::io::ErrorKind::NotADirectory,
+            FsError::IsADirectory(_) => std::io::ErrorKind::IsADirectory,
+            FsError::DirectoryNotEmpty(_) => std::io::ErrorKind::DirectoryNotEmpty,
+            FsError::NoSpace | FsError::NoFreeInodes => std::io::ErrorKind::StorageFull,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+impl From<std::io::Error> for FsError {
+    fn from(e: std::io::Error) -> FsError {
+        FsError::Io(e.to_string())
+    }
+}
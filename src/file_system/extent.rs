@@ -0,0 +1,132 @@
+use super::utils;
+
+// A run of len blocks starting at start
+// Files using extents keep their extent list in blocks of their own,
+// chained together like file data used to be, the data blocks are all End
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Extent {
+    pub start: u32,
+    pub len: u32,
+}
+
+// Each extent takes 8 bytes, [start: u32][len: u32]
+pub static EXTENTS_PER_BLOCK: usize = 128;
+
+impl Extent {
+    // Block numbers in file order -> as few extents as possible
+    pub fn from_numbers(numbers: &[u32]) -> Vec<Extent> {
+        let mut extents: Vec<Extent> = vec![];
+        for n in numbers {
+            match extents.last_mut() {
+                Some(e) if e.start + e.len == *n => e.len += 1,
+                _ => extents.push(Extent { start: *n, len: 1 }),
+            }
+        }
+        extents
+    }
+
+    pub fn to_numbers(extents: &[Extent]) -> Vec<u32> {
+        extents
+            .iter()
+            .flat_map(|e| e.start..e.start + e.len)
+            .collect()
+    }
+
+    // How many extent blocks it takes to hold count extents, always at least one
+    pub fn blocks_needed(count: usize) -> usize {
+        count.div_ceil(EXTENTS_PER_BLOCK).max(1)
+    }
+
+    pub fn to_bytes(extents: &[Extent]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for e in extents {
+            utils::put_u32(&mut bytes, e.start);
+            utils::put_u32(&mut bytes, e.len);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Vec<Extent>> {
+        if !bytes.len().is_multiple_of(8) {
+            return None;
+        }
+        (0..bytes.len())
+            .step_by(8)
+            .map(|i| {
+                Some(Extent {
+                    start: utils::get_u32(bytes, i)?,
+                    len: utils::get_u32(bytes, i + 4)?,
+                })
+            })
+            .collect()
+    }
+
+    // Pick n of the free block numbers, preferring contiguous runs
+    // Blocks straight after near come first, so a growing file stays in one piece
+    // Then the first run big enough for the rest, otherwise the biggest runs
+    pub fn pick_free(free: &[u32], n: usize, near: Option<u32>) -> Option<Vec<u32>> {
+        if free.len() < n {
+            return None;
+        }
+        let mut runs = Extent::from_numbers(free);
+        let mut picked = vec![];
+        if let Some(near) = near {
+            if let Some(run) = runs.iter_mut().find(|e| e.start == near + 1) {
+                let take = run.len.min(n as u32);
+                picked.extend(run.start..run.start + take);
+                run.start += take;
+                run.len -= take;
+            }
+        }
+        let left = (n - picked.len()) as u32;
+        match runs.iter().find(|e| e.len >= left) {
+            Some(run) => picked.extend(run.start..run.start + left),
+            None => {
+                runs.sort_by_key(|e| std::cmp::Reverse(e.len));
+                let numbers = Extent::to_numbers(&runs);
+                picked.extend(numbers.into_iter().take(left as usize));
+            }
+        }
+        Some(picked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extents_should_round_trip() {
+        let numbers = vec![4, 5, 6, 9, 10, 2];
+        let extents = Extent::from_numbers(&numbers);
+        assert_eq!(
+            extents,
+            vec![
+                Extent { start: 4, len: 3 },
+                Extent { start: 9, len: 2 },
+                Extent { start: 2, len: 1 }
+            ]
+        );
+        assert_eq!(Extent::to_numbers(&extents), numbers);
+        assert_eq!(
+            Extent::from_bytes(&Extent::to_bytes(&extents)),
+            Some(extents)
+        );
+        assert_eq!(Extent::from_bytes(&[1, 2, 3]), None);
+        assert_eq!(Extent::blocks_needed(0), 1);
+        assert_eq!(Extent::blocks_needed(EXTENTS_PER_BLOCK + 1), 2);
+    }
+
+    #[test]
+    fn pick_free_should_prefer_contiguous_runs() {
+        let free = vec![3, 5, 6, 8, 9, 10, 11, 20];
+        assert_eq!(Extent::pick_free(&free, 3, None), Some(vec![8, 9, 10]));
+        assert_eq!(Extent::pick_free(&free, 2, Some(4)), Some(vec![5, 6]));
+        assert_eq!(Extent::pick_free(&free, 3, Some(4)), Some(vec![5, 6, 3]));
+        assert_eq!(
+            Extent::pick_free(&free, 6, None),
+            Some(vec![8, 9, 10, 11, 5, 6])
+        );
+        assert_eq!(Extent::pick_free(&free, 9, None), None);
+    }
+}
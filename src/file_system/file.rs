@@ -0,0 +1,267 @@
+use super::disk::*;
+use super::error::*;
+use super::inode::InodeKind;
+use super::FileSystem;
+use std::cell::Cell;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Writes are buffered up to this many bytes before going to disk
+static MAX_PENDING: usize = 4096;
+
+// A mounted disk that files can be opened on
+// DiskActions can still be run against it with run
+#[derive(Debug)]
+pub struct MountedFileSystem<'a> {
+    disk: Cell<Disk<'a>>,
+}
+
+// How a file is opened, works like std::fs::OpenOptions
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+// An open file on a mounted disk
+// Writes are buffered and flushed when the file is dropped
+#[derive(Debug)]
+pub struct File<'f, 'a> {
+    fs: &'f MountedFileSystem<'a>,
+    path: String,
+    options: OpenOptions,
+    position: u64,
+    pending: Option<(u64, Vec<u8>)>, // Offset and data not written yet
+}
+
+impl<'a> MountedFileSystem<'a> {
+    pub fn mount(device: &'a dyn BlockDevice) -> FsResult<MountedFileSystem<'a>> {
+        Ok(MountedFileSystem {
+            disk: Cell::new(FileSystem::mount(device)?),
+        })
+    }
+
+    pub fn run<A>(&self, action: DiskAction<'a, A>) -> A {
+        let (a, disk) = action(self.disk.get());
+        self.disk.set(disk);
+        a
+    }
+
+    pub fn disk(&self) -> Disk<'a> {
+        self.disk.get()
+    }
+
+    // Open files must be dropped first so their writes get flushed
+    pub fn unmount(self) -> FsResult<()> {
+        FileSystem::unmount(self.disk.get())
+    }
+
+    pub fn open(&self, path: &str, options: &OpenOptions) -> FsResult<File<'_, 'a>> {
+        let stat = match self.run(FileSystem::stat(path.into())) {
+            Err(FsError::NotFound(_)) if options.create => {
+                self.run(FileSystem::save_as_file(path.into(), vec![]))?;
+                self.run(FileSystem::stat(path.into()))
+            }
+            x => x,
+        }?;
+        if stat.kind == InodeKind::Directory {
+            return Err(FsError::IsADirectory(path.into()));
+        }
+        if options.truncate && options.write && stat.size > 0 {
+            self.run(FileSystem::truncate(path.into(), 0))?;
+        }
+        Ok(File {
+            fs: self,
+            path: path.into(),
+            options: *options,
+            position: 0,
+            pending: None,
+        })
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    // Every write goes to the end of the file
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+}
+
+impl File<'_, '_> {
+    pub fn size(&mut self) -> FsResult<u64> {
+        self.flush_pending()?;
+        Ok(self.fs.run(FileSystem::stat(self.path.clone()))?.size)
+    }
+
+    fn flush_pending(&mut self) -> FsResult<()> {
+        if let Some((offset, data)) = self.pending.take() {
+            self.fs
+                .run(FileSystem::write_at(self.path.clone(), offset, data))?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for File<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.options.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for reading",
+            ));
+        }
+        self.flush_pending()?;
+        let data = self.fs.run(FileSystem::read_at(
+            self.path.clone(),
+            self.position,
+            buf.len(),
+        ))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Write for File<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.options.write && !self.options.append {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for writing",
+            ));
+        }
+        if self.options.append && self.pending.is_none() {
+            self.position = self.size()?;
+        }
+        // Only writes that carry on from the pending ones are buffered together
+        match &mut self.pending {
+            Some((offset, data)) if *offset + data.len() as u64 == self.position => {
+                data.extend_from_slice(buf)
+            }
+            _ => {
+                self.flush_pending()?;
+                self.pending = Some((self.position, buf.to_vec()));
+            }
+        }
+        self.position += buf.len() as u64;
+        if self.pending.as_ref().map(|(_, x)| x.len()).unwrap_or(0) >= MAX_PENDING {
+            self.flush_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        Ok(self.fs.disk().device.flush()?)
+    }
+}
+
+impl Seek for File<'_, '_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+            SeekFrom::End(n) => self.size()?.checked_add_signed(n),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for File<'_, '_> {
+    fn drop(&mut self) {
+        let _ = self.flush_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::*;
+
+    #[test]
+    fn files_should_read_write_and_seek() {
+        let device = MemDisk::formatted(50).unwrap();
+        let fs = MountedFileSystem::mount(&device).unwrap();
+        {
+            let mut file = fs
+                .open("/shrek.txt", OpenOptions::new().write(true).create(true))
+                .unwrap();
+            file.write_all(b"Ogres are like onions").unwrap();
+            file.seek(SeekFrom::Start(15)).unwrap();
+            file.write_all(b"ONIONS").unwrap();
+        }
+        let data = fs.run(FileSystem::read_file("/shrek.txt".into()));
+        assert_eq!(data, Ok(b"Ogres are like ONIONS".to_vec()));
+
+        let mut file = fs
+            .open("/shrek.txt", OpenOptions::new().read(true))
+            .unwrap();
+        let mut buf = [0; 5];
+        file.seek(SeekFrom::End(-6)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ONION");
+        let mut rest = String::new();
+        file.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "S");
+        assert!(file.write(b"nope").is_err());
+        assert!(file.seek(SeekFrom::Current(-100)).is_err());
+    }
+
+    #[test]
+    fn open_should_follow_options() {
+        let device = MemDisk::formatted(50).unwrap();
+        let fs = MountedFileSystem::mount(&device).unwrap();
+        let missing = fs.open("/a.txt", OpenOptions::new().read(true));
+        assert_eq!(missing.err(), Some(FsError::NotFound("/a.txt".into())));
+
+        fs.run(FileSystem::save_as_file("/a.txt".into(), b"abc".to_vec()))
+            .unwrap();
+        let mut file = fs.open("/a.txt", OpenOptions::new().append(true)).unwrap();
+        file.write_all(b"def").unwrap();
+        drop(file);
+        let data = fs.run(FileSystem::read_file("/a.txt".into()));
+        assert_eq!(data, Ok(b"abcdef".to_vec()));
+
+        let mut file = fs
+            .open("/a.txt", OpenOptions::new().write(true).truncate(true))
+            .unwrap();
+        assert_eq!(file.size(), Ok(0));
+        drop(file);
+        assert_eq!(
+            fs.open("/", OpenOptions::new().read(true)).err(),
+            Some(FsError::IsADirectory("/".into()))
+        );
+        fs.unmount().unwrap();
+    }
+}
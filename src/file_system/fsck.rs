@@ -0,0 +1,773 @@
+use super::bitmap::*;
+use super::block::*;
+use super::compress::{self, Compression};
+use super::directory::Directory;
+use super::disk::*;
+use super::error::*;
+use super::extent::Extent;
+use super::inode::*;
+use super::journal::transaction;
+use super::snapshot;
+use super::write_inode_data;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum FsckProblem {
+    // In use, but no inode reaches it
+    OrphanedBlock(u32),
+    // Claimed by more than one inode
+    SharedBlock {
+        block: u32,
+        inodes: Vec<u32>,
+    },
+    // Chain breaks off after block without reaching End
+    UnterminatedChain {
+        inode: u32,
+        block: u32,
+    },
+    // Extent or pointer points at a block that's free, out of range or claimed twice by the inode
+    BadDataBlock {
+        inode: u32,
+        block: u32,
+    },
+    // Directory entry points at a free inode
+    DanglingEntry {
+        directory: u32,
+        name: String,
+        inode: u32,
+    },
+    // Block fails its checksum or its JSON can't be read
+    Unparsable(u32),
+    // Superblock or one of its backups can't be read or is out of date
+    BadSuperBlock(u32),
+    // Bitmap disagrees with the block or inode
+    BlockBitmapMismatch(u32),
+    InodeBitmapMismatch(u32),
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::OrphanedBlock(n) => write!(f, "block {} is orphaned", n),
+            FsckProblem::SharedBlock { block, inodes } => {
+                write!(f, "block {} is claimed by inodes {:?}", block, inodes)
+            }
+            FsckProblem::UnterminatedChain { inode, block } => {
+                write!(f, "inode {} chain breaks off after block {}", inode, block)
+            }
+            FsckProblem::BadDataBlock { inode, block } => {
+                write!(f, "inode {} points at bad data block {}", inode, block)
+            }
+            FsckProblem::DanglingEntry {
+                directory,
+                name,
+                inode,
+            } => write!(
+                f,
+                "directory {} entry {} points at free inode {}",
+                directory, name, inode
+            ),
+            FsckProblem::Unparsable(n) => write!(f, "block {} can't be parsed", n),
+            FsckProblem::BadSuperBlock(n) => {
+                write!(f, "superblock copy in block {} is bad or out of date", n)
+            }
+            FsckProblem::BlockBitmapMismatch(n) => {
+                write!(f, "block bitmap is wrong about block {}", n)
+            }
+            FsckProblem::InodeBitmapMismatch(n) => {
+                write!(f, "inode bitmap is wrong about inode {}", n)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    pub repaired: Vec<FsckProblem>, // Only filled in repair mode
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Where following an inode's blocks got to
+struct Chain {
+    inode: Inode,
+    blocks: Vec<Block>,
+    metadata_blocks: Vec<Block>,
+    broken: Option<FsckProblem>, // Why it stops short, None when it is complete
+}
+
+impl Chain {
+    fn numbers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.metadata_blocks
+            .iter()
+            .chain(self.blocks.iter())
+            .map(|b| b.number)
+    }
+}
+
+// What's left of a file that was cut short, sized by what it decodes to
+// Compressed data cut in the middle of a match loses that last byte. Data that
+// doesn't decode at all is kept as it is uncompressed, so nothing more is lost
+fn salvage(i: Inode, blocks: &[Block]) -> (Inode, Vec<Vec<u8>>) {
+    let mut chunks: Vec<Vec<u8>> = blocks.iter().map(|b| b.data.clone()).collect();
+    let stored = chunks.concat();
+    let trimmed = stored[..stored.len().saturating_sub(1)].to_vec();
+    if let Ok(data) = compress::decode(&i, stored.clone()) {
+        let stored = match i.compression {
+            Compression::None => 0,
+            _ => stored.len() as u64,
+        };
+        let size = data.len() as u64;
+        return (Inode { size, stored, ..i }, chunks);
+    }
+    if let Ok(data) = compress::decode(&i, trimmed.clone()) {
+        if let Some(last) = chunks.last_mut() {
+            last.pop();
+        }
+        let stored = trimmed.len() as u64;
+        let size = data.len() as u64;
+        return (Inode { size, stored, ..i }, chunks);
+    }
+    let i = Inode {
+        size: stored.len() as u64,
+        stored: 0,
+        compression: Compression::None,
+        ..i
+    };
+    (i, chunks)
+}
+
+// Follow the blocks of an inode, for extents that's the chain of extent blocks
+// and then every data block they point at, up to the first bad one
+// Pointers read their pointer blocks, then the data blocks the same way
+fn follow_inode(disk: &mut Disk, sb: &SuperBlock, inode: Inode) -> (Chain, Vec<FsckProblem>) {
+    let (metadata, numbers, mut problems, mut broken) = match inode.layout {
+        Layout::Chain => return follow_chain(disk, sb, inode),
+        Layout::Extents => {
+            let (chain, mut problems) = follow_chain(disk, sb, inode);
+            let mut broken = chain.broken;
+            let mut extents = vec![];
+            for b in chain.blocks.iter() {
+                match Extent::from_bytes(&b.data) {
+                    Some(mut e) => extents.append(&mut e),
+                    None => {
+                        problems.push(FsckProblem::Unparsable(b.number));
+                        broken = Some(FsckProblem::Unparsable(b.number));
+                        break;
+                    }
+                }
+            }
+            let numbers = Extent::to_numbers(&extents);
+            (chain.blocks, numbers, problems, broken)
+        }
+        Layout::Pointers => {
+            let metadata = run(disk, Inode::get_metadata_blocks(inode));
+            let numbers = run(disk, Inode::get_data_numbers(inode, 0, usize::MAX));
+            let bad = metadata.as_ref().ok().and_then(|m| {
+                m.iter()
+                    .find(|b| {
+                        !sb.get_storage_block_range().contains(&b.number)
+                            || b.b_type == BlockType::Free
+                    })
+                    .map(|b| b.number)
+            });
+            match (metadata, numbers, bad) {
+                (Ok(metadata), Ok(numbers), None) => (metadata, numbers, vec![], None),
+                (_, Err(FsError::Corrupt { block: n }), _)
+                | (_, Err(FsError::ChecksumMismatch(n)), _)
+                | (Err(FsError::Corrupt { block: n }), _, _)
+                | (Err(FsError::ChecksumMismatch(n)), _, _)
+                | (_, _, Some(n)) => {
+                    let problem = FsckProblem::Unparsable(n);
+                    (vec![], vec![], vec![problem.clone()], Some(problem))
+                }
+                _ => {
+                    let problem = FsckProblem::Unparsable(inode.pointers.indirect);
+                    (vec![], vec![], vec![problem.clone()], Some(problem))
+                }
+            }
+        }
+    };
+    let mut blocks = vec![];
+    let mut seen = HashSet::new();
+    for n in numbers {
+        let block = match sb.get_storage_block_range().contains(&n) && seen.insert(n) {
+            true => run(disk, Block::get_block(n)).ok(),
+            false => None,
+        };
+        match block {
+            Some(b) if b.b_type != BlockType::Free => blocks.push(b),
+            _ => {
+                let problem = FsckProblem::BadDataBlock {
+                    inode: inode.number,
+                    block: n,
+                };
+                problems.push(problem.clone());
+                broken.get_or_insert(problem);
+                break;
+            }
+        }
+    }
+    let chain = Chain {
+        inode,
+        blocks,
+        metadata_blocks: metadata,
+        broken,
+    };
+    (chain, problems)
+}
+
+// Follow a chain until End, stopping at the first block that isn't part of a valid chain
+fn follow_chain(disk: &mut Disk, sb: &SuperBlock, inode: Inode) -> (Chain, Vec<FsckProblem>) {
+    let mut problems = vec![];
+    let mut blocks: Vec<Block> = vec![];
+    let mut seen = HashSet::new();
+    let mut next = inode.start_block;
+    let mut complete = false;
+    while let Some(n) = next {
+        next = None;
+        if !sb.get_storage_block_range().contains(&n) || !seen.insert(n) {
+            break;
+        }
+        match run(disk, Block::get_block(n)) {
+            Ok(b) => match b.b_type {
+                BlockType::End => {
+                    blocks.push(b);
+                    complete = true;
+                }
+                BlockType::Next(x) => {
+                    next = Some(x);
+                    blocks.push(b);
+                }
+                BlockType::Free => (),
+            },
+            Err(_) => problems.push(FsckProblem::Unparsable(n)),
+        }
+    }
+    let broken = match complete {
+        true => None,
+        false => Some(FsckProblem::UnterminatedChain {
+            inode: inode.number,
+            block: blocks.last().map(|b| b.number).unwrap_or(0),
+        }),
+    };
+    problems.extend(broken.clone());
+    let chain = Chain {
+        inode,
+        blocks,
+        metadata_blocks: vec![],
+        broken,
+    };
+    (chain, problems)
+}
+
+fn check_disk(disk: &mut Disk, repair: bool) -> FsResult<FsckReport> {
+    let sb = match run(disk, SuperBlock::get_super_block()) {
+        Ok(sb) if sb.valid_super_block() => sb,
+        Ok(_) | Err(FsError::InvalidSuperBlock) => run(disk, SuperBlock::get_backup_super_block())?,
+        Err(e) => return Err(e),
+    };
+    let mut report = FsckReport::default();
+
+    // Superblock copies, the primary one included when it was broken
+    let mut copies = vec![];
+    for n in std::iter::once(1).chain(sb.get_backup_block_range()) {
+        let copy = run(disk, read_block(n))
+            .ok()
+            .and_then(|b| SuperBlock::from_bytes(&b));
+        if copy.as_ref() != Some(&sb) {
+            copies.push(FsckProblem::BadSuperBlock(n));
+        }
+    }
+    let primary_bad = copies.first() == Some(&FsckProblem::BadSuperBlock(1));
+    if repair && !copies.is_empty() {
+        run(disk, SuperBlock::write_super_block(sb.clone()))?;
+        report.repaired.extend(copies.iter().cloned());
+    }
+    report.problems.append(&mut copies);
+    // Everything else reads the superblock, so it can't go on until it's put back
+    if primary_bad && !repair {
+        return Ok(report);
+    }
+
+    // Inode table
+    let mut inodes = vec![];
+    for n in sb.get_inode_table_block_range() {
+        let table = run(disk, Block::get_block(n)).and_then(|b| Inode::blocks_to_inodes(vec![b]));
+        match table {
+            Ok(mut table) => inodes.append(&mut table),
+            Err(_) => report.problems.push(FsckProblem::Unparsable(n)),
+        }
+    }
+
+    // Chains
+    let mut chains = vec![];
+    for inode in inodes.iter().filter(|i| i.start_block.is_some()) {
+        let (chain, mut problems) = follow_inode(disk, &sb, *inode);
+        report.problems.append(&mut problems);
+        chains.push(chain);
+    }
+
+    let mut owners: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for chain in chains.iter() {
+        for n in chain.numbers() {
+            owners.entry(n).or_default().push(chain.inode.number);
+        }
+    }
+    for (block, inodes) in owners.iter().filter(|(_, x)| x.len() > 1) {
+        report.problems.push(FsckProblem::SharedBlock {
+            block: *block,
+            inodes: inodes.clone(),
+        });
+    }
+
+    // A chain is cut short at its last good block, a chain with no good blocks loses its inode
+    // Extents and pointers are cut short the same way and written out again
+    if repair {
+        for chain in chains.iter_mut() {
+            let problem = match chain.broken.take() {
+                Some(problem) => problem,
+                None => continue,
+            };
+            match chain.blocks.last() {
+                Some(_) if chain.inode.layout != Layout::Chain => {
+                    // Extent or pointer blocks that were there get freed as orphans below
+                    let old = Inode {
+                        layout: Layout::Chain,
+                        ..chain.inode
+                    };
+                    let (new, chunks) = salvage(chain.inode, &chain.blocks);
+                    let write = write_inode_data(old, chain.blocks.clone(), new, chunks);
+                    chain.inode = run(disk, write)?;
+                }
+                Some(last) => {
+                    let (new, mut chunks) = salvage(chain.inode, &chain.blocks);
+                    let end = Block {
+                        b_type: BlockType::End,
+                        data: chunks.pop().unwrap_or_default(),
+                        ..last.clone()
+                    };
+                    run(disk, Block::write_block(end))?;
+                    chain.inode = run(disk, Inode::write_inode(new))?;
+                }
+                None => {
+                    chain.inode = run(disk, Inode::free_inode(chain.inode))?;
+                }
+            }
+            report.repaired.push(problem);
+        }
+    }
+
+    // Directory entries
+    let used: HashSet<u32> = chains
+        .iter()
+        .filter(|c| c.inode.start_block.is_some())
+        .map(|c| c.inode.number)
+        .collect();
+    let directories = chains
+        .iter()
+        .filter(|c| c.broken.is_none() && c.inode.start_block.is_some() && c.inode.is_directory())
+        .map(|c| c.inode)
+        .collect::<Vec<Inode>>();
+    for i in directories {
+        let dir = match run(disk, Directory::get_directory_at(i.number)) {
+            Ok(dir) => dir,
+            Err(_) => {
+                report
+                    .problems
+                    .push(FsckProblem::Unparsable(i.start_block.unwrap_or(0)));
+                continue;
+            }
+        };
+        for (name, inode) in dir.entries() {
+            if used.contains(&inode) {
+                continue;
+            }
+            let problem = FsckProblem::DanglingEntry {
+                directory: i.number,
+                name: name.clone(),
+                inode,
+            };
+            report.problems.push(problem.clone());
+            if repair {
+                run(disk, Directory::remove_file_name_at(i.number, name))?;
+                report.repaired.push(problem);
+            }
+        }
+    }
+
+    // Blocks nobody owns, removing dangling entries may have moved directories around
+    // Blocks only a snapshot needs are owned by it
+    let mut owned: HashSet<u32> = if repair {
+        reachable_blocks(disk, &sb)?
+    } else {
+        owners.keys().copied().collect()
+    };
+    owned.extend(run(disk, snapshot::held_blocks())?);
+    for n in sb.get_storage_block_range().filter(|n| !owned.contains(n)) {
+        let problem = match run(disk, Block::get_block(n)) {
+            Ok(b) if b.b_type == BlockType::Free => continue,
+            Ok(_) => FsckProblem::OrphanedBlock(n),
+            Err(_) => FsckProblem::Unparsable(n),
+        };
+        if !report.problems.contains(&problem) {
+            report.problems.push(problem.clone());
+        }
+        if repair {
+            let free = Block {
+                number: n,
+                b_type: BlockType::Free,
+                data: vec![],
+            };
+            run(disk, Block::write_block(free))?;
+            report.repaired.push(problem);
+        }
+    }
+
+    let mut problems = check_bitmaps(disk, &sb)?;
+    if repair && !problems.is_empty() {
+        run(disk, Bitmap::rebuild())?;
+        report.repaired.extend(problems.iter().cloned());
+    }
+    report.problems.append(&mut problems);
+    Ok(report)
+}
+
+// Compare the stored bitmaps against what's really on the disk
+fn check_bitmaps(disk: &mut Disk, sb: &SuperBlock) -> FsResult<Vec<FsckProblem>> {
+    if sb.get_block_bitmap_range().is_empty() {
+        return Ok(vec![]);
+    }
+    let (blocks, inodes) = run(disk, Bitmap::build(sb.clone()))?;
+    let checks = [
+        (BitmapKind::Blocks, blocks, sb.total_blocks),
+        (BitmapKind::Inodes, inodes, sb.get_inode_capacity()),
+    ];
+    let mut problems = vec![];
+    for (kind, expected, len) in checks {
+        let stored = match run(disk, Bitmap::read(sb, kind)) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => continue,
+            Err(FsError::Corrupt { block }) | Err(FsError::ChecksumMismatch(block)) => {
+                problems.push(FsckProblem::Unparsable(block));
+                Bitmap::new(len)
+            }
+            Err(e) => return Err(e),
+        };
+        for n in (1..len + 1).filter(|n| stored.get(*n) != expected.get(*n)) {
+            problems.push(match kind {
+                BitmapKind::Blocks => FsckProblem::BlockBitmapMismatch(n),
+                BitmapKind::Inodes => FsckProblem::InodeBitmapMismatch(n),
+            });
+        }
+    }
+    Ok(problems)
+}
+
+// Every block reached by following the chains of used inodes
+fn reachable_blocks(disk: &mut Disk, sb: &SuperBlock) -> FsResult<HashSet<u32>> {
+    let inodes = run(disk, Inode::get_inode_table())?;
+    let mut owned = HashSet::new();
+    for inode in inodes.into_iter().filter(|i| i.start_block.is_some()) {
+        let (chain, _) = follow_inode(disk, sb, inode);
+        owned.extend(chain.numbers());
+    }
+    Ok(owned)
+}
+
+// Walk the inode table and every chain, reporting anything inconsistent
+// In repair mode broken chains are cut short, dangling entries removed,
+// orphaned or unparsable blocks nobody owns are freed and the bitmaps rebuilt
+// Shared blocks and unparsable inode tables or directories are only reported
+pub fn fsck<'a>(repair: bool) -> DiskAction<'a, FsResult<FsckReport>> {
+    let check: DiskAction<'a, FsResult<FsckReport>> = Box::new(move |disk| {
+        let mut disk = disk;
+        let report = check_disk(&mut disk, repair);
+        (report, disk)
+    });
+    if !repair {
+        return check;
+    }
+    // The journal is found through the superblock, so a broken one is put back first
+    let check = transaction(check);
+    Box::new(move |disk| {
+        let mut disk = disk;
+        let restored = match restore_super_block(&mut disk) {
+            Ok(x) => x,
+            Err(e) => return (Err(e), disk),
+        };
+        let (report, disk) = check(disk);
+        let report = report.map(|mut r| {
+            if restored {
+                r.problems.insert(0, FsckProblem::BadSuperBlock(1));
+                r.repaired.insert(0, FsckProblem::BadSuperBlock(1));
+            }
+            r
+        });
+        (report, disk)
+    })
+}
+
+// Copy a backup over a broken superblock, gives back whether it had to
+fn restore_super_block(disk: &mut Disk) -> FsResult<bool> {
+    match run(disk, SuperBlock::get_super_block()) {
+        Ok(sb) if sb.valid_super_block() => Ok(false),
+        Ok(_) | Err(FsError::InvalidSuperBlock) => {
+            let sb = run(disk, SuperBlock::get_backup_super_block())?;
+            run(disk, write_block(1, sb.to_bytes()))?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
+    use super::*;
+
+    fn write(device: &MemDisk, number: u32, b_type: BlockType, data: &str) {
+        let block = Block {
+            number,
+            b_type,
+            data: data.into(),
+        };
+        device.write_block(number, &block.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn fresh_disk_should_be_clean() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        let writes = disk.writes;
+        let (report, disk) = fsck(false)(disk);
+        assert_eq!(report.map(|r| r.problems), Ok(vec![]));
+        assert_eq!(disk.writes, writes);
+
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let (report, _) = fsck(false)(Disk::new(&device));
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn fsck_should_report_problems() {
+        // sda1: inode 3 is secret.txt at 4 -> 6 -> 8 -> 9, the root directory is at 5
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        write(&device, 7, BlockType::End, "Lost");
+        write(&device, 8, BlockType::Next(5), "Told");
+        let (report, _) = fsck(false)(Disk::new(&device));
+        let problems = report.unwrap().problems;
+        assert!(problems.contains(&FsckProblem::OrphanedBlock(7)));
+        assert!(problems.contains(&FsckProblem::OrphanedBlock(9)));
+        assert!(problems.contains(&FsckProblem::SharedBlock {
+            block: 5,
+            inodes: vec![1, 3]
+        }));
+
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        write(&device, 6, BlockType::Free, "");
+        device.write_block(10, b"garbage").unwrap();
+        let (report, _) = fsck(false)(Disk::new(&device));
+        let problems = report.unwrap().problems;
+        assert!(problems.contains(&FsckProblem::UnterminatedChain { inode: 3, block: 4 }));
+        assert!(problems.contains(&FsckProblem::Unparsable(10)));
+    }
+
+    #[test]
+    fn repair_should_leave_disk_clean() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        let (_, disk) = FileSystem::save_as_file("donkey.txt".into(), "Waffles".into())(disk);
+        // Free donkey's inode behind the directory's back, leaving its block orphaned
+        let (i, disk) = Inode::get_inode(3)(disk);
+        let (_, disk) = Inode::free_inode(i.unwrap())(disk);
+
+        let (report, disk) = fsck(true)(disk);
+        let report = report.unwrap();
+        assert!(report.problems.contains(&FsckProblem::DanglingEntry {
+            directory: ROOT_INODE,
+            name: "donkey.txt".into(),
+            inode: 3
+        }));
+        assert_eq!(report.problems.len(), report.repaired.len());
+
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok("Ogres".into()));
+    }
+
+    #[test]
+    fn repair_should_fix_bitmaps() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("shrek.txt".into(), "Ogres".into())(disk);
+        // Forget the file's data block and inode are in use
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let i = i.unwrap();
+        let block = i.start_block.unwrap();
+        let (_, disk) = Bitmap::mark(BitmapKind::Blocks, vec![block], false)(disk);
+        let (_, disk) = Bitmap::mark(BitmapKind::Inodes, vec![2], false)(disk);
+
+        let (report, disk) = fsck(true)(disk);
+        let report = report.unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                FsckProblem::BlockBitmapMismatch(block),
+                FsckProblem::InodeBitmapMismatch(2)
+            ]
+        );
+        assert_eq!(report.repaired, report.problems);
+        let (report, _) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn repair_should_cut_broken_chain_short() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        write(&device, 8, BlockType::Free, "");
+        let (report, disk) = fsck(true)(Disk::new(&device));
+        assert!(!report.unwrap().repaired.is_empty());
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok("SomebodyOnce".into()));
+        let (stat, _) = FileSystem::stat("secret.txt".into())(disk);
+        assert_eq!(stat.unwrap().size, 12);
+
+        // Chains that recorded a size get the size of what's left
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(50)
+            .with_bitmaps()
+            .with_layout(Layout::Chain);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), vec![b'a'; 120])(disk);
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let (blocks, disk) = Inode::get_inode_blocks(i.unwrap())(disk);
+        write(&device, blocks.unwrap().1[1].number, BlockType::Free, "");
+        let (report, disk) = fsck(true)(disk);
+        assert!(!report.unwrap().repaired.is_empty());
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (i, disk) = Inode::get_inode(2)(disk);
+        assert_eq!(i.unwrap().size, 50);
+        let (data, _) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(vec![b'a'; 50]));
+    }
+
+    #[test]
+    fn repair_should_size_cut_compressed_files_by_their_data() {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(80)
+            .with_bitmaps()
+            .with_compression(Compression::Lz);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = Disk::new(&device);
+        let text: Vec<u8> = (0..400u32)
+            .flat_map(|x| (x % 97).to_string().into_bytes())
+            .collect();
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), text.clone())(disk);
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let (blocks, disk) = Inode::get_inode_blocks(i.unwrap())(disk);
+        let blocks = blocks.unwrap().1;
+        assert!(blocks.len() > 3);
+        write(&device, blocks[3].number, BlockType::Free, "");
+
+        let (report, disk) = fsck(true)(disk);
+        assert!(!report.unwrap().repaired.is_empty());
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, disk) = FileSystem::read_file("a.txt".into())(disk);
+        let data = data.unwrap();
+        assert!(!data.is_empty() && text.starts_with(&data));
+        let (stat, _) = FileSystem::stat("a.txt".into())(disk);
+        let stat = stat.unwrap();
+        assert_eq!(stat.size, data.len() as u64);
+        // Not the length of what's left in the blocks
+        assert_ne!(stat.size, stat.stored);
+    }
+
+    #[test]
+    fn repair_should_cut_bad_extents_short() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let file_data = vec![b'a'; 120];
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), file_data)(disk);
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let (blocks, disk) = Inode::get_inode_blocks(i.unwrap())(disk);
+        let middle = blocks.unwrap().1[1].number;
+        write(&device, middle, BlockType::Free, "");
+
+        let (report, disk) = fsck(true)(disk);
+        let report = report.unwrap();
+        let bad = FsckProblem::BadDataBlock {
+            inode: 2,
+            block: middle,
+        };
+        assert!(report.problems.contains(&bad));
+        assert!(report.repaired.contains(&bad));
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, _) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(vec![b'a'; 50]));
+    }
+
+    #[test]
+    fn repair_should_cut_bad_pointers_short() {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(50)
+            .with_bitmaps()
+            .with_layout(Layout::Pointers);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), vec![b'a'; 120])(disk);
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (i, disk) = Inode::get_inode(2)(disk);
+        let middle = i.unwrap().pointers.direct[1];
+        write(&device, middle, BlockType::Free, "");
+
+        let (report, disk) = fsck(true)(disk);
+        let bad = FsckProblem::BadDataBlock {
+            inode: 2,
+            block: middle,
+        };
+        assert!(report.unwrap().repaired.contains(&bad));
+        let (report, disk) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        let (data, _) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(vec![b'a'; 50]));
+    }
+
+    #[test]
+    fn repair_should_rewrite_bad_superblock_copies() {
+        let device = MemDisk::formatted(50).unwrap();
+        device.write_block(1, b"Fiona").unwrap();
+        device.write_block(50, b"Farquaad").unwrap();
+        let (report, disk) = fsck(false)(Disk::new(&device));
+        let problems = report.unwrap().problems;
+        assert_eq!(
+            problems,
+            vec![
+                FsckProblem::BadSuperBlock(1),
+                FsckProblem::BadSuperBlock(50)
+            ]
+        );
+        let (report, disk) = fsck(true)(disk);
+        assert_eq!(report.unwrap().repaired, problems);
+        let (report, _) = fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+}
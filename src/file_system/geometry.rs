@@ -0,0 +1,98 @@
+use super::block::{BLOCK_CHECKSUM_SIZE, BLOCK_HEADER_SIZE};
+use super::error::*;
+use super::image::BLOCK_SIZE;
+use super::inode::Inode;
+use serde::{Deserialize, Serialize};
+
+// How a disk is carved up, picked when it is formatted and kept in the superblock
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Geometry {
+    pub inode_percent: u32, // Percent of the disk's blocks given to the inode table
+    pub block_data_size: u32, // Bytes of file data kept in each block
+    pub inodes_per_block: u32, // Inodes kept in each inode table block
+}
+
+impl Default for Geometry {
+    // What every disk used before geometry was recorded
+    fn default() -> Geometry {
+        Geometry {
+            inode_percent: 10,
+            block_data_size: 50,
+            inodes_per_block: 5,
+        }
+    }
+}
+
+impl Geometry {
+    pub fn validate(&self) -> FsResult<()> {
+        let max_data = BLOCK_SIZE - (BLOCK_HEADER_SIZE + BLOCK_CHECKSUM_SIZE) as u32;
+        if !(1..=50).contains(&self.inode_percent) {
+            return Err(FsError::InvalidGeometry(format!(
+                "inode percent {} isn't between 1 and 50",
+                self.inode_percent
+            )));
+        }
+        if !(1..=max_data).contains(&self.block_data_size) {
+            return Err(FsError::InvalidGeometry(format!(
+                "block data size {} isn't between 1 and {}",
+                self.block_data_size, max_data
+            )));
+        }
+        // Every inode in a table block has to fit, even with all its fields at their longest
+        let most = (1..)
+            .take_while(|n| Inode::table_block_size(*n) <= max_data as usize)
+            .last()
+            .unwrap_or(0);
+        if !(1..=most).contains(&self.inodes_per_block) {
+            return Err(FsError::InvalidGeometry(format!(
+                "inodes per block {} isn't between 1 and {}",
+                self.inodes_per_block, most
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn inode_table_blocks(&self, total_blocks: u32) -> u32 {
+        (total_blocks as u64 * self.inode_percent as u64 / 100) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_should_reject_impossible_geometry() {
+        assert_eq!(Geometry::default().validate(), Ok(()));
+        assert_eq!(Geometry::default().inode_table_blocks(50), 5);
+        let g = Geometry {
+            block_data_size: BLOCK_SIZE,
+            ..Geometry::default()
+        };
+        assert!(g.validate().is_err());
+        let g = Geometry {
+            inodes_per_block: 0,
+            ..Geometry::default()
+        };
+        assert!(g.validate().is_err());
+    }
+
+    #[test]
+    fn validate_should_fit_full_inodes_in_a_table_block() {
+        let max_data = (BLOCK_SIZE as usize) - BLOCK_HEADER_SIZE - BLOCK_CHECKSUM_SIZE;
+        let most = (1..)
+            .find(|n| Inode::table_block_size(n + 1) > max_data)
+            .unwrap();
+        assert!(Inode::table_block_size(most) <= max_data);
+        let g = Geometry {
+            inodes_per_block: most,
+            ..Geometry::default()
+        };
+        assert_eq!(g.validate(), Ok(()));
+        let g = Geometry {
+            inodes_per_block: most + 1,
+            ..Geometry::default()
+        };
+        assert!(g.validate().is_err());
+    }
+}
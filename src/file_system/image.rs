@@ -0,0 +1,232 @@
+use super::disk::*;
+use super::error::*;
+use super::line_handler::LineFile;
+use super::utils;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Binary image file
+// [header: IMAGE_HEADER_SIZE bytes][block 1][block 2]...
+// Every block takes up exactly block_size bytes, so block n lives at
+// IMAGE_HEADER_SIZE + (n - 1) * block_size
+// Header layout: [magic: 8 bytes][version: u32][block size: u32], the rest is reserved
+static IMAGE_MAGIC: &[u8; 8] = b"PSFSIMG\0";
+pub static IMAGE_VERSION: u32 = 1;
+static IMAGE_HEADER_SIZE: u64 = 64;
+pub static BLOCK_SIZE: u32 = 4096;
+
+#[derive(Debug)]
+pub struct ImageFile {
+    file: RefCell<fs::File>,
+    block_size: u32,
+}
+
+fn header(block_size: u32) -> Vec<u8> {
+    let mut header = IMAGE_MAGIC.to_vec();
+    utils::put_u32(&mut header, IMAGE_VERSION);
+    utils::put_u32(&mut header, block_size);
+    header.resize(IMAGE_HEADER_SIZE as usize, 0);
+    header
+}
+
+impl ImageFile {
+    // Create a new empty image, anything already at path is lost
+    // Blocks are always BLOCK_SIZE, the only size open accepts
+    pub fn create(path: &str) -> FsResult<ImageFile> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(&header(BLOCK_SIZE))?;
+        Ok(ImageFile {
+            file: RefCell::new(file),
+            block_size: BLOCK_SIZE,
+        })
+    }
+
+    pub fn open(path: &str) -> FsResult<ImageFile> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = vec![0; IMAGE_HEADER_SIZE as usize];
+        file.read_exact(&mut header)
+            .map_err(|_| FsError::InvalidImage)?;
+        if &header[0..8] != IMAGE_MAGIC {
+            return Err(FsError::InvalidImage);
+        }
+        let version = utils::get_u32(&header, 8).ok_or(FsError::InvalidImage)?;
+        if version != IMAGE_VERSION {
+            return Err(FsError::UnsupportedVersion(version));
+        }
+        // Blocks are laid out for BLOCK_SIZE, an image with any other size wasn't
+        // written by this filesystem, and 0 would leave nothing to address blocks by
+        let block_size = utils::get_u32(&header, 12).ok_or(FsError::InvalidImage)?;
+        if block_size != BLOCK_SIZE {
+            return Err(FsError::InvalidImage);
+        }
+        Ok(ImageFile {
+            file: RefCell::new(file),
+            block_size,
+        })
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn offset(&self, number: u32) -> u64 {
+        IMAGE_HEADER_SIZE + (number as u64 - 1) * self.block_size as u64
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        if number == 0 || number > self.len()? {
+            return Err(FsError::OutOfRange(number));
+        }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.offset(number)))?;
+        let mut data = vec![0; self.block_size as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+        if number == 0 {
+            return Err(FsError::OutOfRange(number));
+        }
+        if data.len() > self.block_size as usize {
+            return Err(FsError::BlockOverflow(number));
+        }
+        let mut padded = data.to_vec();
+        padded.resize(self.block_size as usize, 0);
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.offset(number)))?;
+        file.write_all(&padded)?;
+        Ok(())
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        let size = self.file.borrow().metadata()?.len();
+        Ok((size.saturating_sub(IMAGE_HEADER_SIZE) / self.block_size as u64) as u32)
+    }
+
+    fn flush(&self) -> FsResult<()> {
+        let mut file = self.file.borrow_mut();
+        file.flush()?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn truncate(&self, len: u32) -> FsResult<()> {
+        if len < self.len()? {
+            self.file.borrow().set_len(self.offset(len + 1))?;
+        }
+        Ok(())
+    }
+}
+
+// Open either kind of disk, binary images are recognised by their magic number
+// anything else is treated as a JSON line file
+pub fn open_device(path: &str) -> FsResult<Box<dyn BlockDevice>> {
+    let mut magic = [0; 8];
+    let is_image = fs::File::open(path)?.read_exact(&mut magic).is_ok() && &magic == IMAGE_MAGIC;
+    if is_image {
+        Ok(Box::new(ImageFile::open(path)?))
+    } else {
+        Ok(Box::new(LineFile::new(path)))
+    }
+}
+
+// JSON line file -> binary image
+pub fn lines_to_image(lines: &str, image: &str) -> FsResult<u32> {
+    let from = LineFile::new(lines);
+    let to = ImageFile::create(image)?;
+    copy_blocks(&from, &to)
+}
+
+// binary image -> JSON line file
+pub fn image_to_lines(image: &str, lines: &str) -> FsResult<u32> {
+    let from = ImageFile::open(image)?;
+    fs::File::create(lines)?;
+    let to = LineFile::new(lines);
+    copy_blocks(&from, &to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::block::*;
+    use super::super::FileSystem;
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("file_system_{}", name))
+            .to_string_lossy()
+            .into()
+    }
+
+    #[test]
+    fn blocks_should_be_fixed_size_and_addressable() {
+        let path = temp_path("image_blocks_test");
+        let image = ImageFile::create(&path).unwrap();
+        assert_eq!(image.block_size(), BLOCK_SIZE);
+        assert_eq!(image.write_block(3, b"Shrek"), Ok(()));
+        assert_eq!(image.len(), Ok(3));
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            IMAGE_HEADER_SIZE + 3 * BLOCK_SIZE as u64
+        );
+
+        let data = image.read_block(3).unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE as usize);
+        assert_eq!(&data[0..5], b"Shrek");
+        assert_eq!(image.read_block(4), Err(FsError::OutOfRange(4)));
+        let too_big = vec![1; BLOCK_SIZE as usize + 1];
+        assert_eq!(
+            image.write_block(1, &too_big),
+            Err(FsError::BlockOverflow(1))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_should_reject_files_that_are_not_images() {
+        assert_eq!(
+            ImageFile::open("./test-files/sda1").err(),
+            Some(FsError::InvalidImage)
+        );
+    }
+
+    #[test]
+    fn open_should_reject_images_with_another_block_size() {
+        let path = temp_path("image_block_size_test");
+        for block_size in [0, 64, BLOCK_SIZE + 1] {
+            fs::write(&path, header(block_size)).unwrap();
+            assert_eq!(ImageFile::open(&path).err(), Some(FsError::InvalidImage));
+        }
+        ImageFile::create(&path).unwrap();
+        assert_eq!(ImageFile::open(&path).unwrap().len(), Ok(0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lines_to_image_and_back_should_keep_files() {
+        let image = temp_path("lines_to_image_test");
+        let lines = temp_path("image_to_lines_test");
+        assert_eq!(lines_to_image("./test-files/sda1", &image), Ok(10));
+
+        let device = open_device(&image).unwrap();
+        let disk = FileSystem::mount(device.as_ref()).unwrap();
+        let (data, _) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok("SomebodyOnceToldMe".into()));
+
+        assert_eq!(image_to_lines(&image, &lines), Ok(10));
+        let device = LineFile::new(&lines);
+        let data = device.read_block(4).unwrap();
+        assert_eq!(Block::from_bytes(4, &data).unwrap().data, b"Somebody");
+        fs::remove_file(&image).unwrap();
+        fs::remove_file(&lines).unwrap();
+    }
+}
@@ -1,219 +1,641 @@
+use super::bitmap::*;
 use super::block::*;
-use super::diagnostics::*;
+use super::compress::{self, Compression};
 use super::disk::*;
+use super::error::*;
+use super::extent::Extent;
+use super::pointers::*;
 use super::utils;
 use serde::{Deserialize, Serialize};
 
-static INODE_TABLE_SIZE: u32 = 5;
+pub static ROOT_INODE: u32 = 1;
 
-#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Inode {
     pub number: u32,
     pub start_block: Option<u32>, // None means inode is free
+    #[serde(default)]
+    pub kind: InodeKind,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default, skip_serializing_if = "Pointers::is_empty")]
+    pub pointers: Pointers,
+    #[serde(default)]
+    pub size: u64, // Bytes of data
+    #[serde(default)]
+    pub created: u64, // Seconds since the unix epoch
+    #[serde(default)]
+    pub modified: u64,
+    #[serde(default)]
+    pub accessed: u64,
+    #[serde(default)]
+    pub mode: u32, // Permission bits, i.e 0o644
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub stored: u64, // Bytes of compressed data in the blocks, 0 when not compressed
 }
 
-// First Inode is associated with the directory
-// Inode table spans across two blocks
-// Inode table blocks take up 10% of available blocks
+fn is_zero(x: &u64) -> bool {
+    *x == 0
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum InodeKind {
+    #[default]
+    File,
+    Directory,
+}
+
+// How an inode finds its data blocks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    #[default]
+    Chain, // start_block is the first data block, each one links to the next
+    Extents,  // start_block is the first block of the extent list
+    Pointers, // start_block is the first data block, pointers has the rest
+}
+
+impl Layout {
+    // Superblocks keep the layout new files get as a number
+    // 0 is extents, they were used before the superblock recorded it
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Layout::Extents => 0,
+            Layout::Pointers => 1,
+            Layout::Chain => 2,
+        }
+    }
+
+    pub fn from_u32(n: u32) -> Option<Layout> {
+        match n {
+            0 => Some(Layout::Extents),
+            1 => Some(Layout::Pointers),
+            2 => Some(Layout::Chain),
+            _ => None,
+        }
+    }
+}
+
+// What stat reports about a file or directory
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileStat {
+    pub inode: u32,
+    pub kind: InodeKind,
+    pub layout: Layout,
+    pub blocks: u32,
+    pub size: u64,   // Bytes of data
+    pub stored: u64, // Bytes the data takes up in its blocks
+    pub compression: Compression,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// First Inode is associated with the root directory
+// Inode table blocks take up the superblock's inode percent of the disk
 impl Inode {
-    pub fn generate_inodes(size: u32) -> Vec<Inode> {
-        let inode_table_blocks = (size as f32 * 0.10) as u32;
-        let total_inodes = inode_table_blocks * size;
-        (1..total_inodes + 1)
-            .into_iter()
+    pub fn generate_inodes(sb: &SuperBlock) -> Vec<Inode> {
+        (1..sb.get_inode_capacity() + 1)
             .map(|x| Inode {
                 number: x,
                 start_block: None,
+                ..Inode::default()
             })
             .collect()
     }
 
-    pub fn get_free_inodes<'a>() -> DiskAction<'a, Vec<Inode>> {
+    // The most bytes count inodes can take as a block of the inode table,
+    // with every field as long as it gets
+    pub fn table_block_size(count: u32) -> usize {
+        let widest = Inode {
+            number: u32::MAX,
+            start_block: Some(u32::MAX),
+            kind: InodeKind::Directory,
+            layout: Layout::Extents,
+            pointers: Pointers {
+                direct: [u32::MAX; DIRECT_POINTERS],
+                indirect: u32::MAX,
+                double_indirect: u32::MAX,
+            },
+            size: u64::MAX,
+            created: u64::MAX,
+            modified: u64::MAX,
+            accessed: u64::MAX,
+            mode: u32::MAX,
+            uid: u32::MAX,
+            gid: u32::MAX,
+            compression: Compression::Lz,
+            stored: u64::MAX,
+        };
+        let size = serde_json::to_vec(&widest).unwrap_or_default().len();
+        // [inode,inode,...]
+        2 + count as usize * (size + 1) - 1
+    }
+
+    // Older disks never recorded a kind, but the root is always a directory
+    pub fn is_directory(&self) -> bool {
+        self.kind == InodeKind::Directory || self.number == ROOT_INODE
+    }
+
+    // Fresh metadata for a newly created file or directory
+    pub fn created(self, kind: InodeKind, size: u64) -> Inode {
+        let now = utils::now();
+        Inode {
+            kind,
+            size,
+            created: now,
+            modified: now,
+            accessed: now,
+            mode: match kind {
+                InodeKind::File => 0o644,
+                InodeKind::Directory => 0o755,
+            },
+            ..self
+        }
+    }
+
+    // Older disks never recorded a size, so it's worked out from the blocks
+    pub fn to_stat(self, blocks: &[Block]) -> FileStat {
+        FileStat {
+            inode: self.number,
+            kind: if self.is_directory() {
+                InodeKind::Directory
+            } else {
+                self.kind
+            },
+            layout: self.layout,
+            blocks: blocks.len() as u32,
+            size: match self.size {
+                0 => blocks.iter().map(|b| b.data.len() as u64).sum(),
+                size => size,
+            },
+            stored: blocks.iter().map(|b| b.data.len() as u64).sum(),
+            compression: self.compression,
+            created: self.created,
+            modified: self.modified,
+            accessed: self.accessed,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+        }
+    }
+
+    pub fn get_free_inodes<'a>() -> DiskAction<'a, FsResult<Vec<Inode>>> {
         let d = Inode::get_inode_table();
-        let d = map(d, Box::new(|x| x.unwrap_or(vec![])));
-        map(
+        map_result(
             d,
-            Box::new(|x| x.into_iter().filter(|x| x.start_block == None).collect()),
+            Box::new(|x| x.into_iter().filter(|x| x.start_block.is_none()).collect()),
         )
     }
 
-    fn get_inode_table<'a>() -> DiskAction<'a, Option<Vec<Inode>>> {
+    pub fn get_inode_table<'a>() -> DiskAction<'a, FsResult<Vec<Inode>>> {
         let d = SuperBlock::get_super_block();
-        let d = flat_map(
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(|sb: SuperBlock| {
+            Box::new(|sb: SuperBlock| {
                 let reads = sb
                     .get_inode_table_block_range()
-                    .into_iter()
-                    .map(|i| Block::get_block(i))
+                    .map(Block::get_block)
                     .collect();
-                sequence(reads)
-            })),
+                sequence_result(reads)
+            }),
         );
 
-        map(d, utils::lift(Box::new(&Inode::blocks_to_inodes)))
+        map(d, Box::new(|x| x.and_then(Inode::blocks_to_inodes)))
     }
 
-    pub fn get_inode<'a>(s: u32) -> DiskAction<'a, Option<Inode>> {
+    pub fn get_inode<'a>(s: u32) -> DiskAction<'a, FsResult<Inode>> {
         let d = Inode::get_inode_table();
-        let d = map(
+        map(
             d,
-            utils::lift(Box::new(move |vec| vec.into_iter().find(|x| x.number == s))),
-        );
-        map(d, Box::new(|x| x.flatten()))
+            Box::new(move |x| {
+                x.and_then(|vec| {
+                    vec.into_iter()
+                        .find(|x| x.number == s)
+                        .ok_or(FsError::InvalidInode(s))
+                })
+            }),
+        )
+    }
+
+    // Free inodes come back with the layout the superblock gives new files
+    // The inode bitmap finds one without going through the whole inode table,
+    // disks without one, or with a bitmap that's out of date, have the table searched
+    pub fn get_free_inode<'a>() -> DiskAction<'a, FsResult<Inode>> {
+        let d = SuperBlock::get_super_block();
+        flat_map_result(
+            d,
+            Box::new(|sb: SuperBlock| {
+                let layout = sb.layout;
+                let d = Bitmap::read(&sb, BitmapKind::Inodes);
+                let d = flat_map_result(
+                    d,
+                    Box::new(move |bitmap| {
+                        let capacity = sb.get_inode_capacity();
+                        let clear = bitmap.map(|b| (1..capacity + 1).find(|n| !b.get(*n)));
+                        match clear {
+                            None => Inode::search_free_inode(),
+                            Some(None) => unit(Err(FsError::NoFreeInodes)),
+                            Some(Some(n)) => {
+                                let d = Inode::get_inode_in_block(&sb, n);
+                                flat_map(
+                                    d,
+                                    Box::new(|i| match i {
+                                        Ok(i) if i.start_block.is_none() => unit(Ok(i)),
+                                        _ => Inode::search_free_inode(),
+                                    }),
+                                )
+                            }
+                        }
+                    }),
+                );
+                map_result(d, Box::new(move |i| Inode { layout, ..i }))
+            }),
+        )
     }
 
-    pub fn get_free_inode<'a>() -> DiskAction<'a, Option<Inode>> {
+    fn search_free_inode<'a>() -> DiskAction<'a, FsResult<Inode>> {
         let d = Inode::get_inode_table();
-        let d = map(
+        map(
             d,
-            utils::lift(Box::new(|v| v.into_iter().find(|x| x.start_block == None))),
-        );
-        map(d, Box::new(|x| x.flatten()))
+            Box::new(|x| {
+                x.and_then(|v| {
+                    v.into_iter()
+                        .find(|x| x.start_block.is_none())
+                        .ok_or(FsError::NoFreeInodes)
+                })
+            }),
+        )
     }
 
-    pub fn parse_inodes(s: &str) -> Option<Vec<Inode>> {
-        serde_json::from_str(s).ok()
+    // Read an inode from the one table block it should be in
+    fn get_inode_in_block<'a>(sb: &SuperBlock, n: u32) -> DiskAction<'a, FsResult<Inode>> {
+        let table = sb.get_inode_table_block_range();
+        let number = table.start + (n.max(1) - 1) / sb.geometry.inodes_per_block;
+        if n == 0 || !table.contains(&number) {
+            return unit(Err(FsError::InvalidInode(n)));
+        }
+        let d = Block::get_block(number);
+        map(
+            d,
+            Box::new(move |b| {
+                Inode::blocks_to_inodes(vec![b?])?
+                    .into_iter()
+                    .find(|x| x.number == n)
+                    .ok_or(FsError::InvalidInode(n))
+            }),
+        )
     }
 
-    pub fn blocks_to_inodes(b: Vec<Option<Block>>) -> Vec<Inode> {
-        let option_inodes = utils::remove_options(b)
-            .into_iter()
-            .map(|x| Inode::parse_inodes(&x.data))
-            .collect();
-        utils::remove_options(option_inodes)
+    pub fn parse_inodes(s: &[u8]) -> Option<Vec<Inode>> {
+        serde_json::from_slice(s).ok()
+    }
+
+    pub fn blocks_to_inodes(b: Vec<Block>) -> FsResult<Vec<Inode>> {
+        let inodes = b
             .into_iter()
-            .flatten()
-            .collect()
+            .map(|x| Inode::parse_inodes(&x.data).ok_or(FsError::Corrupt { block: x.number }))
+            .collect::<FsResult<Vec<Vec<Inode>>>>()?;
+        Ok(inodes.into_iter().flatten().collect())
+    }
+
+    // Given a Inode, return all of its data blocks in order
+    pub fn get_inode_blocks<'a>(i: Inode) -> DiskAction<'a, FsResult<(Inode, Vec<Block>)>> {
+        let d = match (i.start_block, i.layout) {
+            (None, _) => return unit(Err(FsError::InvalidInode(i.number))),
+            (Some(start), Layout::Chain) => Inode::get_chain(start, u64::MAX),
+            (Some(_), _) => Inode::get_data_blocks(i, 0, usize::MAX),
+        };
+        map_result(d, Box::new(move |b| (i, b)))
+    }
+
+    // Read len bytes of data starting at offset, less if the data ends first
+    // Extents and pointers go straight to the blocks needed, where every block
+    // but the last is full. A chain is followed up to the last block needed,
+    // blocks don't all hold the same amount in a chain so it can't jump ahead
+    pub fn read_range<'a>(i: Inode, offset: u64, len: usize) -> DiskAction<'a, FsResult<Vec<u8>>> {
+        // Compressed data has to be read whole before any of it means anything
+        if !i.compression.is_none() {
+            let d = Inode::get_inode_blocks(i);
+            return map(
+                d,
+                Box::new(move |x| {
+                    let (i, b) = x?;
+                    let data = compress::decode(&i, Block::blocks_to_data(b))?;
+                    Ok(data.into_iter().skip(offset as usize).take(len).collect())
+                }),
+            );
+        }
+        let end = offset.saturating_add(len as u64);
+        // Blocks covering the range and how far into the first one it starts
+        let d = match (i.start_block, i.layout) {
+            (None, _) => return unit(Err(FsError::InvalidInode(i.number))),
+            (Some(start), Layout::Chain) => {
+                map_result(Inode::get_chain(start, end), Box::new(move |b| (b, offset)))
+            }
+            (Some(_), _) => flat_map_result(
+                SuperBlock::get_super_block(),
+                Box::new(move |sb: SuperBlock| {
+                    let size = sb.geometry.block_data_size as u64;
+                    let first = offset / size;
+                    let last = end.div_ceil(size);
+                    let d = Inode::get_data_blocks(i, first as usize, last as usize);
+                    map_result(d, Box::new(move |b| (b, offset - first * size)))
+                }),
+            ),
+        };
+        map_result(
+            d,
+            Box::new(move |(b, skip)| {
+                Block::blocks_to_data(b)
+                    .into_iter()
+                    .skip(skip as usize)
+                    .take(len)
+                    .collect()
+            }),
+        )
+    }
+
+    // Data blocks first to last of an inode using extents or pointers
+    fn get_data_blocks<'a>(
+        i: Inode,
+        first: usize,
+        last: usize,
+    ) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        let d = Inode::get_data_numbers(i, first, last);
+        flat_map_result(
+            d,
+            Box::new(|numbers: Vec<u32>| {
+                let reads = numbers.into_iter().map(Inode::get_data_block).collect();
+                sequence_result(reads)
+            }),
+        )
+    }
+
+    // Block numbers of data blocks first to last, without reading the data
+    pub fn get_data_numbers<'a>(
+        i: Inode,
+        first: usize,
+        last: usize,
+    ) -> DiskAction<'a, FsResult<Vec<u32>>> {
+        let d = match i.layout {
+            Layout::Extents => map_result(
+                Inode::get_extents(i),
+                Box::new(|(_, extents)| Extent::to_numbers(&extents)),
+            ),
+            Layout::Pointers => Inode::get_pointed_numbers(i, first, last),
+            Layout::Chain => map_result(
+                Inode::get_inode_blocks(i),
+                Box::new(|(_, b)| b.iter().map(|x| x.number).collect()),
+            ),
+        };
+        map_result(
+            d,
+            Box::new(move |numbers: Vec<u32>| {
+                let len = numbers.len();
+                numbers[first.min(len)..last.min(len)].to_vec()
+            }),
+        )
+    }
+
+    // Only the pointer blocks covering first to last are read, the rest
+    // are stood in for with zeros since every one before the last is full
+    fn get_pointed_numbers<'a>(
+        i: Inode,
+        first: usize,
+        last: usize,
+    ) -> DiskAction<'a, FsResult<Vec<u32>>> {
+        let p = i.pointers;
+        let covers = move |start: usize| first < start + POINTERS_PER_BLOCK && last > start;
+        let read = move |n: u32, start: usize| match covers(start) {
+            true => Inode::get_pointer_block(n),
+            false => unit(Ok(vec![0; POINTERS_PER_BLOCK])),
+        };
+        let direct: Vec<u32> = p.direct.iter().copied().filter(|n| *n != 0).collect();
+        let indirect = match p.indirect {
+            0 => unit(Ok(vec![])),
+            n => read(n, DIRECT_POINTERS),
+        };
+        let double = match p.double_indirect {
+            0 => unit(Ok(vec![])),
+            n => flat_map_result(
+                Inode::get_pointer_block(n),
+                Box::new(move |seconds: Vec<u32>| {
+                    let reads = seconds
+                        .iter()
+                        .enumerate()
+                        .map(|(j, n)| read(*n, DIRECT_POINTERS + (j + 1) * POINTERS_PER_BLOCK))
+                        .collect();
+                    map_result(sequence_result(reads), Box::new(|x| x.concat()))
+                }),
+            ),
+        };
+        map2_result(
+            indirect,
+            double,
+            Box::new(move |a, b| [direct.clone(), a, b].concat()),
+        )
+    }
+
+    fn get_pointer_block<'a>(n: u32) -> DiskAction<'a, FsResult<Vec<u32>>> {
+        let d = Block::get_block(n);
+        map(
+            d,
+            Box::new(move |x| {
+                x.and_then(|b| Pointers::from_bytes(&b.data).ok_or(FsError::Corrupt { block: n }))
+            }),
+        )
+    }
+
+    // Blocks kept by an inode besides its data, extent blocks or pointer blocks
+    // Pointer blocks come indirect first, then double indirect and its indirect blocks
+    pub fn get_metadata_blocks<'a>(i: Inode) -> DiskAction<'a, FsResult<Vec<Block>>> {
+        match i.layout {
+            _ if i.start_block.is_none() => unit(Ok(vec![])),
+            Layout::Chain => unit(Ok(vec![])),
+            Layout::Extents => map_result(Inode::get_extents(i), Box::new(|(b, _)| b)),
+            Layout::Pointers => {
+                let p = i.pointers;
+                let indirect = match p.indirect {
+                    0 => unit(Ok(vec![])),
+                    n => map_result(Block::get_block(n), Box::new(|b| vec![b])),
+                };
+                let double = match p.double_indirect {
+                    0 => unit(Ok(vec![])),
+                    n => flat_map_result(
+                        Block::get_block(n),
+                        Box::new(move |b: Block| {
+                            let seconds = Pointers::from_bytes(&b.data).unwrap_or_default();
+                            let reads = seconds.into_iter().map(Block::get_block).collect();
+                            map_result(
+                                sequence_result(reads),
+                                Box::new(move |x| [vec![b.clone()], x].concat()),
+                            )
+                        }),
+                    ),
+                };
+                map2_result(indirect, double, Box::new(|a, b| [a, b].concat()))
+            }
+        }
+    }
+
+    pub fn get_extents<'a>(i: Inode) -> DiskAction<'a, FsResult<(Vec<Block>, Vec<Extent>)>> {
+        let start = match i.start_block {
+            Some(start) => start,
+            None => return unit(Err(FsError::InvalidInode(i.number))),
+        };
+        let d = Inode::get_chain(start, u64::MAX);
+        map(
+            d,
+            Box::new(|x| {
+                x.and_then(|blocks| {
+                    let mut extents = vec![];
+                    for b in blocks.iter() {
+                        let mut e = Extent::from_bytes(&b.data)
+                            .ok_or(FsError::Corrupt { block: b.number })?;
+                        extents.append(&mut e);
+                    }
+                    Ok((blocks, extents))
+                })
+            }),
+        )
+    }
+
+    // A data block pointed at by an extent or pointer shouldn't be free
+    fn get_data_block<'a>(n: u32) -> DiskAction<'a, FsResult<Block>> {
+        let d = Block::get_block(n);
+        map(
+            d,
+            Box::new(move |x| {
+                x.and_then(|b| match b.b_type {
+                    BlockType::Free => Err(FsError::Corrupt { block: n }),
+                    _ => Ok(b),
+                })
+            }),
+        )
+    }
+
+    // Free an inode's data blocks along with any extent or pointer blocks
+    pub fn free_all_blocks<'a>(i: Inode, data: Vec<Block>) -> DiskAction<'a, FsResult<()>> {
+        let d = Inode::get_metadata_blocks(i);
+        let d = flat_map_result(
+            d,
+            Box::new(move |b| Block::free_blocks([data.clone(), b].concat())),
+        );
+        map_result(d, Box::new(|_| ()))
     }
 
-    // Given a Inode, return all link list of blocks
-    pub fn get_inode_blocks<'a>(i: Inode) -> DiskAction<'a, Option<(Inode, Vec<Block>)>> {
+    // Follow a chain from start until End, or until the blocks read hold bytes of data
+    // A chain that runs into a free block is corrupt
+    pub fn get_chain<'a>(start: u32, bytes: u64) -> DiskAction<'a, FsResult<Vec<Block>>> {
         // This could be improved with unfold, :/
         // Or maybe map2?
-        Box::new(move |mut disk| {
-            let r = i.start_block;
-            let r = r.map(|start| Block::get_block(start));
-            let blocks = r.map(|read_block| {
-                let mut blocks = Vec::<Block>::new();
-                let (data, mut disk2) = read_block(disk);
-                data.map(|mut x| {
-                    blocks.push(x.clone());
-                    while x.clone().b_type != BlockType::End {
-                        if let BlockType::Next(num) = x.clone().b_type {
-                            let (data, disk3) = Block::get_block(num)(disk2);
-                            disk2 = disk3;
-                            x = data.unwrap();
-                            blocks.push(x.clone());
-                        }
-                    }
-                });
+        Box::new(move |disk| {
+            let mut blocks = Vec::<Block>::new();
+            let (data, mut disk) = Block::get_block(start)(disk);
+            let mut x = match data {
+                Ok(x) => x,
+                Err(e) => return (Err(e), disk),
+            };
+            let mut read = x.data.len() as u64;
+            blocks.push(x.clone());
+            while x.b_type != BlockType::End && read < bytes {
+                let num = match x.b_type {
+                    BlockType::Next(num) => num,
+                    _ => return (Err(FsError::Corrupt { block: x.number }), disk),
+                };
+                let (data, disk2) = Block::get_block(num)(disk);
                 disk = disk2;
-                blocks
-            });
-            (blocks.map(|b| (i, b)), disk)
+                x = match data {
+                    Ok(x) => x,
+                    Err(e) => return (Err(e), disk),
+                };
+                read += x.data.len() as u64;
+                blocks.push(x.clone());
+            }
+            (Ok(blocks), disk)
         })
     }
 
-    pub fn set_inode_blocks(a: Option<Inode>, mut b: Vec<Block>) -> Option<(Inode, Vec<Block>)> {
-        a.and_then(|mut i| {
-            b.split_first_mut().map(move |(first, rest)| {
-                i.start_block = Some(first.number);
-
-                let new_blocks: Vec<Block> = vec![first.clone()]
-                    .into_iter()
-                    .chain(rest.to_owned())
-                    .collect();
-
-                let new_blocks = new_blocks
-                    .clone()
-                    .into_iter()
-                    .enumerate()
-                    .map(|(i, mut v)| {
-                        let next = new_blocks.get(i + 1);
-                        if let Some(next_block) = next {
-                            v.b_type = BlockType::Next(next_block.number);
-                        } else {
-                            v.b_type = BlockType::End
-                        }
-                        v
-                    })
-                    .collect();
+    pub fn set_inode_blocks(mut i: Inode, b: Vec<Block>) -> FsResult<(Inode, Vec<Block>)> {
+        let first = b.first().ok_or(FsError::NoSpace)?;
+        i.start_block = Some(first.number);
 
-                (i, new_blocks)
+        let new_blocks = b
+            .iter()
+            .enumerate()
+            .map(|(index, v)| {
+                let mut v = v.clone();
+                if let Some(next_block) = b.get(index + 1) {
+                    v.b_type = BlockType::Next(next_block.number);
+                } else {
+                    v.b_type = BlockType::End
+                }
+                v
             })
-        })
+            .collect();
+
+        Ok((i, new_blocks))
     }
 
-    // inode_table should never be more then INODE_TABLE_SIZE & available Inode Blocks
-    pub fn replace_all_inodes<'a>(inode_table: Vec<Inode>) -> DiskAction<'a, Option<Vec<Inode>>> {
+    // inode_table should never be more then the superblock's inodes per block & available Inode Blocks
+    pub fn replace_all_inodes<'a>(inode_table: Vec<Inode>) -> DiskAction<'a, FsResult<Vec<Inode>>> {
         // Read Inode_Table Blocks
         // Set Data of Blocks to table
         // save all blocks
-        let r = inode_table
-            .chunks(INODE_TABLE_SIZE as usize)
-            .map(|x| x.iter().map(|i| *i).collect::<Vec<Inode>>())
-            .map(|x| serde_json::to_string(&x).ok().unwrap_or("".into()))
-            .collect::<Vec<String>>();
-
         let d = SuperBlock::get_super_block();
-        let d = map(
-            d,
-            utils::lift(Box::new(|x| x.get_inode_table_block_range())),
-        );
-        // Read blocks
-        let d = flat_map(
+        // Read blocks, then set their data to the table
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(|x| {
-                let reads = x.into_iter().map(|a| Block::get_block(a)).collect();
-                sequence(reads)
-            })),
-        );
-        let d = map(d, utils::lift(Box::new(utils::remove_options)));
-        // We have blocks, now set the data of the blocks
-        let d = map(
-            d,
-            utils::lift(Box::new(move |x| {
-                Block::set_data_blocks_data((x, r.clone()))
-            })),
+            Box::new(move |x| {
+                let r = inode_table
+                    .chunks(x.geometry.inodes_per_block as usize)
+                    .map(|x| serde_json::to_vec(&x).unwrap_or_default())
+                    .collect::<Vec<Vec<u8>>>();
+                let reads = x
+                    .get_inode_table_block_range()
+                    .map(Block::get_block)
+                    .collect();
+                map_result(
+                    sequence_result(reads),
+                    Box::new(move |x| Block::set_data_blocks_data((x, r.clone()))),
+                )
+            }),
         );
         // Blocks have been set lets write them
-        let d = flat_map(
+        let d = flat_map_result(
             d,
-            utils::lift_disk_action(Box::new(|x| {
+            Box::new(|x| {
                 let writes = x
                     .into_iter()
                     .map(|mut b| {
                         b.b_type = BlockType::End;
                         b
                     })
-                    .map(|b| Block::write_block(b))
+                    .map(Block::write_block)
                     .collect();
-                sequence(writes)
-            })),
+                sequence_result(writes)
+            }),
         );
-        let d = map(d, utils::lift(Box::new(utils::remove_options)));
-        map(
-            d,
-            utils::lift(Box::new(|blocks| -> Vec<Inode> {
-                let i = blocks
-                    .into_iter()
-                    .map(|b| serde_json::from_str::<Vec<Inode>>(&b.data).ok())
-                    .collect();
-                let i = utils::remove_options(i);
-                i.into_iter().flat_map(|b| b).collect::<Vec<Inode>>()
-            })),
-        )
+        map(d, Box::new(|x| x.and_then(Inode::blocks_to_inodes)))
     }
 
     // Read Inode table
     // Replace associated inode with inode
     // Write Inode table back out to blocks
-    pub fn write_inode<'a>(i: Inode) -> DiskAction<'a, Option<Inode>> {
+    pub fn write_inode<'a>(i: Inode) -> DiskAction<'a, FsResult<Inode>> {
         let d = Inode::get_inode_table();
-        let d = map(
+        let d = map_result(
             d,
-            utils::lift(Box::new(move |inodes| {
+            Box::new(move |inodes| {
                 inodes
                     .into_iter()
                     .map(|x| {
@@ -223,30 +645,47 @@ impl Inode {
                         x
                     })
                     .collect()
-            })),
-        );
-        let d = flat_map(
-            d,
-            utils::lift_disk_action(Box::new(Inode::replace_all_inodes)),
+            }),
         );
-        let d = map(d, Box::new(|x| x.flatten()));
+        let d = flat_map_result(d, Box::new(Inode::replace_all_inodes));
         let d = map(
             d,
-            utils::lift(Box::new(move |inodes| {
-                inodes.into_iter().find(|x| x.number == i.number)
-            })),
+            Box::new(move |x| {
+                x.and_then(|inodes| {
+                    inodes
+                        .into_iter()
+                        .find(|x| x.number == i.number)
+                        .ok_or(FsError::InvalidInode(i.number))
+                })
+            }),
         );
-        map(d, Box::new(|x| x.flatten()))
+        let mark = Bitmap::mark(BitmapKind::Inodes, vec![i.number], i.start_block.is_some());
+        map2_result(d, mark, Box::new(|a, _| a))
     }
 
-    pub fn free_inode<'a>(mut i: Inode) -> DiskAction<'a, Option<Inode>> {
-        i.start_block = None;
-        Inode::write_inode(i)
+    pub fn free_inode<'a>(i: Inode) -> DiskAction<'a, FsResult<Inode>> {
+        Inode::write_inode(Inode {
+            number: i.number,
+            ..Inode::default()
+        })
+    }
+
+    // Like relatime, accessed is only written when it has fallen behind modified
+    // so reading a file over and over doesn't keep writing the inode table
+    pub fn touch_accessed<'a>(i: Inode) -> DiskAction<'a, FsResult<Inode>> {
+        if i.accessed >= i.modified {
+            return unit(Ok(i));
+        }
+        Inode::write_inode(Inode {
+            accessed: utils::now(),
+            ..i
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::mem_disk::MemDisk;
     use super::*;
 
     #[test]
@@ -254,11 +693,13 @@ mod tests {
         let expected_data = Inode {
             number: 1,
             start_block: Some(5),
+            ..Inode::default()
         };
 
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = Inode::get_inode(1)(disk);
-        assert_eq!(data, Some(expected_data));
+        assert_eq!(data, Ok(expected_data));
     }
 
     #[test]
@@ -266,11 +707,25 @@ mod tests {
         let expected_data = Inode {
             number: 2,
             start_block: None,
+            // Layout comes from the superblock, older ones give extents
+            layout: Layout::Extents,
+            ..Inode::default()
         };
 
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = Inode::get_free_inode()(disk);
-        assert_eq!(data, Some(expected_data));
+        assert_eq!(data, Ok(expected_data));
+    }
+
+    #[test]
+    fn get_free_inode_should_read_one_table_block() {
+        let device = MemDisk::formatted(200).unwrap();
+        let disk = Disk::new(&device);
+        let (data, disk) = Inode::get_free_inode()(disk);
+        assert_eq!(data.map(|i| i.number), Ok(2));
+        // Superblock, inode bitmap and the table block with inode 2
+        assert_eq!(disk.reads, 3);
     }
 
     #[test]
@@ -297,21 +752,51 @@ mod tests {
                 data: "Me".into(),
             },
         ];
-        let disk = Disk::new("./test-files/sda1");
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
         let inode = Inode {
             number: 3,
             start_block: Some(4),
+            ..Inode::default()
         };
         let (data, disk) = Inode::get_inode_blocks(inode)(disk);
-        assert_eq!(data, Some((inode, expected_data)));
+        assert_eq!(data, Ok((inode, expected_data)));
         assert_eq!(disk.reads, 4);
     }
 
+    #[test]
+    fn read_range_should_stop_early() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let inode = Inode {
+            number: 3,
+            start_block: Some(4),
+            ..Inode::default()
+        };
+        let (data, disk) = Inode::read_range(inode, 6, 3)(disk);
+        assert_eq!(data, Ok(b"dyO".to_vec()));
+        assert_eq!(disk.reads, 2);
+    }
+
+    #[test]
+    fn get_inode_blocks_free_inode_should_return_invalid_inode() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let inode = Inode {
+            number: 2,
+            start_block: None,
+            ..Inode::default()
+        };
+        let (data, _) = Inode::get_inode_blocks(inode)(disk);
+        assert_eq!(data, Err(FsError::InvalidInode(2)));
+    }
+
     #[test]
     fn set_inodes_blocks_should_return_expected() {
         let i = Inode {
             number: 1,
             start_block: None,
+            ..Inode::default()
         };
         let blocks = vec![
             Block {
@@ -328,6 +813,7 @@ mod tests {
         let expected_inode = Inode {
             number: 1,
             start_block: Some(4),
+            ..Inode::default()
         };
         let expected_blocks = vec![
             Block {
@@ -341,51 +827,83 @@ mod tests {
                 data: "Once".into(),
             },
         ];
-        let (i, b) = Inode::set_inode_blocks(Some(i), blocks).unwrap();
+        let (i, b) = Inode::set_inode_blocks(i, blocks).unwrap();
         assert_eq!(i, expected_inode);
         assert_eq!(b, expected_blocks);
     }
 
+    #[test]
+    fn inodes_without_metadata_should_parse() {
+        let inodes = Inode::parse_inodes(br#"[{"number":3,"start_block":4}]"#).unwrap();
+        assert_eq!(inodes[0].size, 0);
+        assert_eq!(inodes[0].mode, 0);
+        assert_eq!(inodes[0].kind, InodeKind::File);
+    }
+
+    #[test]
+    fn touch_accessed_should_only_write_when_behind() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let disk = Disk::new(&device);
+        let (i, disk) = Inode::get_inode(3)(disk);
+        let i = i.unwrap();
+        let (_, disk) = Inode::touch_accessed(i)(disk);
+        assert_eq!(disk.writes, 0);
+
+        let i = Inode { modified: 42, ..i };
+        let (i, disk) = Inode::touch_accessed(i)(disk);
+        assert!(i.unwrap().accessed > 42);
+        assert!(disk.writes > 0);
+    }
+
     #[test]
     fn replace_all_inodes_should_return_expected() {
         let inodes = vec![
             Inode {
                 number: 1,
                 start_block: Some(5),
+                ..Inode::default()
             },
             Inode {
                 number: 2,
                 start_block: None,
+                ..Inode::default()
             },
             Inode {
                 number: 3,
                 start_block: Some(4),
+                ..Inode::default()
             },
             Inode {
                 number: 4,
                 start_block: None,
+                ..Inode::default()
             },
             Inode {
                 number: 5,
                 start_block: None,
+                ..Inode::default()
             },
             Inode {
                 number: 6,
                 start_block: None,
+                ..Inode::default()
             },
             Inode {
                 number: 7,
                 start_block: None,
+                ..Inode::default()
             },
             Inode {
                 number: 8,
                 start_block: None,
+                ..Inode::default()
             },
         ];
-        let disk = Disk::new("./test-files/inode_replace_all_test");
+        let device = MemDisk::from_image("./test-files/inode_replace_all_test").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = Inode::replace_all_inodes(inodes.clone())(disk);
 
-        assert_eq!(data, Some(inodes));
+        assert_eq!(data, Ok(inodes));
     }
 
     #[test]
@@ -393,10 +911,12 @@ mod tests {
         let inode = Inode {
             number: 3,
             start_block: Some(42),
+            ..Inode::default()
         };
-        let disk = Disk::new("./test-files/inode_write_test");
+        let device = MemDisk::from_image("./test-files/inode_write_test").unwrap();
+        let disk = Disk::new(&device);
         let (data, _) = Inode::write_inode(inode)(disk);
 
-        assert_eq!(data, Some(inode));
+        assert_eq!(data, Ok(inode));
     }
 }
@@ -0,0 +1,416 @@
+use super::block::*;
+use super::disk::*;
+use super::error::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// Write ahead journal
+// The first journal block is the header, after it come copies of the blocks being written
+// Committing a transaction goes
+//   1. Write blocks that were free, nothing points at them until the commit
+//   2. Copy every other block it changes into the journal
+//   3. Mark the header committed, from here on the transaction survives a crash
+//   4. Write the journaled blocks to where they really belong
+//   5. Write blocks it frees, nothing points at them any more
+//   6. Clear the header
+// Mount replays a header that was committed but never cleared. A crash around steps 1
+// or 5 can only leave blocks that nothing owns, which fsck finds and frees.
+// Transactions whose other blocks don't fit in the journal fail with TransactionTooLarge,
+// file writes avoid that by copying data to free blocks when they'd rewrite too many
+
+// Keep the header well under a block so it fits any device
+static HEADER_SIZE: usize = 3072;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct JournalHeader {
+    sequence: u32,
+    committed: bool,
+    #[serde(default)]
+    blocks: Vec<u32>, // Where each copy after the header belongs
+}
+
+// The header is an ordinary block holding JSON
+fn read_journal_block(device: &dyn BlockDevice, number: u32) -> FsResult<Vec<u8>> {
+    let checked = checksums_required(device);
+    Block::parse(number, &device.read_block(number)?, checked).map(|b| b.data)
+}
+
+fn write_journal_block(device: &dyn BlockDevice, number: u32, data: Vec<u8>) -> FsResult<()> {
+    let block = Block {
+        number,
+        b_type: BlockType::End,
+        data,
+    };
+    device.write_block(number, &block.to_bytes())
+}
+
+// A journal that has never been used is an empty block
+fn read_header(device: &dyn BlockDevice, number: u32) -> FsResult<JournalHeader> {
+    let data = read_journal_block(device, number)?;
+    if data.is_empty() {
+        return Ok(JournalHeader::default());
+    }
+    serde_json::from_slice(&data).map_err(|_| FsError::Corrupt { block: number })
+}
+
+fn write_header(device: &dyn BlockDevice, number: u32, header: &JournalHeader) -> FsResult<()> {
+    write_journal_block(
+        device,
+        number,
+        serde_json::to_vec(header).unwrap_or_default(),
+    )
+}
+
+fn read_super_block(device: &dyn BlockDevice) -> FsResult<SuperBlock> {
+    SuperBlock::from_bytes(&device.read_block(1)?).ok_or(FsError::InvalidSuperBlock)
+}
+
+// Buffers every write made through it until commit
+// Reads see the buffered writes, so an operation sees its own changes
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    device: &'a dyn BlockDevice,
+    writes: RefCell<BTreeMap<u32, Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(device: &'a dyn BlockDevice) -> Transaction<'a> {
+        Transaction {
+            device,
+            writes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    // Where the journal is and the header naming blocks, None when the disk has no journal
+    fn plan_journal(&self, blocks: Vec<u32>) -> FsResult<Option<(Range<u32>, JournalHeader)>> {
+        let sb = read_super_block(self.device)?;
+        let range = sb.get_journal_block_range();
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let too_large = FsError::TransactionTooLarge(blocks.len() as u32);
+        if blocks.len() > range.len() - 1 {
+            return Err(too_large);
+        }
+        let previous = read_header(self.device, range.start)?;
+        let header = JournalHeader {
+            sequence: previous.sequence + 1,
+            committed: true,
+            blocks,
+        };
+        if serde_json::to_string(&header).unwrap_or_default().len() > HEADER_SIZE {
+            return Err(too_large);
+        }
+        Ok(Some((range, header)))
+    }
+
+    // Steps 2 and 3, gives back how many device writes it took
+    fn write_journal(
+        &self,
+        range: Range<u32>,
+        header: &JournalHeader,
+        writes: &[(u32, Vec<u8>)],
+    ) -> FsResult<u32> {
+        for (i, (_, data)) in writes.iter().enumerate() {
+            self.device.write_block(range.start + 1 + i as u32, data)?;
+        }
+        self.device.flush()?;
+        write_header(self.device, range.start, header)?;
+        self.device.flush()?;
+        Ok(writes.len() as u32 + 1)
+    }
+
+    // Whether a block is free on the device and whether it is free once written
+    fn freedom(&self, number: u32, data: &[u8]) -> (bool, bool) {
+        let is_free = |bytes: &[u8]| {
+            Block::from_unchecked_bytes(number, bytes).is_ok_and(|b| b.b_type == BlockType::Free)
+        };
+        let was = self.device.read_block(number).is_ok_and(|b| is_free(&b));
+        (was, is_free(data))
+    }
+
+    // Gives back how many device writes it took
+    pub fn commit(&self) -> FsResult<u32> {
+        let writes = self.writes.borrow();
+        if writes.is_empty() {
+            return Ok(0);
+        }
+        let mut before = vec![];
+        let mut journaled = vec![];
+        let mut after = vec![];
+        for (number, data) in writes.iter() {
+            let write = (*number, data.clone());
+            match self.freedom(*number, data) {
+                (true, _) => before.push(write),
+                (false, true) => after.push(write),
+                (false, false) => journaled.push(write),
+            }
+        }
+        // Disks without a journal have every block written straight out
+        let journal = match journaled.is_empty() {
+            true => None,
+            false => self.plan_journal(journaled.iter().map(|(n, _)| *n).collect())?,
+        };
+
+        let mut count = 0;
+        for (block, data) in before.iter() {
+            self.device.write_block(*block, data)?;
+            count += 1;
+        }
+        self.device.flush()?;
+        if let Some((range, header)) = &journal {
+            count += self.write_journal(range.clone(), header, &journaled)?;
+        }
+        for (block, data) in journaled.iter().chain(after.iter()) {
+            self.device.write_block(*block, data)?;
+            count += 1;
+        }
+        self.device.flush()?;
+
+        if journal.is_some() {
+            clear(self.device)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl<'a> BlockDevice for Transaction<'a> {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        match self.writes.borrow().get(&number) {
+            Some(data) => Ok(data.clone()),
+            None => self.device.read_block(number),
+        }
+    }
+
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+        if number == 0 {
+            return Err(FsError::OutOfRange(number));
+        }
+        // Rewriting a block with what it already holds isn't worth journaling
+        let unchanged = self.device.read_block(number).map(|current| {
+            current.len() >= data.len()
+                && current[..data.len()] == *data
+                && current[data.len()..].iter().all(|x| *x == 0)
+        });
+        if unchanged == Ok(true) {
+            self.writes.borrow_mut().remove(&number);
+        } else {
+            self.writes.borrow_mut().insert(number, data.to_vec());
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        let last = self.writes.borrow().keys().last().copied().unwrap_or(0);
+        Ok(std::cmp::max(self.device.len()?, last))
+    }
+
+    // Nothing reaches the device until commit
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+    // Dropping blocks can't be journaled, so it goes straight to the device
+    fn truncate(&self, len: u32) -> FsResult<()> {
+        self.writes.borrow_mut().retain(|n, _| *n <= len);
+        self.device.truncate(len)
+    }
+}
+
+fn clear(device: &dyn BlockDevice) -> FsResult<()> {
+    let sb = read_super_block(device)?;
+    let start = sb.get_journal_block_range().start;
+    let header = JournalHeader {
+        committed: false,
+        ..read_header(device, start)?
+    };
+    write_header(device, start, &header)?;
+    device.flush()
+}
+
+// Run an action as one transaction, either all of its writes happen or none do
+// Transactions shouldn't be nested
+pub fn transaction<'a, A: 'a>(action: DiskAction<'a, FsResult<A>>) -> DiskAction<'a, FsResult<A>> {
+    Box::new(move |disk| {
+        let tx = Transaction::new(disk.device);
+        let tx_disk = Disk {
+            device: &tx,
+            ..disk
+        };
+        let (res, tx_disk) = action(tx_disk);
+        let disk = Disk {
+            device: disk.device,
+            ..tx_disk
+        };
+        match res.and_then(|a| tx.commit().map(|writes| (a, writes))) {
+            Ok((a, writes)) => (
+                Ok(a),
+                Disk {
+                    writes: disk.writes + writes,
+                    ..disk
+                },
+            ),
+            Err(e) => (Err(e), disk),
+        }
+    })
+}
+
+// Apply a committed transaction that never finished, gives back how many blocks were replayed
+pub fn replay(device: &dyn BlockDevice) -> FsResult<u32> {
+    let sb = read_super_block(device)?;
+    let range = sb.get_journal_block_range();
+    if range.len() < 2 {
+        return Ok(0);
+    }
+    let header = read_header(device, range.start)?;
+    if !header.committed {
+        return Ok(0);
+    }
+
+    for (i, block) in header.blocks.iter().enumerate() {
+        let data = device.read_block(range.start + 1 + i as u32)?;
+        device.write_block(*block, &data)?;
+    }
+    device.flush()?;
+    clear(device)?;
+    Ok(header.blocks.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
+    use super::*;
+
+    // Pulls the plug on the first write outside of allowed
+    // Free storage blocks can always be written, they go out before the journal
+    #[derive(Debug)]
+    struct CrashDisk<'a> {
+        device: &'a MemDisk,
+        allowed: std::ops::Range<u32>,
+    }
+
+    impl<'a> BlockDevice for CrashDisk<'a> {
+        fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+            self.device.read_block(number)
+        }
+
+        fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+            let storage = read_super_block(self.device)?.get_storage_block_range();
+            let free = Block::from_bytes(number, &self.device.read_block(number)?)
+                .is_ok_and(|b| b.b_type == BlockType::Free);
+            let writable = self.allowed.contains(&number) || (storage.contains(&number) && free);
+            if !writable {
+                return Err(FsError::Io("crashed".into()));
+            }
+            self.device.write_block(number, data)
+        }
+
+        fn len(&self) -> FsResult<u32> {
+            self.device.len()
+        }
+
+        fn flush(&self) -> FsResult<()> {
+            Ok(())
+        }
+
+        fn truncate(&self, len: u32) -> FsResult<()> {
+            self.device.truncate(len)
+        }
+    }
+
+    #[test]
+    fn commit_should_apply_and_clear_journal() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let save = FileSystem::save_as_file("shrek.txt".into(), "Ogres are like onions".into());
+        let (result, disk) = save(disk);
+        assert!(result.is_ok());
+
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok("Ogres are like onions".into()));
+        let header = read_header(&device, 2).unwrap();
+        assert_eq!(header.sequence, 1);
+        assert!(!header.committed);
+    }
+
+    #[test]
+    fn failed_transaction_should_write_nothing() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let action = FileSystem::save_as_file("/nope/shrek.txt".into(), "Donkey".into());
+        let (result, disk) = action(disk);
+        assert_eq!(result, Err(FsError::NotFound("/nope".into())));
+        assert_eq!(disk.writes, 0);
+    }
+
+    #[test]
+    fn mount_should_replay_committed_transaction() {
+        let device = MemDisk::formatted(50).unwrap();
+        // Crash once the journal is written, before any real block
+        let crash = CrashDisk {
+            device: &device,
+            allowed: 2..14,
+        };
+        let save = FileSystem::save_as_file("shrek.txt".into(), "Ogres are like onions".into());
+        let (result, _) = save(Disk::new(&crash));
+        assert_eq!(result, Err(FsError::Io("crashed".into())));
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(Disk::new(&device));
+        assert_eq!(data, Err(FsError::NotFound("shrek.txt".into())));
+
+        let disk = FileSystem::mount(&device).unwrap();
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok("Ogres are like onions".into()));
+        assert_eq!(replay(&device), Ok(0));
+    }
+
+    #[test]
+    fn uncommitted_journal_should_not_replay() {
+        let device = MemDisk::formatted(50).unwrap();
+        // Crash before the header is marked committed
+        let crash = CrashDisk {
+            device: &device,
+            allowed: 3..14,
+        };
+        let save = FileSystem::save_as_file("shrek.txt".into(), "Ogres are like onions".into());
+        let (result, _) = save(Disk::new(&crash));
+        assert!(result.is_err());
+
+        let disk = FileSystem::mount(&device).unwrap();
+        let (data, _) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Err(FsError::NotFound("shrek.txt".into())));
+    }
+
+    #[test]
+    fn only_blocks_in_use_should_be_journaled() {
+        let device = MemDisk::formatted(200).unwrap();
+        let journal = read_super_block(&device).unwrap().get_journal_block_range();
+        let mut disk = Disk::new(&device);
+        // Far more data blocks than the journal holds, all of them going into free blocks
+        let data = vec![b's'; 50 * 2 * journal.len()];
+        let save = FileSystem::save_as_file("shrek.txt".into(), data.clone());
+        run(&mut disk, save).unwrap();
+        let header = read_header(&device, journal.start).unwrap();
+        assert!(header.blocks.len() < 6);
+        assert_eq!(
+            run(&mut disk, FileSystem::read_file("shrek.txt".into())),
+            Ok(data.clone())
+        );
+
+        // Rewriting it changes more blocks than the journal holds, so they're copied
+        // to free blocks instead of being written over
+        let rewrite = FileSystem::write_at("shrek.txt".into(), 0, vec![b'd'; data.len()]);
+        assert_eq!(run(&mut disk, rewrite), Ok(data.len() as u64));
+        let header = read_header(&device, journal.start).unwrap();
+        assert!(header.blocks.len() < 6);
+        assert_eq!(
+            run(&mut disk, FileSystem::read_file("shrek.txt".into())),
+            Ok(vec![b'd'; data.len()])
+        );
+        let report = run(&mut disk, FileSystem::fsck(false)).unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -1,101 +1,285 @@
+use super::block::*;
 use super::disk::*;
+use super::error::*;
+use super::utils;
+use serde::{Deserialize, Serialize};
 use std::fs;
 
-pub fn read<'a>(line: u32) -> DiskAction<'a, Option<String>> {
-    let index_line = line - 1;
-    Box::new(move |disk: Disk| {
-        let d = disk.read();
-        let r = fs::read_to_string(disk.file)
+// Line oriented text file, each line of the file is a block
+// Blocks are kept as JSON so the disk can be read by a human
+// Lines that aren't blocks are passed through untouched
+#[derive(Debug, Clone)]
+pub struct LineFile {
+    path: String,
+}
+
+impl LineFile {
+    pub fn new(path: &str) -> LineFile {
+        LineFile { path: path.into() }
+    }
+}
+
+// A block as it's kept on a line, lines from before checksums have none
+#[derive(Serialize, Deserialize)]
+struct Line {
+    #[serde(flatten)]
+    block: Block,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<u32>,
+}
+
+// JSON line -> binary block
+// The checksum comes from the line, so a line edited by hand fails it
+fn line_to_bytes(number: u32, line: String) -> Vec<u8> {
+    let parsed = serde_json::from_str::<Line>(&line).ok();
+    let bytes = match parsed {
+        Some(l) if number == 1 => serde_json::from_slice::<SuperBlock>(&l.block.data)
             .ok()
-            .and_then(move |l: String| l.lines().map(|x| x.to_owned()).nth(index_line as usize));
-        (r, d)
-    })
+            .map(|sb| sb.to_bytes()),
+        Some(l) => {
+            let mut bytes = l.block.to_bytes();
+            bytes.truncate(bytes.len() - BLOCK_CHECKSUM_SIZE);
+            if let Some(checksum) = l.checksum {
+                utils::put_u32(&mut bytes, checksum);
+            }
+            Some(bytes)
+        }
+        None => None,
+    };
+    bytes.unwrap_or_else(|| line.into_bytes())
+}
+
+// Binary block -> JSON line
+// Journal copies are blocks from elsewhere, so they're read with their own number
+fn bytes_to_line(number: u32, data: &[u8]) -> FsResult<String> {
+    let line = if number == 1 {
+        SuperBlock::from_bytes(data)
+            .filter(|sb| sb.valid_super_block())
+            .map(|sb| Line {
+                block: Block {
+                    number,
+                    b_type: BlockType::End,
+                    data: serde_json::to_vec(&sb).unwrap_or_default(),
+                },
+                checksum: None,
+            })
+    } else {
+        utils::get_u32(data, 0)
+            .and_then(|n| Block::from_unchecked_bytes(n, data).ok())
+            .map(|block| {
+                let bytes = block.to_bytes();
+                Line {
+                    checksum: utils::get_u32(&bytes, bytes.len() - BLOCK_CHECKSUM_SIZE),
+                    block,
+                }
+            })
+    };
+    match line {
+        Some(l) => serde_json::to_string(&l).map_err(|_| FsError::Corrupt { block: number }),
+        None => std::str::from_utf8(data)
+            .map(|x| x.to_owned())
+            .map_err(|_| FsError::Corrupt { block: number }),
+    }
+}
+
+impl BlockDevice for LineFile {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        read(&self.path, number).map(|x| line_to_bytes(number, x))
+    }
+
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+        let line = bytes_to_line(number, data)?;
+        write(&self.path, number, line)
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        Ok(fs::read_to_string(&self.path)?.lines().count() as u32)
+    }
+
+    // Every write goes straight to the file
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, len: u32) -> FsResult<()> {
+        let s = fs::read_to_string(&self.path)?;
+        let lines: Vec<&str> = s.lines().take(len as usize).collect();
+        fs::write(&self.path, lines.join("\n"))?;
+        Ok(())
+    }
 }
+
+fn read(path: &str, line: u32) -> FsResult<String> {
+    if line == 0 {
+        return Err(FsError::OutOfRange(line));
+    }
+    let index_line = line - 1;
+    fs::read_to_string(path)
+        .map_err(FsError::from)
+        .and_then(move |l: String| {
+            l.lines()
+                .map(|x| x.to_owned())
+                .nth(index_line as usize)
+                .ok_or(FsError::OutOfRange(line))
+        })
+}
+
 // Line [1..]
-pub fn write<'a>(line: u32, data: String) -> DiskAction<'a, Option<String>> {
+fn write(path: &str, line: u32, data: String) -> FsResult<()> {
+    if line == 0 {
+        return Err(FsError::OutOfRange(line));
+    }
     let indexed_line = line - 1;
 
-    Box::new(move |disk: Disk| {
-        let data = data.clone();
+    let s = fs::read_to_string(path)?;
+    let r: Vec<String> = s.lines().map(|x| x.to_owned()).collect();
 
-        let r = fs::read_to_string(disk.file)
-            .ok()
-            .map(Box::new(move |s: String| {
-                let r: Vec<String> = s.lines().map(|x| x.to_owned()).collect();
-
-                let extra_lines_to_add = if line > r.len() as u32 {
-                    line - r.len() as u32
-                } else {
-                    0
-                };
-                let extra_lines: Vec<String> = (0..extra_lines_to_add)
-                    .into_iter()
-                    .map(|_| "".into())
-                    .collect();
-
-                r.into_iter()
-                    .chain(extra_lines.into_iter())
-                    .enumerate()
-                    .map(Box::new(move |(i, v)| {
-                        if i as u32 == indexed_line {
-                            return data.clone();
-                        }
-                        v
-                    }))
-                    .collect()
-            }))
-            .and_then(Box::new(|x: Vec<String>| {
-                let file_string: String = x.join("\n");
-
-                fs::write(disk.file, file_string)
-                    .ok()
-                    .and_then(move |_| x.get(indexed_line as usize).map(|x| x.to_owned()))
-            }));
-        (r, disk.write())
-    })
+    let extra_lines_to_add = if line > r.len() as u32 {
+        line - r.len() as u32
+    } else {
+        0
+    };
+    let extra_lines: Vec<String> = (0..extra_lines_to_add).map(|_| "".into()).collect();
+
+    let x: Vec<String> = r
+        .into_iter()
+        .chain(extra_lines)
+        .enumerate()
+        .map(move |(i, v)| {
+            if i as u32 == indexed_line {
+                return data.clone();
+            }
+            v
+        })
+        .collect();
+
+    let file_string: String = x.join("\n");
+    fs::write(path, file_string)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    // NOTE: these tests should really be cleaning up the changes to the file after running
-    // But Rust's tests runner doesn't have a tear down or set up. :/
+    // Tests that write get their own copy of a fixture, the tracked one never changes
+    // The copy is named after the test and the process, so tests running at once never share one
+    fn temp_path(fixture: &str) -> String {
+        let thread = std::thread::current();
+        let test = thread.name().unwrap_or("test").replace("::", "_");
+        let path: String = std::env::temp_dir()
+            .join(format!("file_system_{}_{}", std::process::id(), test))
+            .to_string_lossy()
+            .into();
+        fs::copy(fixture, &path).unwrap();
+        path
+    }
 
     #[test]
     fn read_should_return_file_line() {
-        let disk = Disk::new("./test-files/line_handler_test_file.txt");
-        let (data, updated_disk) = read(2)(disk);
-        assert_eq!(data, Some("FP4TheWin".into()));
+        let device = LineFile::new("./test-files/line_handler_test_file.txt");
+        let disk = Disk::new(&device);
+        let (data, updated_disk) = read_block(2)(disk);
+        assert_eq!(data, Ok("FP4TheWin".into()));
         assert_eq!(updated_disk.reads, 1);
     }
 
     #[test]
-    fn read_line_does_not_exist_should_return_none() {
-        let disk = Disk::new("./test-files/line_handler_test_file.txt");
-        let (data, updated_disk) = read(10)(disk);
-        assert_eq!(data, None);
+    fn read_line_does_not_exist_should_return_out_of_range() {
+        let device = LineFile::new("./test-files/line_handler_test_file.txt");
+        let disk = Disk::new(&device);
+        let (data, updated_disk) = read_block(10)(disk);
+        assert_eq!(data, Err(FsError::OutOfRange(10)));
         assert_eq!(updated_disk.reads, 1);
     }
 
     #[test]
-    fn read_file_does_not_exist_should_return_none() {
-        let disk = Disk::new("rust_ownership_makes_me_cry_sometimes.rs");
-        let (data, updated_disk) = read(10)(disk);
-        assert_eq!(data, None);
+    fn read_block_zero_should_return_out_of_range() {
+        let device = LineFile::new("./test-files/line_handler_test_file.txt");
+        assert_eq!(device.read_block(0), Err(FsError::OutOfRange(0)));
+    }
+
+    #[test]
+    fn read_block_should_convert_json_to_binary() {
+        let device = LineFile::new("./test-files/sda1");
+        let data = device.read_block(9).unwrap();
+        let expected = Block {
+            number: 9,
+            b_type: BlockType::End,
+            data: "Me".into(),
+        };
+        // sda1 is from before checksums
+        assert_eq!(Block::from_unchecked_bytes(9, &data), Ok(expected));
+        let data = device.read_block(1).unwrap();
+        assert_eq!(SuperBlock::from_bytes(&data), Some(SuperBlock::new(10)));
+    }
+
+    #[test]
+    fn binary_block_should_be_written_as_json() {
+        let block = Block {
+            number: 2,
+            b_type: BlockType::Next(3),
+            data: "Shrek".into(),
+        };
+        let bytes = block.to_bytes();
+        let checksum = Block::checksum(&bytes[..bytes.len() - BLOCK_CHECKSUM_SIZE]);
+        let line = bytes_to_line(2, &bytes).unwrap();
+        assert_eq!(
+            line,
+            format!(
+                "{{\"number\":2,\"b_type\":{{\"Next\":3}},\"data\":\"Shrek\",\"checksum\":{}}}",
+                checksum
+            )
+        );
+        assert_eq!(line_to_bytes(2, line.clone()), bytes);
+        // Editing the line by hand is caught
+        let edited = line_to_bytes(2, line.replace("Shrek", "Fiona"));
+        assert_eq!(
+            Block::from_bytes(2, &edited),
+            Err(FsError::ChecksumMismatch(2))
+        );
+    }
+
+    #[test]
+    fn binary_data_should_be_written_as_hex() {
+        let block = Block {
+            number: 2,
+            b_type: BlockType::End,
+            data: vec![0, 159, 255],
+        };
+        let line = bytes_to_line(2, &block.to_bytes()).unwrap();
+        assert!(line.starts_with(
+            "{\"number\":2,\"b_type\":\"End\",\"data\":{\"hex\":\"009fff\"},\"checksum\":"
+        ));
+        assert_eq!(line_to_bytes(2, line), block.to_bytes());
+    }
+
+    #[test]
+    fn len_should_return_line_count() {
+        let device = LineFile::new("./test-files/line_handler_test_file.txt");
+        assert_eq!(device.len(), Ok(5));
+    }
+
+    #[test]
+    fn read_file_does_not_exist_should_return_io_error() {
+        let device = LineFile::new("rust_ownership_makes_me_cry_sometimes.rs");
+        let disk = Disk::new(&device);
+        let (data, updated_disk) = read_block(10)(disk);
+        assert!(matches!(data, Err(FsError::Io(_))));
         assert_eq!(updated_disk.reads, 1);
     }
 
     #[test]
     fn multiple_reads_should_return_expected() {
         let expected_data = vec![
-            Some("Nope".into()),
-            Some("super_awesome".into()),
-            Some("Yeah".into()),
-            Some("FP4TheWin".into()),
+            Ok("Nope".into()),
+            Ok("super_awesome".into()),
+            Ok("Yeah".into()),
+            Ok("FP4TheWin".into()),
         ];
-        let disk = Disk::new("./test-files/line_handler_test_file.txt");
-        let reads = vec![read(3), read(5), read(1), read(2)];
+        let device = LineFile::new("./test-files/line_handler_test_file.txt");
+        let disk = Disk::new(&device);
+        let reads = vec![read_block(3), read_block(5), read_block(1), read_block(2)];
         let mega_read = sequence(reads);
         let (data, updated_disk) = mega_read(disk);
         assert_eq!(updated_disk.reads, 4);
@@ -104,22 +288,29 @@ mod tests {
 
     #[test]
     fn write_should_return_expected() {
-        let expected_data = Some("super_awesome".into());
-        let disk = Disk::new("./test-files/line_handler_test_file.txt");
-        let (data, updated_disk) = write(5, "super_awesome".into())(disk);
+        let expected_data = Ok("super_awesome".into());
+        let path = temp_path("./test-files/line_handler_test_file.txt");
+        let device = LineFile::new(&path);
+        let disk = Disk::new(&device);
+        let (data, updated_disk) = write_block(5, "super_awesome".into())(disk);
         assert_eq!(data, expected_data);
         assert_eq!(updated_disk.writes, 1);
         assert_eq!(updated_disk.reads, 0);
-        let (data, updated_disk) = read(5)(updated_disk);
+        let (data, updated_disk) = read_block(5)(updated_disk);
         assert_eq!(data, expected_data);
         assert_eq!(updated_disk.writes, 1);
         assert_eq!(updated_disk.reads, 1);
+        fs::remove_file(&path).unwrap();
     }
 
     #[test]
     fn write_line_exists_should_return_expected() {
-        let disk = Disk::new("./test-files/line_handler_write_test.txt");
-        let (_, updated_disk) = write(1, "Yeah".into())(disk);
+        let path = temp_path("./test-files/line_handler_write_test.txt");
+        let device = LineFile::new(&path);
+        let disk = Disk::new(&device);
+        let (_, updated_disk) = write_block(1, "Yeah".into())(disk);
         assert_eq!(updated_disk.writes, 1);
+        assert_eq!(device.read_block(1), Ok("Yeah".into()));
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,115 @@
+use super::disk::*;
+use super::error::*;
+use super::image;
+use super::FileSystem;
+use std::cell::RefCell;
+
+// Blocks kept in a Vec, nothing touches the real filesystem
+// Good for tests, every MemDisk is its own isolated disk
+#[derive(Debug, Default)]
+pub struct MemDisk {
+    blocks: RefCell<Vec<Vec<u8>>>,
+}
+
+impl MemDisk {
+    pub fn new() -> MemDisk {
+        MemDisk::default()
+    }
+
+    // Copy every block out of another device
+    pub fn from_device(device: &dyn BlockDevice) -> FsResult<MemDisk> {
+        let blocks = (1..device.len()? + 1)
+            .map(|x| device.read_block(x))
+            .collect::<FsResult<Vec<Vec<u8>>>>()?;
+        Ok(MemDisk {
+            blocks: RefCell::new(blocks),
+        })
+    }
+
+    // Load an existing image or line file, changes are never written back to it
+    pub fn from_image(path: &str) -> FsResult<MemDisk> {
+        MemDisk::from_device(image::open_device(path)?.as_ref())
+    }
+
+    // Same as FileSystem::format, but in memory
+    pub fn formatted(size: u32) -> FsResult<MemDisk> {
+        let disk = MemDisk::new();
+        FileSystem::format(&disk, size)?;
+        Ok(disk)
+    }
+
+    // Copy every block into another device, i.e saving to an image file
+    pub fn save_to(&self, device: &dyn BlockDevice) -> FsResult<u32> {
+        copy_blocks(self, device)
+    }
+}
+
+impl BlockDevice for MemDisk {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        if number == 0 {
+            return Err(FsError::OutOfRange(number));
+        }
+        self.blocks
+            .borrow()
+            .get(number as usize - 1)
+            .cloned()
+            .ok_or(FsError::OutOfRange(number))
+    }
+
+    fn write_block(&self, number: u32, data: &[u8]) -> FsResult<()> {
+        if number == 0 {
+            return Err(FsError::OutOfRange(number));
+        }
+        let mut blocks = self.blocks.borrow_mut();
+        if blocks.len() < number as usize {
+            blocks.resize(number as usize, vec![]);
+        }
+        blocks[number as usize - 1] = data.to_vec();
+        Ok(())
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        Ok(self.blocks.borrow().len() as u32)
+    }
+
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, len: u32) -> FsResult<()> {
+        self.blocks.borrow_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::block::Block;
+    use super::*;
+
+    #[test]
+    fn from_image_should_have_every_block() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        assert_eq!(device.len(), Ok(10));
+        let data = device.read_block(9).unwrap();
+        // sda1 is from before checksums
+        assert_eq!(Block::from_unchecked_bytes(9, &data).unwrap().data, b"Me");
+    }
+
+    #[test]
+    fn write_past_end_should_grow() {
+        let device = MemDisk::new();
+        assert_eq!(device.write_block(3, b"Shrek"), Ok(()));
+        assert_eq!(device.len(), Ok(3));
+        assert_eq!(device.read_block(3), Ok(b"Shrek".to_vec()));
+        assert_eq!(device.read_block(2), Ok(vec![]));
+        assert_eq!(device.read_block(4), Err(FsError::OutOfRange(4)));
+    }
+
+    #[test]
+    fn formatted_should_mount() {
+        let device = MemDisk::formatted(50).unwrap();
+        assert_eq!(device.len(), Ok(50));
+        assert!(FileSystem::mount(&device).is_ok());
+    }
+}
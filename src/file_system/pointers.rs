@@ -0,0 +1,135 @@
+use super::utils;
+use serde::{Deserialize, Serialize};
+
+// Direct block pointers kept in the inode itself, like ext2
+pub const DIRECT_POINTERS: usize = 12;
+// Block numbers held by an indirect block, each takes 4 bytes
+pub const POINTERS_PER_BLOCK: usize = 256;
+
+// Where the blocks of an inode using pointers are, 0 means no block
+// Blocks fill up in order, so the indirect block is full before the
+// double indirect one is used, and so are all but its last indirect block
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pointers {
+    pub direct: [u32; DIRECT_POINTERS],
+    pub indirect: u32,        // Block of data block numbers
+    pub double_indirect: u32, // Block of indirect block numbers
+}
+
+impl Pointers {
+    pub fn is_empty(&self) -> bool {
+        *self == Pointers::default()
+    }
+
+    // Most data blocks the direct, indirect and double indirect pointers can reach
+    pub fn max_blocks() -> usize {
+        DIRECT_POINTERS + POINTERS_PER_BLOCK + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK
+    }
+
+    // How many pointer blocks it takes to find count data blocks
+    pub fn blocks_needed(count: usize) -> usize {
+        let rest = count.saturating_sub(DIRECT_POINTERS);
+        match rest {
+            0 => 0,
+            r if r <= POINTERS_PER_BLOCK => 1,
+            r => 2 + (r - POINTERS_PER_BLOCK).div_ceil(POINTERS_PER_BLOCK),
+        }
+    }
+
+    // Point at data block numbers, using the pointer blocks given
+    // The first is the indirect block, then the double indirect and its indirect blocks
+    // Gives back the pointers and what goes in each pointer block
+    pub fn build(numbers: &[u32], pointer_blocks: &[u32]) -> (Pointers, Vec<Vec<u8>>) {
+        let mut pointers = Pointers::default();
+        let mut lists = vec![];
+        for (n, x) in numbers.iter().take(DIRECT_POINTERS).enumerate() {
+            pointers.direct[n] = *x;
+        }
+        let rest = numbers.get(DIRECT_POINTERS..).unwrap_or_default();
+        if let Some(indirect) = pointer_blocks.first() {
+            pointers.indirect = *indirect;
+            lists.push(Pointers::to_bytes(
+                &rest[..rest.len().min(POINTERS_PER_BLOCK)],
+            ));
+        }
+        if let Some(double) = pointer_blocks.get(1) {
+            pointers.double_indirect = *double;
+            let seconds = &pointer_blocks[2..];
+            lists.push(Pointers::to_bytes(seconds));
+            for chunk in rest[POINTERS_PER_BLOCK..].chunks(POINTERS_PER_BLOCK) {
+                lists.push(Pointers::to_bytes(chunk));
+            }
+        }
+        (pointers, lists)
+    }
+
+    pub fn to_bytes(numbers: &[u32]) -> Vec<u8> {
+        let mut bytes = vec![];
+        numbers.iter().for_each(|n| utils::put_u32(&mut bytes, *n));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Vec<u32>> {
+        if !bytes.len().is_multiple_of(4) {
+            return None;
+        }
+        (0..bytes.len())
+            .step_by(4)
+            .map(|i| utils::get_u32(bytes, i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_needed_should_count_pointer_blocks() {
+        assert_eq!(Pointers::blocks_needed(DIRECT_POINTERS), 0);
+        assert_eq!(Pointers::blocks_needed(DIRECT_POINTERS + 1), 1);
+        assert_eq!(
+            Pointers::blocks_needed(DIRECT_POINTERS + POINTERS_PER_BLOCK + 1),
+            3
+        );
+        assert_eq!(
+            Pointers::blocks_needed(DIRECT_POINTERS + POINTERS_PER_BLOCK * 3),
+            4
+        );
+    }
+
+    #[test]
+    fn max_blocks_should_count_every_pointer() {
+        assert_eq!(Pointers::default().direct.len(), DIRECT_POINTERS);
+        assert_eq!(Pointers::max_blocks(), 12 + 256 + 256 * 256);
+        // One indirect block, the double indirect one and all of its indirect blocks
+        assert_eq!(
+            Pointers::blocks_needed(Pointers::max_blocks()),
+            2 + POINTERS_PER_BLOCK
+        );
+    }
+
+    #[test]
+    fn build_should_fill_direct_then_indirect() {
+        let numbers: Vec<u32> = (100..100 + DIRECT_POINTERS as u32 + 2).collect();
+        let (pointers, lists) = Pointers::build(&numbers, &[7]);
+        assert_eq!(pointers.direct[0], 100);
+        assert_eq!(pointers.indirect, 7);
+        assert_eq!(pointers.double_indirect, 0);
+        assert_eq!(
+            Pointers::from_bytes(&lists[0]),
+            Some(numbers[DIRECT_POINTERS..].to_vec())
+        );
+
+        let count = DIRECT_POINTERS + POINTERS_PER_BLOCK + 1;
+        let numbers: Vec<u32> = (1000..1000 + count as u32).collect();
+        let (pointers, lists) = Pointers::build(&numbers, &[7, 8, 9]);
+        assert_eq!(pointers.double_indirect, 8);
+        assert_eq!(Pointers::from_bytes(&lists[1]), Some(vec![9]));
+        assert_eq!(
+            Pointers::from_bytes(&lists[2]),
+            Some(vec![1000 + count as u32 - 1])
+        );
+        assert_eq!(Pointers::from_bytes(&[1, 2]), None);
+    }
+}
@@ -0,0 +1,285 @@
+use super::bitmap::*;
+use super::block::*;
+use super::directory::Directory;
+use super::disk::*;
+use super::error::*;
+use super::inode::*;
+use super::mem_disk::MemDisk;
+use super::pointers::Pointers;
+use super::{chunk_data, write_inode_data, FileSystem};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+// What resizing a disk did
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Resize {
+    pub from: u32,
+    pub to: u32,
+    pub moved: u32,      // Inodes whose blocks were written somewhere else
+    pub renumbered: u32, // Inodes that were past the end of a smaller inode table
+}
+
+// An inode in use along with everything it keeps
+struct Kept {
+    inode: Inode,
+    blocks: Vec<u32>, // Data blocks, then extent or pointer blocks
+    lens: Vec<usize>, // Bytes in each data block
+    data: Vec<u8>,
+    changed: bool, // Data is different to what's in the blocks
+}
+
+// The same disk laid out over size blocks
+// The journal keeps its size, the bitmaps and inode table are sized for the new disk
+fn resized(sb: &SuperBlock, size: u32) -> SuperBlock {
+    let new = SuperBlock {
+        total_blocks: size,
+        ..sb.clone()
+    };
+    match sb.block_bitmap_blocks {
+        0 => new,
+        _ => SuperBlock {
+            journal_blocks: sb.journal_blocks,
+            ..new.with_bitmaps()
+        },
+    }
+}
+
+// Every inode in use with its blocks and data
+fn read_inodes(disk: &mut Disk) -> FsResult<Vec<Kept>> {
+    let inodes = run(disk, Inode::get_inode_table())?;
+    let mut kept = vec![];
+    for i in inodes.into_iter().filter(|i| i.start_block.is_some()) {
+        let (_, data) = run(disk, Inode::get_inode_blocks(i))?;
+        let metadata = run(disk, Inode::get_metadata_blocks(i))?;
+        kept.push(Kept {
+            inode: i,
+            blocks: data
+                .iter()
+                .chain(metadata.iter())
+                .map(|b| b.number)
+                .collect(),
+            lens: data.iter().map(|b| b.data.len()).collect(),
+            data: Block::blocks_to_data(data),
+            changed: false,
+        });
+    }
+    Ok(kept)
+}
+
+// Give an inode its new number, directories get their entries renumbered too
+fn renumber(k: Kept, numbers: &HashMap<u32, u32>) -> FsResult<Kept> {
+    let number = *numbers.get(&k.inode.number).unwrap_or(&k.inode.number);
+    let inode = Inode { number, ..k.inode };
+    if !inode.is_directory() {
+        return Ok(Kept { inode, ..k });
+    }
+    let d = Directory::parse_directory(k.data.clone()).ok_or(FsError::Corrupt {
+        block: inode.start_block.unwrap_or(0),
+    })?;
+    let renumbered = d.renumbered(numbers);
+    if renumbered == d {
+        return Ok(Kept { inode, ..k });
+    }
+    let data = serde_json::to_vec(&renumbered).unwrap_or_default();
+    Ok(Kept {
+        inode: Inode {
+            size: data.len() as u64,
+            ..inode
+        },
+        lens: vec![],
+        data,
+        changed: true,
+        ..k
+    })
+}
+
+// Grow or shrink a disk to size blocks without losing anything on it
+// Inodes keeping blocks outside the new storage blocks are written inside them, and
+// ones past the end of a smaller inode table get free numbers nearer the start.
+// Like upgrading it is done in memory first, the device is only written once that has worked
+pub fn resize(device: &dyn BlockDevice, size: u32) -> FsResult<Resize> {
+    let mut disk = FileSystem::mount(device)?;
+    let sb = run(&mut disk, SuperBlock::get_super_block())?;
+    let mut resize = Resize {
+        from: sb.total_blocks,
+        to: size,
+        moved: 0,
+        renumbered: 0,
+    };
+    if size == sb.total_blocks {
+        return Ok(resize);
+    }
+    // Moving files would leave snapshots pointing at the wrong blocks
+    if !sb.snapshots.is_empty() {
+        return Err(FsError::HasSnapshots);
+    }
+    let new_sb = resized(&sb, size);
+    if new_sb.get_storage_block_range().is_empty() {
+        return Err(FsError::InvalidGeometry(
+            "no blocks are left for storage".into(),
+        ));
+    }
+
+    let scratch = MemDisk::from_device(device)?;
+    let mut disk = Disk::new(&scratch);
+    let kept = read_inodes(&mut disk)?;
+
+    let capacity = new_sb.get_inode_capacity();
+    let used: HashSet<u32> = kept.iter().map(|k| k.inode.number).collect();
+    let mut free = (1..capacity + 1).filter(|n| !used.contains(n));
+    let mut numbers = HashMap::new();
+    for k in kept.iter().filter(|k| k.inode.number > capacity) {
+        numbers.insert(k.inode.number, free.next().ok_or(FsError::NoFreeInodes)?);
+    }
+    resize.renumbered = numbers.len() as u32;
+
+    let storage = new_sb.get_storage_block_range();
+    let mut staying = vec![];
+    let mut moving = vec![];
+    for k in kept {
+        let k = renumber(k, &numbers)?;
+        match k.changed || k.blocks.iter().any(|n| !storage.contains(n)) {
+            true => moving.push(k),
+            false => staying.push(k),
+        }
+    }
+    resize.moved = moving.len() as u32;
+
+    // Everything is in memory now, so every block not staying where it is starts off free
+    run(&mut disk, SuperBlock::write_super_block(new_sb.clone()))?;
+    let kept_blocks: HashSet<u32> = staying.iter().flat_map(|k| k.blocks.clone()).collect();
+    let frees = (2..storage.end)
+        .filter(|n| !kept_blocks.contains(n))
+        .map(|number| Block {
+            number,
+            b_type: BlockType::Free,
+            data: vec![],
+        })
+        .map(Block::write_block)
+        .collect();
+    run(&mut disk, sequence_result(frees))?;
+    let mut table = Inode::generate_inodes(&new_sb);
+    for k in staying.iter() {
+        let slot = (k.inode.number as usize)
+            .checked_sub(1)
+            .and_then(|n| table.get_mut(n))
+            .ok_or(FsError::InvalidInode(k.inode.number))?;
+        *slot = k.inode;
+    }
+    run(&mut disk, Inode::replace_all_inodes(table))?;
+    run(&mut disk, Bitmap::rebuild())?;
+
+    for k in moving {
+        let old = Inode {
+            start_block: None,
+            pointers: Pointers::default(),
+            ..k.inode
+        };
+        let chunks = run(&mut disk, chunk_data(k.data, k.lens))?;
+        run(&mut disk, write_inode_data(old, vec![], k.inode, chunks))?;
+    }
+
+    scratch.truncate(size)?;
+    scratch.save_to(device)?;
+    device.truncate(size)?;
+    device.flush()?;
+    Ok(resize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::geometry::Geometry;
+    use super::*;
+
+    #[test]
+    fn resize_should_grow_a_disk() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, _) = FileSystem::save_as_file("shrek.txt".into(), vec![b's'; 120])(disk);
+
+        let resize = super::resize(&device, 120).unwrap();
+        assert_eq!((resize.from, resize.to), (50, 120));
+        assert_eq!(device.len(), Ok(120));
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        assert_eq!(sb.total_blocks, 120);
+        assert_eq!(sb.get_backup_block_range(), 119..121);
+        assert!(sb.get_inode_capacity() > 25);
+        let (data, disk) = FileSystem::read_file("shrek.txt".into())(disk);
+        assert_eq!(data, Ok(vec![b's'; 120]));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+    }
+
+    #[test]
+    fn resize_should_reject_inode_zero() {
+        let device = MemDisk::formatted(50).unwrap();
+        let mut disk = Disk::new(&device);
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), vec![b's'; 20]),
+        )
+        .unwrap();
+        let mut inodes = run(&mut disk, Inode::get_inode_table()).unwrap();
+        let shrek = inodes[1];
+        inodes[2] = Inode { number: 0, ..shrek };
+        run(&mut disk, Inode::replace_all_inodes(inodes)).unwrap();
+
+        let res = super::resize(&device, 60);
+        assert_eq!(res, Err(FsError::InvalidInode(0)));
+        assert_eq!(device.len(), Ok(50));
+    }
+
+    #[test]
+    fn resize_should_move_files_out_of_a_smaller_disk() {
+        // One inode per table block, so shrinking loses inode numbers as well
+        let geometry = Geometry {
+            inodes_per_block: 1,
+            ..Geometry::default()
+        };
+        let sb = SuperBlock::with_journal(200)
+            .with_geometry(geometry)
+            .with_bitmaps()
+            .with_backups();
+        let device = MemDisk::new();
+        FileSystem::format_with(&device, sb).unwrap();
+        let mut disk = Disk::new(&device);
+        for n in 1..13 {
+            let name = format!("{}.txt", n);
+            run(&mut disk, FileSystem::save_as_file(name, vec![b'a'; n])).unwrap();
+        }
+        let shrek = vec![b's'; 2000];
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), shrek.clone()),
+        )
+        .unwrap();
+        for n in 1..9 {
+            run(&mut disk, FileSystem::remove_file(format!("{}.txt", n))).unwrap();
+        }
+
+        let resize = super::resize(&device, 100).unwrap();
+        assert_eq!(resize.renumbered, 4);
+        assert!(resize.moved > 0);
+        assert_eq!(device.len(), Ok(100));
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        assert_eq!(sb.unwrap().get_inode_capacity(), 10);
+        let mut disk = disk;
+        for n in 9..13 {
+            let data = run(&mut disk, FileSystem::read_file(format!("{}.txt", n)));
+            assert_eq!(data, Ok(vec![b'a'; n]));
+        }
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok(shrek.clone()));
+        let report = run(&mut disk, FileSystem::fsck(false));
+        assert!(report.unwrap().is_clean());
+
+        // Too small for what's on it, nothing changes
+        assert_eq!(super::resize(&device, 60), Err(FsError::NoSpace));
+        assert_eq!(device.len(), Ok(100));
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok(shrek));
+    }
+}
@@ -0,0 +1,174 @@
+use super::bitmap::*;
+use super::block::*;
+use super::directory::Directory;
+use super::disk::*;
+use super::error::*;
+use super::inode::*;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ScrubReport {
+    pub checked: u32,       // Blocks read
+    pub damaged: Vec<u32>,  // Blocks that failed their checksum or couldn't be parsed
+    pub files: Vec<String>, // Files and directories keeping a damaged block
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+// The block a read failed on, when it failed because that block is bad
+fn damaged_block(e: &FsError) -> Option<u32> {
+    match e {
+        FsError::ChecksumMismatch(n) | FsError::Corrupt { block: n } => Some(*n),
+        _ => None,
+    }
+}
+
+// Every block the disk is using, superblocks aside since fsck checks those
+// Journal copies hold blocks from elsewhere, so only the journal header is read
+fn allocated_blocks(disk: &mut Disk, sb: &SuperBlock) -> Vec<u32> {
+    let journal = sb.get_journal_block_range();
+    let header = match journal.is_empty() {
+        true => None,
+        false => Some(journal.start),
+    };
+    let used = run(disk, Bitmap::read(sb, BitmapKind::Blocks))
+        .ok()
+        .flatten();
+    let storage = sb
+        .get_storage_block_range()
+        .filter(|n| used.as_ref().is_none_or(|b| b.get(*n)));
+    header
+        .into_iter()
+        .chain(sb.get_block_bitmap_range())
+        .chain(sb.get_inode_bitmap_range())
+        .chain(sb.get_inode_table_block_range())
+        .chain(storage)
+        .collect()
+}
+
+// Blocks an inode keeps, reading stops at the first damaged one
+fn inode_blocks(disk: &mut Disk, i: Inode) -> FsResult<Vec<u32>> {
+    let metadata = run(disk, Inode::get_metadata_blocks(i))
+        .map(|b| b.iter().map(|x| x.number).collect::<Vec<u32>>());
+    let data = run(disk, Inode::get_data_numbers(i, 0, usize::MAX));
+    let mut numbers = vec![];
+    for x in [metadata, data] {
+        match x {
+            Ok(mut x) => numbers.append(&mut x),
+            Err(e) => numbers.push(damaged_block(&e).ok_or(e)?),
+        }
+    }
+    Ok(numbers)
+}
+
+// Walk the tree from a directory, collecting paths that keep a damaged block
+// An inode that can't be read at all counts as damaged too
+fn find_files(
+    disk: &mut Disk,
+    path: &str,
+    number: u32,
+    damaged: &HashSet<u32>,
+    files: &mut Vec<String>,
+    seen: &mut HashSet<u32>,
+) -> FsResult<()> {
+    let name = if path.is_empty() { "/" } else { path };
+    let i = match run(disk, Inode::get_inode(number)) {
+        Ok(i) => i,
+        Err(e) if damaged_block(&e).is_some() => {
+            files.push(name.into());
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if inode_blocks(disk, i)?.iter().any(|n| damaged.contains(n)) {
+        files.push(name.into());
+        return Ok(());
+    }
+    if !i.is_directory() {
+        return Ok(());
+    }
+    let d = run(disk, Directory::get_directory_at(number))?;
+    for (entry, n) in d.entries() {
+        if seen.insert(n) {
+            find_files(
+                disk,
+                &format!("{}/{}", path, entry),
+                n,
+                damaged,
+                files,
+                seen,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn scrub_disk(disk: &mut Disk) -> FsResult<ScrubReport> {
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    let mut report = ScrubReport::default();
+    for n in allocated_blocks(disk, &sb) {
+        report.checked += 1;
+        match run(disk, Block::get_block(n)) {
+            Ok(_) => (),
+            Err(e) if damaged_block(&e).is_some() => report.damaged.push(n),
+            Err(e) => return Err(e),
+        }
+    }
+    if report.is_clean() {
+        return Ok(report);
+    }
+    let damaged = report.damaged.iter().copied().collect();
+    let mut seen = HashSet::from([ROOT_INODE]);
+    find_files(disk, "", ROOT_INODE, &damaged, &mut report.files, &mut seen)?;
+    Ok(report)
+}
+
+// Read every allocated block checking its checksum, nothing is written
+pub fn scrub<'a>() -> DiskAction<'a, FsResult<ScrubReport>> {
+    Box::new(|disk| {
+        let mut disk = disk;
+        let report = scrub_disk(&mut disk);
+        (report, disk)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::super::FileSystem;
+    use super::*;
+
+    #[test]
+    fn scrub_should_find_files_with_damaged_blocks() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::mkdir("/docs".into())(disk);
+        let (_, disk) = FileSystem::save_as_file("/docs/shrek.txt".into(), vec![b's'; 120])(disk);
+        let (_, disk) = FileSystem::save_as_file("donkey.txt".into(), b"Waffles".to_vec())(disk);
+        let (report, disk) = scrub()(disk);
+        let report = report.unwrap();
+        assert!(report.is_clean());
+        assert!(report.checked > 0);
+
+        // Flip a byte in the middle of shrek.txt
+        let (numbers, disk) = Directory::resolve("/docs/shrek.txt".into())(disk);
+        let (i, disk) = Inode::get_inode(numbers.unwrap())(disk);
+        let (numbers, disk) = Inode::get_data_numbers(i.unwrap(), 1, 2)(disk);
+        let n = numbers.unwrap()[0];
+        let mut bytes = device.read_block(n).unwrap();
+        bytes[BLOCK_HEADER_SIZE] = b'z';
+        device.write_block(n, &bytes).unwrap();
+
+        let (data, disk) = FileSystem::read_file("/docs/shrek.txt".into())(disk);
+        assert_eq!(data, Err(FsError::ChecksumMismatch(n)));
+        let (report, _) = scrub()(disk);
+        let report = report.unwrap();
+        assert_eq!(report.damaged, vec![n]);
+        assert_eq!(report.files, vec!["/docs/shrek.txt".to_string()]);
+    }
+}
@@ -0,0 +1,475 @@
+use super::bitmap::*;
+use super::block::*;
+use super::disk::*;
+use super::error::*;
+use super::inode::*;
+use super::journal::transaction;
+use super::{chunk_data, utils, FileSystem};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+// How many snapshots the superblock records, names are kept short so they all fit
+pub const MAX_SNAPSHOTS: usize = 16;
+const MAX_NAME_LEN: usize = 64;
+
+// A named, read-only copy of the whole filesystem
+// Its blocks are shared with the live tree until the live tree writes over them
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: u64,
+    pub table: u32, // First block of the chain holding its inodes
+}
+
+// What a snapshot keeps, stored as json in a chain of storage blocks
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SnapshotTable {
+    inodes: Vec<Inode>, // Only the ones that were in use
+    blocks: Vec<u32>,   // Data, extent and pointer blocks of those inodes
+}
+
+// A disk as it was when a snapshot was taken
+// The snapshot's inodes stand in for the inode table and nothing can be written
+#[derive(Debug)]
+pub struct SnapshotDevice<'a> {
+    device: &'a dyn BlockDevice,
+    table: BTreeMap<u32, Vec<u8>>, // Inode table blocks made from the snapshot
+}
+
+impl<'a> BlockDevice for SnapshotDevice<'a> {
+    fn read_block(&self, number: u32) -> FsResult<Vec<u8>> {
+        match self.table.get(&number) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => self.device.read_block(number),
+        }
+    }
+
+    fn write_block(&self, _number: u32, _data: &[u8]) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn len(&self) -> FsResult<u32> {
+        self.device.len()
+    }
+
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, _len: u32) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+fn find(sb: &SuperBlock, name: &str) -> FsResult<Snapshot> {
+    sb.snapshots
+        .iter()
+        .find(|s| s.name == name)
+        .cloned()
+        .ok_or_else(|| FsError::NotFound(name.into()))
+}
+
+// A snapshot's table along with the blocks of the chain holding it
+fn read_table<'a>(s: &Snapshot) -> DiskAction<'a, FsResult<(SnapshotTable, Vec<u32>)>> {
+    let table = s.table;
+    let d = Inode::get_chain(table, u64::MAX);
+    map(
+        d,
+        Box::new(move |x| {
+            x.and_then(|blocks| {
+                let numbers = blocks.iter().map(|b| b.number).collect();
+                serde_json::from_slice(&Block::blocks_to_data(blocks))
+                    .map(|t| (t, numbers))
+                    .map_err(|_| FsError::Corrupt { block: table })
+            })
+        }),
+    )
+}
+
+// Every block some snapshot still needs, they can't be written over or freed
+pub fn held_blocks<'a>() -> DiskAction<'a, FsResult<HashSet<u32>>> {
+    let d = SuperBlock::get_super_block();
+    flat_map_result(
+        d,
+        Box::new(|sb: SuperBlock| {
+            let reads = sb.snapshots.iter().map(read_table).collect();
+            map_result(
+                sequence_result(reads),
+                Box::new(|tables| {
+                    tables
+                        .into_iter()
+                        .flat_map(|(t, chain)| t.blocks.into_iter().chain(chain))
+                        .collect()
+                }),
+            )
+        }),
+    )
+}
+
+fn used_inodes(disk: &mut Disk) -> FsResult<Vec<Inode>> {
+    let inodes = run(disk, Inode::get_inode_table())?;
+    Ok(inodes
+        .into_iter()
+        .filter(|i| i.start_block.is_some())
+        .collect())
+}
+
+// Data and metadata blocks of the inodes given
+fn owned_blocks(disk: &mut Disk, inodes: &[Inode]) -> FsResult<HashSet<u32>> {
+    let mut owned = HashSet::new();
+    for i in inodes.iter() {
+        owned.extend(run(disk, Inode::get_data_numbers(*i, 0, usize::MAX))?);
+        let metadata = run(disk, Inode::get_metadata_blocks(*i))?;
+        owned.extend(metadata.iter().map(|b| b.number));
+    }
+    Ok(owned)
+}
+
+fn create_snapshot(disk: &mut Disk, name: &str) -> FsResult<Snapshot> {
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+        return Err(FsError::InvalidName(name.into()));
+    }
+    if sb.snapshots.iter().any(|s| s.name == name) {
+        return Err(FsError::AlreadyExists(name.into()));
+    }
+    if sb.snapshots.len() >= MAX_SNAPSHOTS {
+        return Err(FsError::NoSpace);
+    }
+
+    let inodes = used_inodes(disk)?;
+    let mut blocks: Vec<u32> = owned_blocks(disk, &inodes)?.into_iter().collect();
+    blocks.sort_unstable();
+    let table = serde_json::to_vec(&SnapshotTable { inodes, blocks }).unwrap_or_default();
+    let chunks = run(disk, chunk_data(table, vec![]))?;
+    let free = run(disk, Block::get_free_data_blocks(chunks.len()))?;
+    let chain = Block::set_data_blocks_data((free, chunks));
+    let (start, chain) = Inode::set_inode_blocks(Inode::default(), chain)?;
+    let numbers = chain.iter().map(|b| b.number).collect();
+    run(
+        disk,
+        sequence_result(chain.into_iter().map(Block::write_block).collect()),
+    )?;
+    run(disk, Bitmap::mark(BitmapKind::Blocks, numbers, true))?;
+
+    let snapshot = Snapshot {
+        name: name.into(),
+        created: utils::now(),
+        table: start.start_block.unwrap_or_default(),
+    };
+    let mut snapshots = sb.snapshots.clone();
+    snapshots.push(snapshot.clone());
+    run(
+        disk,
+        SuperBlock::write_super_block(sb.with_snapshots(snapshots)),
+    )?;
+    Ok(snapshot)
+}
+
+// A whole inode table with the snapshot's inodes in place, the rest free
+// An inode the table has no room for means the snapshot is broken
+fn full_table(sb: &SuperBlock, kept: Vec<Inode>) -> FsResult<Vec<Inode>> {
+    let mut inodes = Inode::generate_inodes(sb);
+    for i in kept {
+        let slot = (i.number as usize)
+            .checked_sub(1)
+            .and_then(|n| inodes.get_mut(n))
+            .ok_or(FsError::InvalidInode(i.number))?;
+        *slot = i;
+    }
+    Ok(inodes)
+}
+
+// Put the live tree back to how it was, the snapshot is kept
+// Blocks only the live tree had are freed, the snapshot's are shared again
+fn rollback_disk(disk: &mut Disk, name: &str) -> FsResult<Snapshot> {
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    let snapshot = find(&sb, name)?;
+    let (table, _) = run(disk, read_table(&snapshot))?;
+    let inodes = full_table(&sb, table.inodes)?;
+    let live = used_inodes(disk)?;
+    let frees = owned_blocks(disk, &live)?
+        .into_iter()
+        .map(|number| Block {
+            number,
+            b_type: BlockType::Free,
+            data: vec![],
+        })
+        .collect();
+    run(disk, Block::free_blocks(frees))?;
+    run(disk, Inode::replace_all_inodes(inodes))?;
+    run(disk, Bitmap::rebuild())?;
+    Ok(snapshot)
+}
+
+// Forget a snapshot, freeing the blocks nothing else needs
+fn delete_snapshot(disk: &mut Disk, name: &str) -> FsResult<Snapshot> {
+    let sb = run(disk, SuperBlock::get_super_block())?;
+    let snapshot = find(&sb, name)?;
+    let (table, chain) = run(disk, read_table(&snapshot))?;
+    let rest = sb
+        .snapshots
+        .iter()
+        .filter(|s| s.name != name)
+        .cloned()
+        .collect();
+    run(disk, SuperBlock::write_super_block(sb.with_snapshots(rest)))?;
+
+    // Other snapshots' blocks are left alone by free_blocks
+    let live = used_inodes(disk)?;
+    let owned = owned_blocks(disk, &live)?;
+    let frees = table
+        .blocks
+        .into_iter()
+        .chain(chain)
+        .filter(|n| !owned.contains(n))
+        .map(|number| Block {
+            number,
+            b_type: BlockType::Free,
+            data: vec![],
+        })
+        .collect();
+    run(disk, Block::free_blocks(frees))?;
+    Ok(snapshot)
+}
+
+fn in_transaction<'a>(
+    name: String,
+    f: fn(&mut Disk, &str) -> FsResult<Snapshot>,
+) -> DiskAction<'a, FsResult<Snapshot>> {
+    let d: DiskAction<FsResult<Snapshot>> = Box::new(move |disk| {
+        let mut disk = disk;
+        let res = f(&mut disk, &name);
+        (res, disk)
+    });
+    transaction(d)
+}
+
+pub fn snapshot<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+    in_transaction(name, create_snapshot)
+}
+
+pub fn rollback<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+    in_transaction(name, rollback_disk)
+}
+
+pub fn delete<'a>(name: String) -> DiskAction<'a, FsResult<Snapshot>> {
+    in_transaction(name, delete_snapshot)
+}
+
+pub fn list<'a>() -> DiskAction<'a, FsResult<Vec<Snapshot>>> {
+    map_result(SuperBlock::get_super_block(), Box::new(|sb| sb.snapshots))
+}
+
+// Mount the disk as it was when the snapshot was taken
+pub fn mount(device: &dyn BlockDevice, name: String) -> FsResult<SnapshotDevice<'_>> {
+    let mut disk = FileSystem::mount(device)?;
+    let sb = run(&mut disk, SuperBlock::get_super_block())?;
+    let snapshot = find(&sb, &name)?;
+    let (table, _) = run(&mut disk, read_table(&snapshot))?;
+    let inodes = full_table(&sb, table.inodes)?;
+    let table = sb
+        .get_inode_table_block_range()
+        .zip(inodes.chunks(sb.geometry.inodes_per_block as usize))
+        .map(|(number, chunk)| {
+            let b = Block {
+                number,
+                b_type: BlockType::End,
+                data: serde_json::to_vec(chunk).unwrap_or_default(),
+            };
+            (number, b.to_bytes())
+        })
+        .collect();
+    Ok(SnapshotDevice { device, table })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem_disk::MemDisk;
+    use super::*;
+
+    fn free_count(disk: &mut Disk) -> usize {
+        run(disk, Block::get_all_free_data_blocks()).unwrap().len()
+    }
+
+    #[test]
+    fn superblock_should_record_snapshots() {
+        let snapshot = Snapshot {
+            name: "before".into(),
+            created: 1234,
+            table: 40,
+        };
+        let sb = SuperBlock::with_journal(100).with_snapshots(vec![snapshot]);
+        assert_eq!(sb.features & FEATURE_SNAPSHOTS, FEATURE_SNAPSHOTS);
+        assert_eq!(SuperBlock::from_bytes(&sb.to_bytes()), Some(sb.clone()));
+        let sb = sb.with_snapshots(vec![]);
+        assert_eq!(sb.features & FEATURE_SNAPSHOTS, 0);
+    }
+
+    #[test]
+    fn snapshot_should_keep_files_as_they_were() {
+        let device = MemDisk::formatted(200).unwrap();
+        let mut disk = Disk::new(&device);
+        let shrek = b"Ogres are like onions".to_vec();
+        run(&mut disk, FileSystem::mkdir("/docs".into())).unwrap();
+        run(
+            &mut disk,
+            FileSystem::save_as_file("/docs/shrek.txt".into(), shrek.clone()),
+        )
+        .unwrap();
+        run(
+            &mut disk,
+            FileSystem::save_as_file("donkey.txt".into(), vec![b'd'; 120]),
+        )
+        .unwrap();
+        let before = run(&mut disk, snapshot("before".into())).unwrap();
+        assert_eq!(run(&mut disk, list()), Ok(vec![before]));
+        assert_eq!(
+            run(&mut disk, snapshot("before".into())),
+            Err(FsError::AlreadyExists("before".into()))
+        );
+
+        run(
+            &mut disk,
+            FileSystem::append("/docs/shrek.txt".into(), b", they have layers".to_vec()),
+        )
+        .unwrap();
+        run(&mut disk, FileSystem::remove_file("donkey.txt".into())).unwrap();
+        run(
+            &mut disk,
+            FileSystem::save_as_file("fiona.txt".into(), vec![b'f'; 200]),
+        )
+        .unwrap();
+        let live = run(&mut disk, FileSystem::read_file("/docs/shrek.txt".into()));
+        assert_eq!(
+            live,
+            Ok(b"Ogres are like onions, they have layers".to_vec())
+        );
+
+        let snapshot = mount(&device, "before".into()).unwrap();
+        let mut old = Disk::new(&snapshot);
+        let data = run(&mut old, FileSystem::read_file("/docs/shrek.txt".into()));
+        assert_eq!(data, Ok(shrek));
+        let data = run(&mut old, FileSystem::read_file("donkey.txt".into()));
+        assert_eq!(data, Ok(vec![b'd'; 120]));
+        let data = run(&mut old, FileSystem::read_file("fiona.txt".into()));
+        assert_eq!(data, Err(FsError::NotFound("fiona.txt".into())));
+        let res = run(
+            &mut old,
+            FileSystem::save_as_file("lord.txt".into(), vec![]),
+        );
+        assert_eq!(res, Err(FsError::ReadOnly));
+
+        let report = run(&mut disk, FileSystem::fsck(false)).unwrap();
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn rollback_should_put_the_live_tree_back() {
+        let device = MemDisk::formatted(200).unwrap();
+        let mut disk = Disk::new(&device);
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), vec![b's'; 120]),
+        )
+        .unwrap();
+        run(&mut disk, snapshot("before".into())).unwrap();
+        run(
+            &mut disk,
+            FileSystem::write_at("shrek.txt".into(), 0, vec![b'x'; 60]),
+        )
+        .unwrap();
+        run(
+            &mut disk,
+            FileSystem::save_as_file("fiona.txt".into(), vec![b'f'; 200]),
+        )
+        .unwrap();
+
+        run(&mut disk, rollback("before".into())).unwrap();
+        let data = run(&mut disk, FileSystem::read_file("shrek.txt".into()));
+        assert_eq!(data, Ok(vec![b's'; 120]));
+        let data = run(&mut disk, FileSystem::read_file("fiona.txt".into()));
+        assert_eq!(data, Err(FsError::NotFound("fiona.txt".into())));
+        let report = run(&mut disk, FileSystem::fsck(false)).unwrap();
+        assert!(report.is_clean());
+
+        // Blocks are shared again, so writing copies them once more
+        run(
+            &mut disk,
+            FileSystem::append("shrek.txt".into(), vec![b'!']),
+        )
+        .unwrap();
+        let snapshot = mount(&device, "before".into()).unwrap();
+        let data = run(
+            &mut Disk::new(&snapshot),
+            FileSystem::read_file("shrek.txt".into()),
+        );
+        assert_eq!(data, Ok(vec![b's'; 120]));
+    }
+
+    #[test]
+    fn snapshot_with_an_inode_out_of_range_should_fail() {
+        let device = MemDisk::formatted(200).unwrap();
+        let mut disk = Disk::new(&device);
+        let sb = run(&mut disk, SuperBlock::get_super_block()).unwrap();
+        let n = run(&mut disk, Block::get_all_free_data_blocks()).unwrap()[0].number;
+        for number in [0, sb.get_inode_capacity() + 1] {
+            let table = SnapshotTable {
+                inodes: vec![Inode {
+                    number,
+                    start_block: Some(n),
+                    ..Inode::default()
+                }],
+                blocks: vec![],
+            };
+            let block = Block {
+                number: n,
+                b_type: BlockType::End,
+                data: serde_json::to_vec(&table).unwrap(),
+            };
+            run(&mut disk, Block::write_block(block)).unwrap();
+            let broken = Snapshot {
+                name: "broken".into(),
+                created: 0,
+                table: n,
+            };
+            let sb = sb.clone().with_snapshots(vec![broken]);
+            run(&mut disk, SuperBlock::write_super_block(sb)).unwrap();
+
+            let res = run(&mut disk, rollback("broken".into()));
+            assert_eq!(res, Err(FsError::InvalidInode(number)));
+            let res = mount(&device, "broken".into());
+            assert_eq!(res.err(), Some(FsError::InvalidInode(number)));
+        }
+    }
+
+    #[test]
+    fn delete_should_free_what_only_the_snapshot_needed() {
+        let device = MemDisk::formatted(200).unwrap();
+        let mut disk = Disk::new(&device);
+        run(
+            &mut disk,
+            FileSystem::save_as_file("shrek.txt".into(), vec![b's'; 120]),
+        )
+        .unwrap();
+        let free = free_count(&mut disk);
+
+        run(&mut disk, snapshot("before".into())).unwrap();
+        assert!(free_count(&mut disk) < free);
+        run(
+            &mut disk,
+            FileSystem::write_at("shrek.txt".into(), 0, vec![b'x'; 60]),
+        )
+        .unwrap();
+        run(&mut disk, delete("before".into())).unwrap();
+        assert_eq!(run(&mut disk, list()), Ok(vec![]));
+        assert_eq!(free_count(&mut disk), free);
+        assert_eq!(
+            run(&mut disk, delete("before".into())),
+            Err(FsError::NotFound("before".into()))
+        );
+
+        let (sb, _) = SuperBlock::get_super_block()(disk);
+        assert_eq!(sb.unwrap().features & FEATURE_SNAPSHOTS, 0);
+    }
+}
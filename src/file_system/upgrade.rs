@@ -0,0 +1,321 @@
+use super::bitmap::*;
+use super::block::*;
+use super::compress;
+use super::directory::Directory;
+use super::disk::*;
+use super::error::*;
+use super::inode::*;
+use super::mem_disk::MemDisk;
+use super::FileSystem;
+use serde::Serialize;
+use std::collections::HashSet;
+
+// What upgrading a disk did
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Upgrade {
+    pub from: u32,
+    pub to: u32,
+    pub rebuilt: bool, // Too old to upgrade in place, so it was laid out again
+    pub migrated: u32, // Inodes moved off block chains
+}
+
+// Something found on a disk being rebuilt, parents come before their children
+enum Entry {
+    Directory(String, Inode),
+    File(String, Inode, Vec<u8>),
+}
+
+// Bring a disk up to FORMAT_VERSION
+// Disks that already have a journal and bitmaps get their chained files moved to
+// extents in place, and backup superblocks if the last blocks are free. Anything
+// else is laid out again in memory with the same size and geometry, the device
+// is only written once that has worked
+pub fn upgrade(device: &dyn BlockDevice) -> FsResult<Upgrade> {
+    let mut disk = FileSystem::mount(device)?;
+    let sb = run(&mut disk, SuperBlock::get_super_block())?;
+    let mut upgrade = Upgrade {
+        from: sb.version,
+        to: FORMAT_VERSION,
+        rebuilt: false,
+        migrated: 0,
+    };
+    if sb.version == FORMAT_VERSION {
+        return Ok(upgrade);
+    }
+    // Disks from the version before only lack checksums
+    if sb.version == CHECKSUM_VERSION - 1 {
+        add_checksums(disk.device, &sb)?;
+        run(
+            &mut disk,
+            SuperBlock::write_super_block(sb.with_current_version()),
+        )?;
+        FileSystem::unmount(disk)?;
+        return Ok(upgrade);
+    }
+    if !sb.snapshots.is_empty() {
+        return Err(FsError::HasSnapshots);
+    }
+    if sb.journal_blocks > 0 && sb.block_bitmap_blocks > 0 && sb.inode_bitmap_blocks > 0 {
+        upgrade.migrated = run(&mut disk, FileSystem::migrate_to_extents())?;
+        upgrade.rebuilt = !add_backups(&mut disk, sb.clone())?;
+    } else {
+        upgrade.rebuilt = true;
+    }
+    if upgrade.rebuilt {
+        upgrade.migrated += rebuild(&mut disk, sb)?;
+    }
+    FileSystem::unmount(disk)?;
+    Ok(upgrade)
+}
+
+// Backups go in the last storage blocks, which can only be taken over while free
+// Gives back false when they're in use
+fn add_backups(disk: &mut Disk, sb: SuperBlock) -> FsResult<bool> {
+    if sb.backup_blocks == 0 {
+        let backups = sb.clone().with_backups().get_backup_block_range();
+        for n in backups.clone() {
+            if run(disk, Block::get_block(n))?.b_type != BlockType::Free {
+                return Ok(false);
+            }
+        }
+        run(
+            disk,
+            Bitmap::mark(BitmapKind::Blocks, backups.collect(), true),
+        )?;
+    }
+    add_checksums(disk.device, &sb)?;
+    let sb = sb.with_backups().with_current_version();
+    run(disk, SuperBlock::write_super_block(sb))?;
+    Ok(true)
+}
+
+// Write every block that has no checksum again with one, which has to happen before
+// the superblock says every block has one. Gives back how many were written
+fn add_checksums(device: &dyn BlockDevice, sb: &SuperBlock) -> FsResult<u32> {
+    let backups = sb.get_backup_block_range();
+    let mut added = 0;
+    for n in (2..device.len()? + 1).filter(|n| !backups.contains(n)) {
+        let bytes = device.read_block(n)?;
+        if Block::from_bytes(n, &bytes).is_ok() {
+            continue;
+        }
+        if let Ok(b) = Block::from_unchecked_bytes(n, &bytes) {
+            device.write_block(n, &b.to_bytes())?;
+            added += 1;
+        }
+    }
+    device.flush()?;
+    Ok(added)
+}
+
+// Copy every file and directory onto a freshly formatted disk, then over the old one
+// Gives back how many inodes were using chains
+fn rebuild(disk: &mut Disk, sb: SuperBlock) -> FsResult<u32> {
+    let root = run(disk, Inode::get_inode(ROOT_INODE))?;
+    let mut entries = vec![];
+    let mut seen = HashSet::from([ROOT_INODE]);
+    collect(disk, "", root, &mut entries, &mut seen)?;
+    let chains = std::iter::once(&root)
+        .chain(entries.iter().map(|e| match e {
+            Entry::Directory(_, i) | Entry::File(_, i, _) => i,
+        }))
+        .filter(|i| i.layout == Layout::Chain)
+        .count() as u32;
+
+    // Small disks might only fit without backups
+    let layout = SuperBlock::with_journal(sb.total_blocks)
+        .with_geometry(sb.geometry)
+        .with_bitmaps()
+        .with_layout(Layout::Extents);
+    let new_device = match copy_onto(&entries, &root, layout.clone().with_backups()) {
+        Err(FsError::NoSpace) => copy_onto(&entries, &root, layout),
+        x => x,
+    }?;
+    new_device.save_to(disk.device)?;
+    Ok(chains)
+}
+
+fn copy_onto(entries: &[Entry], root: &Inode, layout: SuperBlock) -> FsResult<MemDisk> {
+    let new_device = MemDisk::new();
+    FileSystem::format_with(&new_device, layout)?;
+    let mut new_disk = Disk::new(&new_device);
+    for entry in entries.iter() {
+        let (path, old) = match entry {
+            Entry::Directory(path, i) => {
+                run(&mut new_disk, FileSystem::mkdir(path.clone()))?;
+                (path.clone(), i)
+            }
+            Entry::File(path, i, data) => {
+                run(
+                    &mut new_disk,
+                    FileSystem::save_as_file(path.clone(), data.clone()),
+                )?;
+                if !i.compression.is_none() {
+                    let d = FileSystem::set_compression(path.clone(), i.compression);
+                    run(&mut new_disk, d)?;
+                }
+                (path.clone(), i)
+            }
+        };
+        let number = run(&mut new_disk, Directory::resolve(path))?;
+        keep_metadata(&mut new_disk, number, old)?;
+    }
+    keep_metadata(&mut new_disk, ROOT_INODE, root)?;
+    Ok(new_device)
+}
+
+fn collect(
+    disk: &mut Disk,
+    path: &str,
+    directory: Inode,
+    entries: &mut Vec<Entry>,
+    seen: &mut HashSet<u32>,
+) -> FsResult<()> {
+    let d = run(disk, Directory::get_directory_at(directory.number))?;
+    for (name, number) in d.entries() {
+        let i = run(disk, Inode::get_inode(number))?;
+        // Entries for free inodes and loops back up the tree are left behind
+        if i.start_block.is_none() || !seen.insert(number) {
+            continue;
+        }
+        let path = format!("{}/{}", path, name);
+        if i.is_directory() {
+            entries.push(Entry::Directory(path.clone(), i));
+            collect(disk, &path, i, entries, seen)?;
+        } else {
+            let (_, blocks) = run(disk, Inode::get_inode_blocks(i))?;
+            let data = compress::decode(&i, Block::blocks_to_data(blocks))?;
+            entries.push(Entry::File(path, i, data));
+        }
+    }
+    Ok(())
+}
+
+// Older disks never recorded times or owners, those keep the ones just made
+fn keep_metadata(disk: &mut Disk, number: u32, old: &Inode) -> FsResult<()> {
+    if old.created == 0 {
+        return Ok(());
+    }
+    let i = run(disk, Inode::get_inode(number))?;
+    let i = Inode {
+        created: old.created,
+        modified: old.modified,
+        accessed: old.accessed,
+        mode: old.mode,
+        uid: old.uid,
+        gid: old.gid,
+        ..i
+    };
+    run(disk, Inode::write_inode(i))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_should_rebuild_disks_without_a_journal() {
+        let device = MemDisk::from_image("./test-files/sda1").unwrap();
+        let upgrade = super::upgrade(&device).unwrap();
+        assert_eq!(upgrade.from, 0);
+        assert!(upgrade.rebuilt);
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        assert_eq!(sb.version, FORMAT_VERSION);
+        // Too small for a journal, but it gets bitmaps
+        assert_eq!(sb.journal_blocks, 0);
+        assert!(sb.block_bitmap_blocks > 0);
+        let (data, disk) = FileSystem::read_file("secret.txt".into())(disk);
+        assert_eq!(data, Ok(b"SomebodyOnceToldMe".to_vec()));
+        let (report, _) = FileSystem::fsck(false)(disk);
+        assert!(report.unwrap().is_clean());
+        assert_eq!(super::upgrade(&device).unwrap().from, FORMAT_VERSION);
+    }
+
+    #[test]
+    fn upgrade_should_migrate_newer_disks_in_place() {
+        let device = MemDisk::new();
+        let sb = SuperBlock::with_journal(50)
+            .with_bitmaps()
+            .with_layout(Layout::Chain);
+        FileSystem::format_with(&device, sb).unwrap();
+        let disk = Disk::new(&device);
+        let (_, disk) = FileSystem::save_as_file("a.txt".into(), vec![b'a'; 120])(disk);
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let old = SuperBlock {
+            version: 0,
+            features: 0,
+            ..sb.unwrap()
+        };
+        let _ = SuperBlock::write_super_block(old)(disk);
+
+        let upgrade = super::upgrade(&device).unwrap();
+        assert_eq!((upgrade.rebuilt, upgrade.migrated), (false, 2));
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        assert_eq!(sb.version, FORMAT_VERSION);
+        assert_eq!(
+            sb.features,
+            FEATURE_JOURNAL | FEATURE_BITMAPS | FEATURE_BACKUP_SUPER_BLOCKS
+        );
+        let (data, _) = FileSystem::read_file("a.txt".into())(disk);
+        assert_eq!(data, Ok(vec![b'a'; 120]));
+    }
+
+    #[test]
+    fn upgrade_should_add_a_superblock_checksum() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (sb, _) = SuperBlock::get_super_block()(disk);
+        let old = SuperBlock {
+            version: CHECKSUM_VERSION - 1,
+            ..sb.unwrap()
+        };
+        let mut bytes = old.to_bytes();
+        bytes.truncate(bytes.len() - 4);
+        device.write_block(1, &bytes).unwrap();
+        // A block written before checksums
+        let storage = old.get_storage_block_range().start;
+        let mut block = device.read_block(storage).unwrap();
+        block.truncate(block.len() - BLOCK_CHECKSUM_SIZE);
+        device.write_block(storage, &block).unwrap();
+
+        let upgrade = super::upgrade(&device).unwrap();
+        assert_eq!((upgrade.from, upgrade.rebuilt), (old.version, false));
+        assert_eq!(device.read_block(1).unwrap().len(), bytes.len() + 4);
+        let block = device.read_block(storage).unwrap();
+        assert!(Block::from_bytes(storage, &block).is_ok());
+        let disk = FileSystem::mount(&device).unwrap();
+        let (sb, _) = SuperBlock::get_super_block()(disk);
+        assert_eq!(sb.unwrap().version, FORMAT_VERSION);
+    }
+
+    #[test]
+    fn mount_should_refuse_newer_formats() {
+        let device = MemDisk::formatted(50).unwrap();
+        let disk = Disk::new(&device);
+        let (sb, disk) = SuperBlock::get_super_block()(disk);
+        let sb = sb.unwrap();
+        assert_eq!(sb.version, FORMAT_VERSION);
+        let newer = SuperBlock {
+            version: FORMAT_VERSION + 1,
+            ..sb.clone()
+        };
+        let _ = SuperBlock::write_super_block(newer)(disk);
+        let res = FileSystem::mount(&device);
+        assert_eq!(
+            res.err(),
+            Some(FsError::UnsupportedFormat(FORMAT_VERSION + 1))
+        );
+        let unknown = SuperBlock {
+            features: 1 << 31,
+            ..sb
+        };
+        let _ = SuperBlock::write_super_block(unknown)(disk);
+        let res = FileSystem::mount(&device);
+        assert_eq!(res.err(), Some(FsError::UnsupportedFeatures(1 << 31)));
+    }
+}
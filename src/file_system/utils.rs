@@ -1,50 +1,177 @@
-use super::disk::*;
-
-static MAX_DATA_SIZE: u32 = 50;
-
-pub fn lift<'a, A: 'a, B: 'a>(f: Box<dyn Fn(A) -> B>) -> Box<dyn Fn(Option<A>) -> Option<B> + 'a> {
-    Box::new(move |x| match x {
-        Some(a) => Some(f(a)),
-        _ => None,
-    })
-}
-
-pub fn lift_disk_action<'a, A: 'a, B: 'a>(
-    f: Box<dyn Fn(A) -> DiskAction<'a, B>>,
-) -> Box<dyn Fn(Option<A>) -> DiskAction<'a, Option<B>> + 'a> {
-    Box::new(move |a: Option<A>| match a {
-        Some(b) => map(f(b), Box::new(|c| Some(c))),
-        None => Box::new(|disk| (None, disk)),
-    })
-}
-
-pub fn remove_options<A>(b: Vec<Option<A>>) -> Vec<A> {
-    b.into_iter()
-        .fold(Vec::<A>::new(), |mut acc, curr| match curr {
-            Some(a) => {
-                acc.push(a);
-                acc
-            }
-            None => acc,
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Chunk lengths for size bytes that keep the boundaries of the existing chunks
+// Growing fills the last chunk up to max before adding more
+// There is always at least one chunk, even if it's empty, so empty files have a block
+pub fn resize_chunks(lens: &[usize], size: usize, max: u32) -> Vec<usize> {
+    let max = max as usize;
+    let mut left = size;
+    let mut out = vec![];
+    for (n, len) in lens.iter().enumerate() {
+        if left == 0 {
+            break;
+        }
+        let room = if n + 1 == lens.len() {
+            max.max(*len)
+        } else {
+            *len
+        };
+        out.push(left.min(room));
+        left -= left.min(room);
+    }
+    while left > 0 {
+        out.push(left.min(max));
+        left -= left.min(max);
+    }
+    if out.is_empty() {
+        out.push(0);
+    }
+    out
+}
+
+// Split data into chunks of the given lengths
+pub fn split_into_chunks(data: &[u8], lens: &[usize]) -> Vec<Vec<u8>> {
+    let mut start = 0;
+    lens.iter()
+        .map(|len| {
+            let chunk = data[start..start + len].to_vec();
+            start += len;
+            chunk
         })
+        .collect()
+}
+
+// "/docs/notes.txt" -> ["docs", "notes.txt"]
+pub fn path_components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|x| !x.is_empty()).collect()
+}
+
+// "/docs/notes.txt" -> ("/docs", "notes.txt")
+// The root has no parent, so it gives back None
+pub fn split_path(path: &str) -> Option<(String, String)> {
+    let mut components = path_components(path);
+    let name = components.pop()?;
+    Some((format!("/{}", components.join("/")), name.to_owned()))
+}
+
+// Little endian helpers for the binary formats
+pub fn put_u32(buffer: &mut Vec<u8>, x: u32) {
+    buffer.extend_from_slice(&x.to_le_bytes());
+}
+
+pub fn get_u32(buffer: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buffer.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn put_u64(buffer: &mut Vec<u8>, x: u64) {
+    buffer.extend_from_slice(&x.to_le_bytes());
+}
+
+pub fn get_u64(buffer: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buffer.get(offset..offset + 8)?;
+    let mut x = [0; 8];
+    x.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(x))
+}
+
+// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-pub fn string_to_block_data_chunks(s: String) -> Vec<String> {
-    let s = s.chars().collect::<Vec<char>>();
-    s.chunks(MAX_DATA_SIZE as usize)
-        .map(|chunk| chunk.iter().collect())
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
         .collect()
 }
 
+// CRC-32 (IEEE), bit by bit since blocks are small
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn string_to_block_data_chunks_return_expected() {
-        let r = string_to_block_data_chunks(
-            " This is my stirn go fahst ea;lsf jasjfadklsjfal;sdfjads f".into(),
-        );
+    fn new_chunks_return_expected() {
+        let data = b" This is my stirn go fahst ea;lsf jasjfadklsjfal;sdfjads f";
+        let r = split_into_chunks(data, &resize_chunks(&[], data.len(), 50));
         assert_eq!(r.len(), 2);
+        // Chunks split on bytes, not characters
+        let r = split_into_chunks(&[0xFF; 101], &resize_chunks(&[], 101, 50));
+        assert_eq!(
+            r.iter().map(|x| x.len()).collect::<Vec<_>>(),
+            vec![50, 50, 1]
+        );
+    }
+
+    #[test]
+    fn resize_chunks_should_keep_existing_boundaries() {
+        assert_eq!(resize_chunks(&[8, 4, 4, 2], 10, 50), vec![8, 2]);
+        assert_eq!(resize_chunks(&[8, 4, 4, 2], 20, 50), vec![8, 4, 4, 4]);
+        assert_eq!(resize_chunks(&[8, 4], 70, 50), vec![8, 50, 12]);
+        assert_eq!(resize_chunks(&[8, 4], 70, 30), vec![8, 30, 30, 2]);
+        assert_eq!(resize_chunks(&[8, 4], 0, 50), vec![0]);
+        let chunks = split_into_chunks(b"SomebodyOnce", &[8, 4]);
+        assert_eq!(chunks, vec![b"Somebody".to_vec(), b"Once".to_vec()]);
+    }
+
+    #[test]
+    fn split_path_should_return_parent_and_name() {
+        assert_eq!(
+            split_path("/docs/notes.txt"),
+            Some(("/docs".into(), "notes.txt".into()))
+        );
+        assert_eq!(
+            split_path("secret.txt"),
+            Some(("/".into(), "secret.txt".into()))
+        );
+        assert_eq!(split_path("/"), None);
+    }
+
+    #[test]
+    fn put_and_get_u32_should_round_trip() {
+        let mut buffer = vec![7];
+        put_u32(&mut buffer, 0xDEADBEEF);
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(get_u32(&buffer, 1), Some(0xDEADBEEF));
+        assert_eq!(get_u32(&buffer, 2), None);
+    }
+
+    #[test]
+    fn hex_should_round_trip() {
+        let bytes = vec![0, 15, 16, 255];
+        assert_eq!(to_hex(&bytes), "000f10ff");
+        assert_eq!(from_hex("000f10ff"), Some(bytes));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+
+    #[test]
+    fn crc32_should_match_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
     }
 }
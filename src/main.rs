@@ -1,162 +1,961 @@
 extern crate file_system;
-use file_system::disk::Disk;
+use file_system::disk::*;
+use file_system::{
+    open_device, Compression, FileStat, FileSystem, Fragmentation, FsError, FsResult, FsckReport,
+    Geometry, InodeKind, Layout, ScrubReport, Snapshot, SuperBlock,
+};
+use serde::Serialize;
+use std::env;
 use std::fs;
-use std::io::*;
+use std::io::{stdin, stdout, Write};
+use std::process;
+
+// Exit codes
+static EXIT_OK: i32 = 0;
+static EXIT_FAILED: i32 = 1; // The operation failed, i.e the file wasn't found
+static EXIT_USAGE: i32 = 2; // Bad command line
+static EXIT_BAD_DISK: i32 = 3; // Disk couldn't be opened or mounted
+static EXIT_FSCK_PROBLEMS: i32 = 4; // fsck found problems it didn't repair, or scrub found damage
+
+// Where the passphrase comes from when neither --passphrase-file nor --passphrase is given
+static PASSPHRASE_VAR: &str = "FILE_SYSTEM_PASSPHRASE";
+
+static USAGE: &str = "usage: file_system <command> <disk> [args] [--json]
+
+commands:
+  mkfs  <disk> <blocks>           create and format a new disk
+        [--force]                 replace a disk that's already there
+        [--layout=extents|pointers|chain]  how new files keep their blocks
+        [--inode-percent=10] [--block-data-size=50] [--inodes-per-block=5]
+        [--compress=lz|none]      whether new files are compressed
+        [--passphrase-file=path]  encrypt the disk with a key from the passphrase
+        [--encrypt-metadata]      encrypt the inode table and bitmaps too
+  ls    <disk> [path]             list a directory, / by default
+  cat   <disk> <path>             print a file
+  put   <disk> <local> [path]     copy a local file onto the disk
+  get   <disk> <path> [local]     copy a file off the disk, stdout by default
+  rm    <disk> <path>             remove a file
+  mkdir <disk> <path>             make a directory
+  rmdir <disk> <path>             remove an empty directory
+  stat  <disk> <path>             show inode details
+  compress <disk> <path>          keep a file compressed
+  uncompress <disk> <path>        keep a file as it is
+  diag  <disk>                    show disk diagnostics
+  fsck  <disk> [--repair]         check the disk, optionally repairing it
+  scrub <disk>                    read every block in use, listing damaged files
+  migrate <disk>                  move files still using block chains to extents
+  upgrade <disk>                  rewrite an older disk in the current format
+  frag  <disk>                    show how many pieces each file is in
+  defrag <disk> [--budget=N]      put fragmented files in one piece, moving at most N blocks
+  resize <disk> <blocks>          grow or shrink a disk, keeping what's on it
+  snapshot <disk> <name>          take a snapshot of the whole disk
+  snapshots <disk>                list snapshots
+  rollback <disk> <name>          put the disk back how it was in a snapshot
+  rmsnap <disk> <name>            delete a snapshot
+
+ls, cat, get and stat take [--snapshot=name] to read a snapshot instead
+every command on an encrypted disk needs its passphrase, either from
+[--passphrase-file=path], from [--passphrase] which asks for it, or from
+the FILE_SYSTEM_PASSPHRASE environment variable
+
+--json prints machine readable output, errors included
+
+exit codes: 0 ok, 1 operation failed, 2 bad usage, 3 bad disk, 4 fsck problems left or damage found";
+
+enum CliError {
+    Usage(String),
+    BadDisk(FsError),
+    Failed(FsError),
+}
+
+impl CliError {
+    fn code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::BadDisk(_) => EXIT_BAD_DISK,
+            CliError::Failed(_) => EXIT_FAILED,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            CliError::Usage(s) => s.clone(),
+            CliError::BadDisk(e) => format!("could not open disk: {}", e),
+            CliError::Failed(e) => e.to_string(),
+        }
+    }
+}
+
+impl From<FsError> for CliError {
+    fn from(e: FsError) -> Self {
+        CliError::Failed(e)
+    }
+}
+
+struct Options {
+    json: bool,
+    repair: bool,
+    force: bool, // mkfs may replace an existing disk
+    layout: Layout,
+    geometry: Geometry,
+    compression: Compression,   // What new files get on a new disk
+    budget: Option<u32>,        // Most blocks defrag may move
+    snapshot: Option<String>,   // Read from this snapshot instead
+    passphrase: Option<String>, // Disk is encrypted with it
+    encrypt_metadata: bool,
+    args: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    #[serde(flatten)]
+    stat: FileStat,
+}
+
+#[derive(Serialize)]
+struct Saved {
+    path: String,
+    inode: u32,
+}
 
 fn main() {
-    let mut file_name = String::new();
-    let mut disk_mount: Option<Disk> = None;
-
-    loop {
-        println!("Enter 1 to create a disk");
-        println!("Enter 2 to mount disk");
-        println!("Enter 3 to unmount disk");
-        println!("Enter 4 to ls directory");
-        println!("Enter 5 to get diagnostic information");
-        println!("Enter 6 to quit");
-        println!("Enter 7 for help");
-        println!("Enter 8 to write file to disk");
-        println!("Enter 9 to format disk");
-        let mut input = String::new();
-        let mut input_two = String::new();
-        let _ = stdout().flush();
-        stdin()
-            .read_line(&mut input)
-            .expect("Could not read user input");
-        input = remove_carriage_return(input);
-        let my_int = input.parse().expect("Should of unwrawpped");
-
-        match my_int {
-            1 => {
-                input = String::new();
-                input_two = String::new();
-                println!("Please enter a path for the disk");
-                stdin()
-                    .read_line(&mut input)
-                    .expect("Could not read user input");
-                println!("Please enter a disk size");
-                stdin()
-                    .read_line(&mut input_two)
-                    .expect("Could not read user input");
-                input = remove_carriage_return(input);
-                input_two = remove_carriage_return(input_two);
-                let my_u32 = input_two.parse().unwrap();
-                let disk_is_created = file_system::FileSystem::create_disk(input, my_u32);
-                println!("{}", disk_is_created);
-            }
+    process::exit(run(env::args().skip(1).collect()));
+}
 
-            2 => {
-                file_name = String::new();
-                println!("name of disk to be mounted");
-                stdin()
-                    .read_line(&mut file_name)
-                    .expect("Could not read user input");
-                file_name = remove_carriage_return(file_name);
-                println!("{}", file_name);
-                disk_mount = file_system::FileSystem::mount(&file_name);
-                println!("{:?}", disk_mount);
+fn run(args: Vec<String>) -> i32 {
+    let options = match parse_options(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e.message(), USAGE);
+            return e.code();
+        }
+    };
+    match run_command(&options) {
+        Ok(code) => code,
+        Err(e) => {
+            if options.json {
+                println!("{}", serde_json::json!({ "error": e.message() }));
+            } else {
+                eprintln!("error: {}", e.message());
             }
+            e.code()
+        }
+    }
+}
 
-            3 => {
-                disk_mount = None;
-                println!("Unmounted disk");
+fn parse_options(args: Vec<String>) -> Result<Options, CliError> {
+    let mut options = Options {
+        json: false,
+        repair: false,
+        force: false,
+        layout: Layout::Extents,
+        geometry: Geometry::default(),
+        compression: Compression::None,
+        budget: None,
+        snapshot: None,
+        passphrase: None,
+        encrypt_metadata: false,
+        args: vec![],
+    };
+    let mut passphrase_file = None;
+    let mut ask = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--repair" => options.repair = true,
+            "--force" => options.force = true,
+            "--layout=extents" => options.layout = Layout::Extents,
+            "--layout=pointers" => options.layout = Layout::Pointers,
+            "--layout=chain" => options.layout = Layout::Chain,
+            "--compress=lz" => options.compression = Compression::Lz,
+            "--compress=none" => options.compression = Compression::None,
+            "--encrypt-metadata" => options.encrypt_metadata = true,
+            a if a.starts_with("--inode-percent=") => {
+                options.geometry.inode_percent = flag_number(a)?
             }
-
-            4 => {
-                disk_mount
-                    .and_then(|disk| {
-                        let (d, disk) = file_system::FileSystem::get_directory()(disk);
-                        disk_mount = Some(disk);
-                        d
-                    })
-                    .map(|d| {
-                        println!("{:?}", d);
-                        d
-                    });
+            a if a.starts_with("--block-data-size=") => {
+                options.geometry.block_data_size = flag_number(a)?
             }
-
-            5 => {
-                println!("Disk diagnostics");
-                disk_mount
-                    .and_then(|disk| {
-                        let (d, disk) = file_system::FileSystem::get_diagnostic()(disk);
-                        disk_mount = Some(disk);
-                        d
-                    })
-                    .map(|d| {
-                        println!("{:?}", d);
-                        d
-                    });
+            a if a.starts_with("--inodes-per-block=") => {
+                options.geometry.inodes_per_block = flag_number(a)?
             }
-
-            6 => {
-                let exit_code = 0;
-                println!("Exiting");
-                std::process::exit(exit_code);
+            a if a.starts_with("--budget=") => options.budget = Some(flag_number(a)?),
+            a if a.starts_with("--snapshot=") => {
+                options.snapshot = a.split_once('=').map(|(_, name)| name.to_string())
             }
-
-            7 => {
-                help();
+            a if a.starts_with("--passphrase-file=") => {
+                passphrase_file = a.split_once('=').map(|(_, path)| path.to_string())
             }
-
-            8 => {
-                input = String::new();
-                input_two = String::new();
-                println!("Please enter the disk to be written to");
-                stdin()
-                    .read_line(&mut input)
-                    .expect("Could not read user input");
-                input = remove_carriage_return(input);
-                println!("Please enter file name");
-                stdin()
-                    .read_line(&mut input_two)
-                    .expect("Could not read user input");
-                input_two = remove_carriage_return(input_two);
-                let file_data = fs::read_to_string(&input_two).unwrap();
-                fs::write(input, &file_data).unwrap();
-                disk_mount
-                    .map(|disk| {
-                        let (data, disk) =
-                            file_system::FileSystem::save_as_file(input_two, file_data)(disk);
-                        disk_mount = Some(disk);
-                        data
-                    })
-                    .map(|x| match x {
-                        Some(_) => println!("Save file successfully"),
-                        None => println!("Something went wrong"),
-                    });
+            "--passphrase" => ask = true,
+            // Anyone who can list processes would see it
+            a if a.starts_with("--passphrase=") => {
+                let hint = "use --passphrase-file, --passphrase or FILE_SYSTEM_PASSPHRASE";
+                return Err(CliError::Usage(format!(
+                    "passphrases on the command line aren't secret, {}",
+                    hint
+                )));
             }
+            "-h" | "--help" => return Err(CliError::Usage("".into())),
+            a if a.starts_with("--") => return Err(CliError::Usage(format!("unknown flag {}", a))),
+            _ => options.args.push(arg),
+        }
+    }
+    options.passphrase = match (passphrase_file, ask) {
+        (Some(path), _) => Some(read_passphrase_file(&path)?),
+        (None, true) => Some(ask_passphrase()?),
+        (None, false) => env::var(PASSPHRASE_VAR).ok(),
+    };
+    Ok(options)
+}
+
+// The first line of the file, so a trailing newline isn't part of it
+fn read_passphrase_file(path: &str) -> Result<String, CliError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| CliError::Usage(format!("can't read passphrase file {}: {}", path, e)))?;
+    match text.lines().next() {
+        Some(p) if !p.is_empty() => Ok(p.to_string()),
+        _ => Err(CliError::Usage(format!(
+            "passphrase file {} is empty",
+            path
+        ))),
+    }
+}
+
+fn ask_passphrase() -> Result<String, CliError> {
+    eprint!("passphrase: ");
+    let mut line = String::new();
+    stdin().read_line(&mut line).map_err(FsError::from)?;
+    match line.trim_end_matches(&['\r', '\n'][..]) {
+        "" => Err(CliError::Usage("no passphrase given".into())),
+        p => Ok(p.to_string()),
+    }
+}
+
+// "--inode-percent=20" -> 20
+fn flag_number(arg: &str) -> Result<u32, CliError> {
+    let (_, value) = arg.split_once('=').unwrap_or((arg, ""));
+    value
+        .parse()
+        .map_err(|_| CliError::Usage(format!("{} isn't a number", arg)))
+}
 
-            9 => {
-                input = String::new();
-                input_two = String::new();
-                println!("Please enter disk to format");
-                stdin()
-                    .read_line(&mut input)
-                    .expect("Could not read user input");
-                input = remove_carriage_return(input);
-                println!("Please enter new disk size");
-                stdin()
-                    .read_line(&mut input_two)
-                    .expect("Could not read user input");
-                input_two = remove_carriage_return(input_two);
-                let parsed = input_two.parse().unwrap();
-                let formatted = file_system::FileSystem::format(input, parsed);
-                println!("{:?}", formatted);
+fn run_command(options: &Options) -> Result<i32, CliError> {
+    let args: Vec<&str> = options.args.iter().map(|x| x.as_str()).collect();
+    match args.as_slice() {
+        ["mkfs", disk, blocks] => {
+            let blocks = blocks
+                .parse()
+                .map_err(|_| CliError::Usage(format!("{} isn't a block count", blocks)))?;
+            if !options.force && fs::metadata(disk).is_ok() {
+                return Err(CliError::Failed(FsError::AlreadyExists(disk.to_string())));
+            }
+            let super_block = SuperBlock::with_journal(blocks)
+                .with_geometry(options.geometry)
+                .with_bitmaps()
+                .with_backups()
+                .with_layout(options.layout)
+                .with_compression(options.compression);
+            match &options.passphrase {
+                Some(p) => FileSystem::create_encrypted_disk(
+                    disk.to_string(),
+                    super_block,
+                    p,
+                    options.encrypt_metadata,
+                )?,
+                None => FileSystem::create_disk_with(disk.to_string(), super_block)?,
+            }
+            print(
+                options,
+                &serde_json::json!({ "disk": disk, "blocks": blocks }),
+                |_| String::new(),
+            );
+            Ok(EXIT_OK)
+        }
+        ["ls", disk] => ls(options, disk, "/"),
+        ["ls", disk, path] => ls(options, disk, path),
+        ["cat", disk, path] => cat(options, disk, path),
+        ["put", disk, local] => {
+            let name = local.rsplit('/').next().unwrap_or(local);
+            put(options, disk, local, &format!("/{}", name))
+        }
+        ["put", disk, local, path] => put(options, disk, local, path),
+        ["get", disk, path] => cat(options, disk, path),
+        ["get", disk, path, local] => {
+            let data = read_disk(options, disk, FileSystem::read_file(path.to_string()))?;
+            fs::write(local, data).map_err(FsError::from)?;
+            print(
+                options,
+                &serde_json::json!({ "path": path, "local": local }),
+                |_| String::new(),
+            );
+            Ok(EXIT_OK)
+        }
+        ["rm", disk, path] => {
+            with_disk(options, disk, FileSystem::remove_file(path.to_string()))?;
+            print(options, &serde_json::json!({ "path": path }), |_| {
+                String::new()
+            });
+            Ok(EXIT_OK)
+        }
+        ["mkdir", disk, path] => {
+            let inode = with_disk(options, disk, FileSystem::mkdir(path.to_string()))?;
+            let saved = Saved {
+                path: path.to_string(),
+                inode,
+            };
+            print(options, &saved, |_| String::new());
+            Ok(EXIT_OK)
+        }
+        ["rmdir", disk, path] => {
+            with_disk(options, disk, FileSystem::rmdir(path.to_string()))?;
+            print(options, &serde_json::json!({ "path": path }), |_| {
+                String::new()
+            });
+            Ok(EXIT_OK)
+        }
+        ["stat", disk, path] => {
+            let stat = read_disk(options, disk, FileSystem::stat(path.to_string()))?;
+            print(options, &stat, fields);
+            Ok(EXIT_OK)
+        }
+        ["compress", disk, path] => compress(options, disk, path, Compression::Lz),
+        ["uncompress", disk, path] => compress(options, disk, path, Compression::None),
+        ["diag", disk] => {
+            let diagnostics = with_disk(options, disk, FileSystem::get_diagnostic())?;
+            print(options, &diagnostics, fields);
+            Ok(EXIT_OK)
+        }
+        ["fsck", disk] => fsck(options, disk),
+        ["scrub", disk] => {
+            let report = with_disk(options, disk, FileSystem::scrub())?;
+            print(options, &report, |r: &ScrubReport| {
+                let blocks = r
+                    .damaged
+                    .iter()
+                    .map(|n| format!("block {} is damaged\n", n));
+                let files = r.files.iter().map(|f| format!("damaged: {}\n", f));
+                let mut out: String = blocks.chain(files).collect();
+                if r.is_clean() {
+                    out += &format!("checked {} blocks, clean\n", r.checked);
+                }
+                out
+            });
+            match report.is_clean() {
+                true => Ok(EXIT_OK),
+                false => Ok(EXIT_FSCK_PROBLEMS),
             }
-            _ => println!("Something went wrong"),
         }
+        ["migrate", disk] => {
+            let migrated = with_disk(options, disk, FileSystem::migrate_to_extents())?;
+            print(
+                options,
+                &serde_json::json!({ "migrated": migrated }),
+                |_| format!("migrated {} inodes\n", migrated),
+            );
+            Ok(EXIT_OK)
+        }
+        ["upgrade", disk] => {
+            let upgrade = with_device(options, disk, |device| {
+                FileSystem::upgrade(device).map_err(|e| match e {
+                    FsError::InvalidSuperBlock
+                    | FsError::UnsupportedFormat(_)
+                    | FsError::UnsupportedFeatures(_)
+                    | FsError::Locked => CliError::BadDisk(e),
+                    e => CliError::Failed(e),
+                })
+            })?;
+            print(options, &upgrade, |u| {
+                format!("upgraded from version {} to {}\n", u.from, u.to)
+            });
+            Ok(EXIT_OK)
+        }
+        ["frag", disk] => {
+            let measured = with_disk(options, disk, FileSystem::fragmentation())?;
+            print(options, &measured, |m: &Vec<Fragmentation>| {
+                m.iter()
+                    .map(|f| format!("{} {} blocks in {} pieces\n", f.path, f.blocks, f.fragments))
+                    .collect()
+            });
+            Ok(EXIT_OK)
+        }
+        ["defrag", disk] => {
+            let defrag = with_disk(options, disk, FileSystem::defragment(options.budget))?;
+            print(options, &defrag, |d| {
+                format!(
+                    "moved {} blocks, {} files defragmented, {} left\n",
+                    d.moved,
+                    d.defragmented.len(),
+                    d.remaining
+                )
+            });
+            Ok(EXIT_OK)
+        }
+        ["resize", disk, blocks] => {
+            let size = blocks
+                .parse::<u32>()
+                .map_err(|_| CliError::Usage(format!("bad block count {}", blocks)))?;
+            let resize = with_device(options, disk, |device| {
+                FileSystem::resize(device, size).map_err(|e| match e {
+                    FsError::InvalidSuperBlock
+                    | FsError::UnsupportedFormat(_)
+                    | FsError::UnsupportedFeatures(_)
+                    | FsError::Locked => CliError::BadDisk(e),
+                    e => CliError::Failed(e),
+                })
+            })?;
+            print(options, &resize, |r| {
+                format!("resized from {} to {} blocks\n", r.from, r.to)
+            });
+            Ok(EXIT_OK)
+        }
+        ["snapshot", disk, name] => {
+            let snapshot = with_disk(options, disk, FileSystem::snapshot(name.to_string()))?;
+            print(options, &snapshot, |_| String::new());
+            Ok(EXIT_OK)
+        }
+        ["snapshots", disk] => {
+            let snapshots = with_disk(options, disk, FileSystem::list_snapshots())?;
+            print(options, &snapshots, |s: &Vec<Snapshot>| {
+                s.iter()
+                    .map(|s| format!("{} {}\n", s.created, s.name))
+                    .collect()
+            });
+            Ok(EXIT_OK)
+        }
+        ["rollback", disk, name] => {
+            let snapshot = with_disk(options, disk, FileSystem::rollback(name.to_string()))?;
+            print(options, &snapshot, |s| {
+                format!("rolled back to {}\n", s.name)
+            });
+            Ok(EXIT_OK)
+        }
+        ["rmsnap", disk, name] => {
+            let snapshot = with_disk(options, disk, FileSystem::delete_snapshot(name.to_string()))?;
+            print(options, &snapshot, |_| String::new());
+            Ok(EXIT_OK)
+        }
+        [] => Err(CliError::Usage("missing command".into())),
+        [command, ..] => Err(CliError::Usage(format!("bad arguments for {}", command))),
     }
 }
 
-pub fn help() {
-    println!("To create a disk path enter a path like ./sda1 or ./sda2");
-    println!("Enter a disk size such as 30 or 10");
-    println!("To mount a disk, enter the disk path as you did earlier, for example ./sda1");
+// Open the disk's device, unlocked with the passphrase when there is one
+fn with_device<A>(
+    options: &Options,
+    path: &str,
+    f: impl FnOnce(&dyn BlockDevice) -> Result<A, CliError>,
+) -> Result<A, CliError> {
+    let device = open_device(path).map_err(CliError::BadDisk)?;
+    match &options.passphrase {
+        Some(p) => {
+            let unlocked =
+                FileSystem::mount_encrypted(device.as_ref(), p).map_err(CliError::BadDisk)?;
+            f(&unlocked)
+        }
+        None => f(device.as_ref()),
+    }
 }
 
-pub fn remove_carriage_return(mut input: String) -> String {
-    input.pop();
-    // input.pop();
-    input
+// Mount the disk, run the action, then unmount
+fn with_disk<A>(
+    options: &Options,
+    path: &str,
+    action: DiskAction<FsResult<A>>,
+) -> Result<A, CliError> {
+    with_device(options, path, |device| {
+        let disk = FileSystem::mount(device).map_err(CliError::BadDisk)?;
+        let (res, disk) = action(disk);
+        FileSystem::unmount(disk)?;
+        Ok(res?)
+    })
+}
+
+// Like with_disk, but reads from --snapshot when it's given
+fn read_disk<A>(
+    options: &Options,
+    path: &str,
+    action: DiskAction<FsResult<A>>,
+) -> Result<A, CliError> {
+    let name = match &options.snapshot {
+        Some(name) => name.clone(),
+        None => return with_disk(options, path, action),
+    };
+    with_device(options, path, |device| {
+        let snapshot = FileSystem::mount_snapshot(device, name).map_err(|e| match e {
+            FsError::NotFound(_) => CliError::Failed(e),
+            e => CliError::BadDisk(e),
+        })?;
+        let (res, _) = action(Disk::new(&snapshot));
+        Ok(res?)
+    })
+}
+
+// JSON for --json, otherwise whatever human gives back
+fn print<T: Serialize>(options: &Options, value: &T, human: impl Fn(&T) -> String) {
+    let out = if options.json {
+        serde_json::to_string(value).unwrap_or_default() + "\n"
+    } else {
+        human(value)
+    };
+    let _ = stdout().write_all(out.as_bytes());
+}
+
+// One "key: value" line per field
+fn fields<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::Object(map)) => map
+            .iter()
+            .map(|(k, v)| match v {
+                serde_json::Value::String(s) => format!("{}: {}\n", k, s),
+                v => format!("{}: {}\n", k, v),
+            })
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+fn ls(options: &Options, disk: &str, path: &str) -> Result<i32, CliError> {
+    let dir = path.trim_end_matches('/').to_string();
+    let d = FileSystem::list_directory(path.to_string());
+    let d = flat_map_result(
+        d,
+        Box::new(move |x| {
+            let stats = x
+                .entries()
+                .into_iter()
+                .map(|(name, _)| {
+                    let stat = FileSystem::stat(format!("{}/{}", dir, name));
+                    map_result(
+                        stat,
+                        Box::new(move |stat| Entry {
+                            name: name.clone(),
+                            stat,
+                        }),
+                    )
+                })
+                .collect();
+            sequence_result(stats)
+        }),
+    );
+    let entries = read_disk(options, disk, d)?;
+    print(options, &entries, |entries| {
+        entries
+            .iter()
+            .map(|e| {
+                let name = match e.stat.kind {
+                    InodeKind::Directory => format!("{}/", e.name),
+                    InodeKind::File => e.name.clone(),
+                };
+                format!("{:o} {:>8} {}\n", e.stat.mode, e.stat.size, name)
+            })
+            .collect()
+    });
+    Ok(EXIT_OK)
+}
+
+// Raw bytes to stdout, with --json the data is hex when it isn't UTF-8
+fn cat(options: &Options, disk: &str, path: &str) -> Result<i32, CliError> {
+    let data = read_disk(options, disk, FileSystem::read_file(path.to_string()))?;
+    if options.json {
+        let json = match std::str::from_utf8(&data) {
+            Ok(s) => serde_json::json!({ "path": path, "size": data.len(), "data": s }),
+            Err(_) => serde_json::json!({
+                "path": path,
+                "size": data.len(),
+                "hex": data.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            }),
+        };
+        println!("{}", json);
+    } else {
+        let _ = stdout().write_all(&data);
+    }
+    Ok(EXIT_OK)
+}
+
+fn put(options: &Options, disk: &str, local: &str, path: &str) -> Result<i32, CliError> {
+    let data = fs::read(local).map_err(FsError::from)?;
+    let inode = with_disk(
+        options,
+        disk,
+        FileSystem::save_as_file(path.to_string(), data),
+    )?;
+    let saved = Saved {
+        path: path.to_string(),
+        inode,
+    };
+    print(options, &saved, |_| String::new());
+    Ok(EXIT_OK)
+}
+
+fn compress(
+    options: &Options,
+    disk: &str,
+    path: &str,
+    compression: Compression,
+) -> Result<i32, CliError> {
+    let d = FileSystem::set_compression(path.to_string(), compression);
+    let stat = with_disk(options, disk, d)?;
+    print(options, &stat, |s| {
+        format!("{} bytes stored in {}\n", s.size, s.stored)
+    });
+    Ok(EXIT_OK)
+}
+
+fn fsck(options: &Options, disk: &str) -> Result<i32, CliError> {
+    let report = with_disk(options, disk, FileSystem::fsck(options.repair))?;
+    print(options, &report, |r: &FsckReport| {
+        let problems = r.problems.iter().map(|p| format!("{}\n", p));
+        let repaired = r.repaired.iter().map(|p| format!("repaired: {}\n", p));
+        let mut out: String = problems.chain(repaired).collect();
+        if r.is_clean() {
+            out += "clean\n";
+        }
+        out
+    });
+    if report.problems.iter().all(|p| report.repaired.contains(p)) {
+        Ok(EXIT_OK)
+    } else {
+        Ok(EXIT_FSCK_PROBLEMS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("file_system_cli_{}", name))
+            .to_string_lossy()
+            .into()
+    }
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    // A fresh disk for one test, with flags for mkfs
+    fn mkfs(name: &str, flags: &str) -> String {
+        let disk = temp_path(name);
+        let cmd = format!("mkfs {} 50 --force {}", disk, flags);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        disk
+    }
+
+    // A local file for one test holding data
+    fn local_file(name: &str, data: &[u8]) -> String {
+        let local = temp_path(name);
+        fs::write(&local, data).unwrap();
+        local
+    }
+
+    fn remove(paths: &[&str]) {
+        for path in paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn mkfs_should_refuse_to_replace_a_disk_without_force() {
+        let disk = mkfs("mkfs_disk", "");
+        let local = local_file("mkfs_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("mkfs {} 50", disk))), EXIT_FAILED);
+        assert_eq!(run(args(&format!("cat {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("mkfs {} 50 --force", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /a", disk))), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn mkfs_with_pointers_should_keep_files_the_same() {
+        let disk = mkfs(
+            "mkfs_pointers_disk",
+            "--layout=pointers --block-data-size=7",
+        );
+        let bytes = vec![0, 159, 146, 150, 255, b'\n'];
+        let local = local_file("mkfs_pointers_local", &bytes);
+        assert_eq!(run(args(&format!("put {} {} /bin", disk, local))), EXIT_OK);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /bin {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read(&local).unwrap(), bytes);
+        assert_eq!(run(args(&format!("fsck {}", disk))), EXIT_OK);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn mkfs_with_compression_should_keep_files_the_same() {
+        let disk = mkfs("mkfs_lz_disk", "--compress=lz");
+        let text = "Ogres are like onions. ".repeat(10);
+        let local = local_file("mkfs_lz_local", text.as_bytes());
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), text);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn put_and_get_should_keep_binary_files_byte_for_byte() {
+        let disk = mkfs("put_disk", "");
+        let bytes = vec![0, 159, 146, 150, 255, b'\n'];
+        let local = local_file("put_local", &bytes);
+        assert_eq!(run(args(&format!("put {} {} /bin", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("put {} {}", disk, local))), EXIT_OK);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /bin {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read(&local).unwrap(), bytes);
+        assert_eq!(
+            run(args(&format!("get {} /nope {}", disk, local))),
+            EXIT_FAILED
+        );
+        let cmd = format!("put {} {} /a", disk, temp_path("put_missing"));
+        assert_eq!(run(args(&cmd)), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn cat_should_print_files() {
+        let disk = mkfs("cat_disk", "");
+        let local = local_file("cat_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /a --json", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /nope", disk))), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn ls_should_list_directories() {
+        let disk = mkfs("ls_disk", "");
+        assert_eq!(run(args(&format!("ls {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("ls {} / --json", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("ls {} /nope", disk))), EXIT_FAILED);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn stat_should_show_inodes() {
+        let disk = mkfs("stat_disk", "");
+        assert_eq!(run(args(&format!("stat {} / --json", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("stat {} /nope", disk))), EXIT_FAILED);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn rm_should_remove_files() {
+        let disk = mkfs("rm_disk", "");
+        let local = local_file("rm_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("rm {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /a", disk))), EXIT_FAILED);
+        assert_eq!(run(args(&format!("rm {} /a", disk))), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn mkdir_should_make_directories() {
+        let disk = mkfs("mkdir_disk", "");
+        let local = local_file("mkdir_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("mkdir {} /docs", disk))), EXIT_OK);
+        let cmd = format!("put {} {} /docs/shrek.txt", disk, local);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        assert_eq!(run(args(&format!("ls {} /docs", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("mkdir {} /docs", disk))), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn rmdir_should_only_remove_empty_directories() {
+        let disk = mkfs("rmdir_disk", "");
+        let local = local_file("rmdir_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("mkdir {} /docs", disk))), EXIT_OK);
+        let cmd = format!("put {} {} /docs/shrek.txt", disk, local);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        assert_eq!(run(args(&format!("rmdir {} /docs", disk))), EXIT_FAILED);
+        assert_eq!(run(args(&format!("rm {} /docs/shrek.txt", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rmdir {} /docs", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("ls {} /docs", disk))), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn compress_and_uncompress_should_keep_files_the_same() {
+        let disk = mkfs("compress_disk", "");
+        let text = "Ogres are like onions. ".repeat(10);
+        let local = local_file("compress_local", text.as_bytes());
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("compress {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("uncompress {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("compress {} /nope", disk))), EXIT_FAILED);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), text);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn diag_should_exit_ok() {
+        let disk = mkfs("diag_disk", "");
+        assert_eq!(run(args(&format!("diag {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("diag {} --json", disk))), EXIT_OK);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn fsck_should_pass_a_fresh_disk() {
+        let disk = mkfs("fsck_disk", "");
+        assert_eq!(run(args(&format!("fsck {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("fsck {} --repair", disk))), EXIT_OK);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn scrub_should_pass_a_fresh_disk() {
+        let disk = mkfs("scrub_disk", "");
+        assert_eq!(run(args(&format!("scrub {}", disk))), EXIT_OK);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn migrate_and_upgrade_should_leave_current_disks_alone() {
+        let disk = mkfs("migrate_disk", "");
+        let local = local_file("migrate_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("migrate {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("upgrade {}", disk))), EXIT_OK);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "Ogres are like onions");
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn frag_and_defrag_should_keep_files() {
+        let disk = mkfs("frag_disk", "");
+        let local = local_file("frag_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("frag {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("defrag {} --budget=10", disk))), EXIT_OK);
+        assert_eq!(
+            run(args(&format!("defrag {} --budget=ten", disk))),
+            EXIT_USAGE
+        );
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "Ogres are like onions");
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn resize_should_keep_files() {
+        let disk = mkfs("resize_disk", "");
+        let local = local_file("resize_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("resize {} 80", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("resize {} lots", disk))), EXIT_USAGE);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "Ogres are like onions");
+        assert_eq!(run(args(&format!("fsck {}", disk))), EXIT_OK);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn snapshots_should_keep_files_until_rolled_back_to() {
+        let disk = mkfs("snapshot_disk", "");
+        let bytes = vec![0, 159, 146, 150, 255, b'\n'];
+        let local = local_file("snapshot_local", &bytes);
+        assert_eq!(run(args(&format!("put {} {} /bin", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("snapshot {} before", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("snapshot {} before", disk))), EXIT_FAILED);
+        assert_eq!(run(args(&format!("snapshots {}", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rm {} /bin", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("cat {} /bin", disk))), EXIT_FAILED);
+        let cmd = format!("ls {} --snapshot=before", disk);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        fs::remove_file(&local).unwrap();
+        let cmd = format!("get {} /bin {} --snapshot=before", disk, local);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        assert_eq!(fs::read(&local).unwrap(), bytes);
+        let cmd = format!("cat {} /bin --snapshot=after", disk);
+        assert_eq!(run(args(&cmd)), EXIT_FAILED);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn rollback_should_bring_files_back() {
+        let disk = mkfs("rollback_disk", "");
+        let local = local_file("rollback_local", b"Ogres are like onions");
+        assert_eq!(run(args(&format!("put {} {} /a", disk, local))), EXIT_OK);
+        assert_eq!(run(args(&format!("snapshot {} before", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rm {} /a", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rollback {} before", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rollback {} after", disk))), EXIT_FAILED);
+        fs::remove_file(&local).unwrap();
+        assert_eq!(run(args(&format!("get {} /a {}", disk, local))), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "Ogres are like onions");
+        assert_eq!(run(args(&format!("fsck {}", disk))), EXIT_OK);
+        remove(&[&disk, &local]);
+    }
+
+    #[test]
+    fn rmsnap_should_delete_snapshots() {
+        let disk = mkfs("rmsnap_disk", "");
+        assert_eq!(run(args(&format!("snapshot {} before", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rmsnap {} before", disk))), EXIT_OK);
+        assert_eq!(run(args(&format!("rmsnap {} before", disk))), EXIT_FAILED);
+        assert_eq!(run(args(&format!("fsck {}", disk))), EXIT_OK);
+        remove(&[&disk]);
+    }
+
+    #[test]
+    fn encrypted_disks_should_need_their_passphrase() {
+        // Never from the command line
+        let key = local_file("encrypted_key", b"donkey\n");
+        let wrong_key = local_file("encrypted_wrong_key", b"shrek\n");
+        let disk = temp_path("encrypted_disk");
+        let cmd = format!("mkfs {} 50 --force --passphrase=donkey", disk);
+        assert_eq!(run(args(&cmd)), EXIT_USAGE);
+        let disk = mkfs(
+            "encrypted_disk",
+            &format!("--passphrase-file={} --encrypt-metadata", key),
+        );
+        let text = "Ogres are like onions";
+        let local = local_file("encrypted_local", text.as_bytes());
+        let cmd = format!("put {} {} /a --passphrase-file={}", disk, local, key);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        assert!(!fs::read(&disk).unwrap().windows(5).any(|w| w == b"Ogres"));
+        assert_eq!(run(args(&format!("cat {} /a", disk))), EXIT_BAD_DISK);
+        let cmd = format!("cat {} /a --passphrase-file={}", disk, wrong_key);
+        assert_eq!(run(args(&cmd)), EXIT_BAD_DISK);
+        fs::remove_file(&local).unwrap();
+        let cmd = format!("get {} /a {} --passphrase-file={}", disk, local, key);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        assert_eq!(fs::read_to_string(&local).unwrap(), text);
+        let cmd = format!("fsck {} --passphrase-file={}", disk, key);
+        assert_eq!(run(args(&cmd)), EXIT_OK);
+        let cmd = format!("fsck {} --passphrase-file={}", disk, temp_path("no_key"));
+        assert_eq!(run(args(&cmd)), EXIT_USAGE);
+        remove(&[&disk, &local, &key, &wrong_key]);
+    }
+
+    #[test]
+    fn bad_usage_and_bad_disks_should_exit_with_expected_codes() {
+        assert_eq!(run(args("")), EXIT_USAGE);
+        assert_eq!(run(args("cat")), EXIT_USAGE);
+        assert_eq!(run(args("ls ./test-files/sda1 --nope")), EXIT_USAGE);
+        assert_eq!(run(args("mkfs ./nowhere lots")), EXIT_USAGE);
+        assert_eq!(run(args("mkfs ./nowhere 50 --layout=fat")), EXIT_USAGE);
+        assert_eq!(
+            run(args("mkfs ./nowhere 50 --inode-percent=ten")),
+            EXIT_USAGE
+        );
+        assert_eq!(run(args("ls ./test-files/does_not_exist")), EXIT_BAD_DISK);
+        assert_eq!(
+            run(args("ls ./test-files/line_handler_test_file.txt")),
+            EXIT_BAD_DISK
+        );
+    }
 }